@@ -14,6 +14,7 @@ qemu-system-x86_64 \
     -m 4G \
     -bios third_party/ovmf/RELEASEX64_OVMF.fd \
     -machine q35 \
+    -global hpet.msi=on \
     -drive format=raw,file=fat:rw:mnt \
     -monitor telnet:0.0.0.0:2345,server,nowait,logfile=log/qemu_monitor.txt \
     -chardev stdio,id=char_com1,mux=on,logfile=log/com1.txt \
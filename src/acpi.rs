@@ -1,5 +1,7 @@
 use crate::hpet::HpetRegisters;
+use crate::result::Error;
 use crate::result::Result;
+use core::fmt;
 use core::mem::size_of;
 
 #[repr(packed)]
@@ -32,14 +34,9 @@ impl<'a> XsdtIterator<'a> {
 impl<'a> Iterator for XsdtIterator<'a> {
     type Item = &'static SystemDescriptionTableHeader;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.table.num_of_entiries() {
-            None
-        } else {
-            self.index += 1;
-            Some(unsafe {
-                &*(self.table.entry(self.index - 1) as *const SystemDescriptionTableHeader)
-            })
-        }
+        let entry = self.table.entry(self.index)?;
+        self.index += 1;
+        Some(unsafe { &*(entry as *const SystemDescriptionTableHeader) })
     }
 }
 
@@ -56,13 +53,20 @@ impl Xsdt {
     fn header_size(&self) -> usize {
         size_of::<Self>()
     }
+    // lengthがヘッダより短い壊れたテーブルでは0を返す
     fn num_of_entiries(&self) -> usize {
-        (self.header.length as usize - self.header_size()) / size_of::<*const u8>()
+        (self.header.length as usize).saturating_sub(self.header_size()) / size_of::<*const u8>()
     }
-    unsafe fn entry(&self, index: usize) -> *const u8 {
-        ((self as *const Self as *const u8).add(self.header_size()) as *const *const u8)
-            .add(index)
-            .read_unaligned()
+    // テーブル自身のlengthの範囲外は読まない
+    fn entry(&self, index: usize) -> Option<*const u8> {
+        if index >= self.num_of_entiries() {
+            return None;
+        }
+        Some(unsafe {
+            ((self as *const Self as *const u8).add(self.header_size()) as *const *const u8)
+                .add(index)
+                .read_unaligned()
+        })
     }
     fn iter(&self) -> XsdtIterator {
         XsdtIterator::new(self)
@@ -95,7 +99,19 @@ impl GenericAddress {
         if self.address_space_id == 0 {
             Ok(self.address as usize)
         } else {
-            Err("ACPI Generic Address is not in system memory space")
+            Err(Error::Acpi(
+                "ACPI Generic Address is not in system memory space",
+            ))
+        }
+    }
+    // I/Oポート空間（address_space_id = 1）のアドレスを読みだす
+    pub fn address_in_io_space(&self) -> Result<u16> {
+        if self.address_space_id == 1 {
+            Ok(self.address as u16)
+        } else {
+            Err(Error::Acpi(
+                "ACPI Generic Address is not in system I/O space",
+            ))
         }
     }
 }
@@ -123,8 +139,251 @@ impl AcpiHpetDescriptor {
 }
 const _: () = assert!(size_of::<AcpiHpetDescriptor>() == 56);
 
+// 電源管理のレジスタの場所などが格納されたACPIテーブル (Fixed ACPI Description Table)
+#[repr(packed)]
+pub struct AcpiFadt {
+    header: SystemDescriptionTableHeader,
+    _firmware_ctrl: u32,
+    dsdt: u32,
+    _unused0: [u8; 20],
+    pm1a_cnt_blk: u32,
+    _pm1b_cnt_blk: u32,
+    _unused1: [u8; 68],
+    x_dsdt: u64,
+    _x_pm1_evt_blk: [u8; 24],
+    x_pm1a_cnt_blk: GenericAddress,
+}
+impl AcpiTable for AcpiFadt {
+    const SIGNATURE: &'static [u8; 4] = b"FACP";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiFadt>() == 184);
+
+impl AcpiFadt {
+    // ACPI 1.0のFADTは短いので、長さを確認してから拡張フィールドを読む
+    fn has_field(&self, end_offset: usize) -> bool {
+        self.header.length as usize >= end_offset
+    }
+    pub fn pm1a_control_block(&self) -> Result<u16> {
+        if self.has_field(size_of::<Self>()) {
+            if let Ok(port) = self.x_pm1a_cnt_blk.address_in_io_space() {
+                if port != 0 {
+                    return Ok(port);
+                }
+            }
+        }
+        let port = self.pm1a_cnt_blk;
+        if port != 0 && port <= u16::MAX as u32 {
+            Ok(port as u16)
+        } else {
+            Err(Error::Acpi("PM1a control block is not available"))
+        }
+    }
+    fn dsdt(&self) -> Option<&'static SystemDescriptionTableHeader> {
+        let x_dsdt = if self.has_field(148) { self.x_dsdt } else { 0 };
+        let addr = if x_dsdt != 0 {
+            x_dsdt as usize
+        } else {
+            self.dsdt as usize
+        };
+        if addr == 0 {
+            None
+        } else {
+            Some(unsafe { &*(addr as *const SystemDescriptionTableHeader) })
+        }
+    }
+    // DSDTのAMLから\_S5_パッケージを探し、S5のSLP_TYPaを取り出す
+    pub fn s5_sleep_type(&self) -> Result<u16> {
+        let dsdt = self.dsdt().ok_or(Error::Acpi("DSDT is not available"))?;
+        let aml = unsafe {
+            core::slice::from_raw_parts(
+                dsdt as *const SystemDescriptionTableHeader as *const u8,
+                dsdt.length as usize,
+            )
+        };
+        find_s5_sleep_type(aml).ok_or(Error::Acpi("_S5_ object is not found in DSDT"))
+    }
+}
+
+fn find_s5_sleep_type(aml: &[u8]) -> Option<u16> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0a;
+    const WORD_PREFIX: u8 = 0x0b;
+    const DWORD_PREFIX: u8 = 0x0c;
+    let pos = aml.windows(4).position(|w| w == b"_S5_")?;
+    // NameOp _S5_ または NameOp \_S5_ であること
+    let is_name = (pos >= 1 && aml[pos - 1] == NAME_OP)
+        || (pos >= 2 && aml[pos - 2] == NAME_OP && aml[pos - 1] == b'\\');
+    if !is_name || *aml.get(pos + 4)? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLengthの先頭バイトの上位2bitは後続のバイト数
+    let pkg_length_bytes = 1 + (*aml.get(pos + 5)? >> 6) as usize;
+    // NumElementsの次が最初の要素(SLP_TYPa)
+    let element = pos + 5 + pkg_length_bytes + 1;
+    match *aml.get(element)? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => aml.get(element + 1).map(|v| *v as u16),
+        WORD_PREFIX => aml
+            .get(element + 1..element + 3)
+            .map(|v| u16::from_le_bytes([v[0], v[1]])),
+        DWORD_PREFIX => aml
+            .get(element + 1..element + 5)
+            .and_then(|v| u16::try_from(u32::from_le_bytes([v[0], v[1], v[2], v[3]])).ok()),
+        // 他のオペコード(メソッド呼び出しなど)は値を解釈できない
+        _ => None,
+    }
+}
+
+// PCIのコンフィグ空間(ECAM)の場所を示すACPIテーブル
+#[repr(packed)]
+pub struct AcpiMcfgDescriptor {
+    header: SystemDescriptionTableHeader,
+    _unused: [u8; 8],
+    // この後ろにEcamEntryがnum_of_entries()個並ぶ
+}
+impl AcpiTable for AcpiMcfgDescriptor {
+    const SIGNATURE: &'static [u8; 4] = b"MCFG";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiMcfgDescriptor>() == 44);
+
+impl AcpiMcfgDescriptor {
+    fn header_size(&self) -> usize {
+        size_of::<Self>()
+    }
+    pub fn num_of_entries(&self) -> usize {
+        (self.header.length as usize).saturating_sub(self.header_size()) / size_of::<EcamEntry>()
+    }
+    pub fn entry(&self, index: usize) -> Option<&EcamEntry> {
+        if index >= self.num_of_entries() {
+            None
+        } else {
+            Some(unsafe {
+                &*((self as *const Self as *const u8).add(self.header_size()) as *const EcamEntry)
+                    .add(index)
+            })
+        }
+    }
+}
+
+#[repr(packed)]
+pub struct EcamEntry {
+    ecm_base_addr: u64,
+    _pci_segment_group: u16,
+    start_pci_bus: u8,
+    end_pci_bus: u8,
+    _reserved: u32,
+}
+const _: () = assert!(size_of::<EcamEntry>() == 16);
+
+impl EcamEntry {
+    pub fn base_address(&self) -> u64 {
+        self.ecm_base_addr
+    }
+}
+impl fmt::Display for EcamEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // packedな構造体のフィールドへの参照は作れないのでコピーしておく
+        let base = self.ecm_base_addr;
+        let bus_start = self.start_pci_bus;
+        let bus_end = self.end_pci_bus;
+        write!(
+            f,
+            "ECAM: Bus range [{bus_start},{bus_end}] mapped at {base:#X}"
+        )
+    }
+}
+
+// MADTの各エントリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        io_apic_id: u8,
+        address: u32,
+        global_system_interrupt_base: u32,
+    },
+    Other {
+        entry_type: u8,
+    },
+}
+
+// 割り込みコントローラ(Local APIC, I/O APIC)の情報が格納されたACPIテーブル
+#[repr(packed)]
+pub struct AcpiMadt {
+    header: SystemDescriptionTableHeader,
+    local_apic_address: u32,
+    _flags: u32,
+    // この後ろに可変長のエントリが並ぶ
+}
+impl AcpiTable for AcpiMadt {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiMadt>() == 44);
+
+impl AcpiMadt {
+    pub fn local_apic_address(&self) -> usize {
+        self.local_apic_address as usize
+    }
+    pub fn iter(&self) -> MadtIterator {
+        MadtIterator {
+            madt: self,
+            ofs: size_of::<Self>(),
+        }
+    }
+}
+
+pub struct MadtIterator<'a> {
+    madt: &'a AcpiMadt,
+    ofs: usize,
+}
+impl<'a> Iterator for MadtIterator<'a> {
+    type Item = MadtEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = self.madt.header.length as usize;
+        // エントリは先頭2バイトが(type, length)
+        if self.ofs + 2 > length {
+            return None;
+        }
+        let p = unsafe { (self.madt as *const AcpiMadt as *const u8).add(self.ofs) };
+        let entry_type = unsafe { p.read() };
+        let entry_len = unsafe { p.add(1).read() } as usize;
+        if entry_len < 2 || self.ofs + entry_len > length {
+            return None;
+        }
+        self.ofs += entry_len;
+        let read_u32 = |ofs: usize| unsafe { (p.add(ofs) as *const u32).read_unaligned() };
+        Some(match (entry_type, entry_len) {
+            (0, 8..) => MadtEntry::LocalApic {
+                processor_id: unsafe { p.add(2).read() },
+                apic_id: unsafe { p.add(3).read() },
+                flags: read_u32(4),
+            },
+            (1, 12..) => MadtEntry::IoApic {
+                io_apic_id: unsafe { p.add(2).read() },
+                address: read_u32(4),
+                global_system_interrupt_base: read_u32(8),
+            },
+            _ => MadtEntry::Other { entry_type },
+        })
+    }
+}
+
+// ACPI 1.0のRSDPの長さ（checksumの対象範囲）
+const RSDP_V1_LENGTH: usize = 20;
+
 // UEFIから取得されたACPI RSDPのポインタ
-#[repr(C)]
+#[repr(C, packed)]
 #[derive(Debug)]
 pub struct AcpiRsdpStruct {
     signature: [u8; 8],
@@ -134,13 +393,234 @@ pub struct AcpiRsdpStruct {
     rsdt_address: u32, // Root System Description Tableのポインタ（32bit）
     length: u32,
     xsdt: u64, // Extended System Description Tableのポインタ（64bit)
+    extended_checksum: u8,
+    _reserved: [u8; 3],
 }
+const _: () = assert!(size_of::<AcpiRsdpStruct>() == 36);
+
+// 全バイトの和が0になっていればよい
+fn is_checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
 impl AcpiRsdpStruct {
+    // シグネチャとチェックサムを確認する
+    // ACPI 2.0以降ではlength全体に対する拡張チェックサムも確認する
+    pub fn validate(&self) -> Result<()> {
+        if &self.signature != b"RSD PTR " {
+            return Err(Error::Acpi("RSDP: invalid signature"));
+        }
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>())
+        };
+        if !is_checksum_valid(&bytes[..RSDP_V1_LENGTH]) {
+            return Err(Error::Acpi("RSDP: invalid checksum"));
+        }
+        if self.rebision < 2 {
+            return Err(Error::Acpi("RSDP: ACPI 1.0 (no XSDT) is not supported"));
+        }
+        if (self.length as usize) < size_of::<Self>() {
+            return Err(Error::Acpi("RSDP: length is too short"));
+        }
+        if !is_checksum_valid(bytes) {
+            return Err(Error::Acpi("RSDP: invalid extended checksum"));
+        }
+        Ok(())
+    }
     fn xsdt(&self) -> &Xsdt {
         unsafe { &*(self.xsdt as *const Xsdt) }
     }
+    // ファームウェアのベンダを示す文字列（後ろの空白は除く）
+    pub fn oem_id(&self) -> &str {
+        core::str::from_utf8(&self.oem_id)
+            .unwrap_or("")
+            .trim_end_matches(' ')
+    }
+    pub fn revision(&self) -> u8 {
+        self.rebision
+    }
+    // XSDTに登録されている全てのテーブルのシグネチャ
+    pub fn list_tables(&self) -> impl Iterator<Item = [u8; 4]> + '_ {
+        self.xsdt().iter().map(|e| *e.signature())
+    }
     pub fn hpet(&self) -> Option<&AcpiHpetDescriptor> {
         let xsdt = self.xsdt();
         xsdt.find_table(b"HPET").map(AcpiHpetDescriptor::new)
     }
+    pub fn fadt(&self) -> Option<&AcpiFadt> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(b"FACP").map(AcpiFadt::new)
+    }
+    pub fn mcfg(&self) -> Option<&AcpiMcfgDescriptor> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(b"MCFG").map(AcpiMcfgDescriptor::new)
+    }
+    pub fn madt(&self) -> Option<&AcpiMadt> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(b"APIC").map(AcpiMadt::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn table_with_header(signature: &[u8; 4], length: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; length];
+        buf[0..4].copy_from_slice(signature);
+        buf[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+        buf
+    }
+    fn as_header(buf: &[u8]) -> &SystemDescriptionTableHeader {
+        unsafe { &*(buf.as_ptr() as *const SystemDescriptionTableHeader) }
+    }
+    fn rsdp_bytes(rsdp: &mut AcpiRsdpStruct) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                rsdp as *mut AcpiRsdpStruct as *mut u8,
+                size_of::<AcpiRsdpStruct>(),
+            )
+        }
+    }
+    // チェックサムを埋めたACPI 2.0のRSDP
+    fn rsdp_with_xsdt(xsdt: u64) -> AcpiRsdpStruct {
+        let mut rsdp = AcpiRsdpStruct {
+            signature: *b"RSD PTR ",
+            checksum: 0,
+            oem_id: *b"BOCHS ",
+            rebision: 2,
+            rsdt_address: 0,
+            length: 36,
+            xsdt,
+            extended_checksum: 0,
+            _reserved: [0; 3],
+        };
+        let sum = |b: &[u8]| b.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        rsdp.checksum = sum(&rsdp_bytes(&mut rsdp)[..RSDP_V1_LENGTH]).wrapping_neg();
+        rsdp.extended_checksum = sum(rsdp_bytes(&mut rsdp)).wrapping_neg();
+        rsdp
+    }
+
+    #[test_case]
+    fn validate_rsdp() {
+        let mut rsdp = rsdp_with_xsdt(0x1234_5000);
+        assert_eq!(rsdp.validate(), Ok(()));
+
+        rsdp.signature = *b"RSD PTX ";
+        assert_eq!(rsdp.validate(), Err(Error::Acpi("RSDP: invalid signature")));
+
+        let mut rsdp = rsdp_with_xsdt(0x1234_5000);
+        rsdp.rsdt_address = 1;
+        assert_eq!(rsdp.validate(), Err(Error::Acpi("RSDP: invalid checksum")));
+
+        // 拡張部分だけが壊れている
+        let mut rsdp = rsdp_with_xsdt(0x1234_5000);
+        rsdp._reserved[0] = 1;
+        assert_eq!(
+            rsdp.validate(),
+            Err(Error::Acpi("RSDP: invalid extended checksum"))
+        );
+    }
+
+    #[test_case]
+    fn fadt_pm1a_control_block() {
+        let mut buf = table_with_header(b"FACP", 244);
+        // PM1a_CNT_BLK (offset 64)
+        buf[64..68].copy_from_slice(&0x0404u32.to_le_bytes());
+        // X_PM1a_CNT_BLK (offset 172): system I/O space
+        buf[172] = 1;
+        buf[176..184].copy_from_slice(&0x0604u64.to_le_bytes());
+        let fadt = AcpiFadt::new(as_header(&buf));
+        assert_eq!(fadt.pm1a_control_block(), Ok(0x0604));
+
+        // ACPI 1.0のFADTでは拡張フィールドは読まない
+        buf[4..8].copy_from_slice(&116u32.to_le_bytes());
+        let fadt = AcpiFadt::new(as_header(&buf));
+        assert_eq!(fadt.pm1a_control_block(), Ok(0x0404));
+
+        buf[64..68].copy_from_slice(&0u32.to_le_bytes());
+        let fadt = AcpiFadt::new(as_header(&buf));
+        assert!(fadt.pm1a_control_block().is_err());
+    }
+
+    #[test_case]
+    fn list_tables_in_xsdt() {
+        let tables = [
+            table_with_header(b"HPET", 56),
+            table_with_header(b"APIC", 44),
+            table_with_header(b"MCFG", 44),
+        ];
+        let mut xsdt = table_with_header(b"XSDT", 36 + 8 * tables.len());
+        for (i, t) in tables.iter().enumerate() {
+            let ofs = 36 + 8 * i;
+            xsdt[ofs..ofs + 8].copy_from_slice(&(t.as_ptr() as u64).to_le_bytes());
+        }
+        let rsdp = rsdp_with_xsdt(xsdt.as_ptr() as u64);
+        assert_eq!(rsdp.oem_id(), "BOCHS");
+        assert_eq!(rsdp.revision(), 2);
+        let signatures: Vec<[u8; 4]> = rsdp.list_tables().collect();
+        assert_eq!(signatures, [*b"HPET", *b"APIC", *b"MCFG"]);
+    }
+
+    #[test_case]
+    fn malformed_xsdt_has_no_entries() {
+        // ヘッダ(36バイト)より短いlengthを持つ壊れたXSDT
+        let mut buf = table_with_header(b"XSDT", 36 + 8);
+        buf[4..8].copy_from_slice(&10u32.to_le_bytes());
+        let xsdt = unsafe { &*(buf.as_ptr() as *const Xsdt) };
+        assert_eq!(xsdt.num_of_entiries(), 0);
+        assert_eq!(xsdt.entry(0), None);
+        assert_eq!(xsdt.iter().count(), 0);
+
+        let mut mcfg = table_with_header(b"MCFG", 44);
+        mcfg[4..8].copy_from_slice(&10u32.to_le_bytes());
+        let mcfg = AcpiMcfgDescriptor::new(as_header(&mcfg));
+        assert_eq!(mcfg.num_of_entries(), 0);
+        assert!(mcfg.entry(0).is_none());
+    }
+
+    #[test_case]
+    fn xsdt_entry_is_bounds_checked() {
+        let table = table_with_header(b"HPET", 56);
+        // lengthの後ろに余分なエントリがあっても読まない
+        let mut buf = table_with_header(b"XSDT", 36 + 8 * 2);
+        buf[4..8].copy_from_slice(&(36u32 + 8).to_le_bytes());
+        buf[36..44].copy_from_slice(&(table.as_ptr() as u64).to_le_bytes());
+        buf[44..52].copy_from_slice(&u64::MAX.to_le_bytes());
+        let xsdt = unsafe { &*(buf.as_ptr() as *const Xsdt) };
+        assert_eq!(xsdt.num_of_entiries(), 1);
+        assert_eq!(xsdt.entry(0), Some(table.as_ptr()));
+        assert_eq!(xsdt.entry(1), None);
+        assert_eq!(xsdt.entry(usize::MAX), None);
+        assert_eq!(xsdt.iter().count(), 1);
+    }
+
+    #[test_case]
+    fn find_s5_package() {
+        assert_eq!(
+            find_s5_sleep_type(b"\x10\x08_S5_\x12\x06\x04\x0a\x05\x0a\x05\x00\x00"),
+            Some(5)
+        );
+        assert_eq!(
+            find_s5_sleep_type(b"\x08\\_S5_\x12\x06\x04\x00\x00\x00\x00"),
+            Some(0)
+        );
+        assert_eq!(find_s5_sleep_type(b"\x08_S4_\x12\x06\x04\x00"), None);
+        assert_eq!(
+            find_s5_sleep_type(b"\x08_S5_\x12\x08\x04\x0b\x07\x00\x0b\x07\x00"),
+            Some(7)
+        );
+        assert_eq!(
+            find_s5_sleep_type(b"\x08_S5_\x12\x0c\x04\x0c\x05\x00\x00\x00\x0c\x05\x00\x00\x00"),
+            Some(5)
+        );
+        // 解釈できないオペコードを値として使わない
+        assert_eq!(
+            find_s5_sleep_type(b"\x08_S5_\x12\x06\x04\x5b\x00\x00"),
+            None
+        );
+    }
 }
@@ -1,26 +1,36 @@
 extern crate alloc;
 
+use crate::result::Error;
 use crate::result::Result;
 use crate::uefi::EfiMemoryDescriptor;
 use crate::uefi::EfiMemoryType;
 use crate::uefi::MemoryMapHolder;
+use crate::x86::busy_loop_hint;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
+use crate::x86::interrupts_enabled;
 
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
 use alloc::boxed::Box;
 
 use core::borrow::BorrowMut;
+use core::cell::Cell;
 use core::cell::RefCell;
 use core::cmp::max;
 use core::fmt;
 use core::mem::size_of;
 use core::ops::DerefMut;
+use core::ops::Range;
 use core::ptr::null_mut;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
 
 pub fn round_up_to_nearest_pow2(v: usize) -> Result<usize> {
     1usize
         .checked_shl(usize::BITS - v.wrapping_sub(1).leading_zeros())
-        .ok_or("Out of range")
+        .ok_or(Error::InvalidArgument)
 }
 
 // 次のヘッダのアドレス、この領域のサイズ
@@ -41,15 +51,37 @@ const _: () = assert!(HEADER_SIZE.count_ones() == 1);
 
 pub const LAYOUT_PAGE_4K: Layout = unsafe { Layout::from_size_align_unchecked(4096, 4096) };
 
+// sizeとalignの調整
+// HEADER_SIZEより小さければこれに修正
+// 2のべき乗に切り上げ
+fn adjust_size_and_align(size: usize, align: usize) -> Option<(usize, usize)> {
+    let size = max(round_up_to_nearest_pow2(size).ok()?, HEADER_SIZE);
+    let align = max(align, HEADER_SIZE);
+    Some((size, align))
+}
+
 impl Header {
+    // provide()で切り出せるかどうか（切り出しはしない）
+    fn can_provide_layout(&self, size: usize, align: usize) -> bool {
+        match adjust_size_and_align(size, align) {
+            Some((size, align)) => !self.is_allocated() && self.can_provide(size, align),
+            None => false,
+        }
+    }
     fn can_provide(&self, size: usize, align: usize) -> bool {
         self.size >= size + HEADER_SIZE * 2 * align
     }
     fn is_allocated(&self) -> bool {
         self.is_allocated
     }
+    fn addr(&self) -> usize {
+        self as *const Header as usize
+    }
     fn end_addr(&self) -> usize {
-        self as *const Header as usize + self.size
+        self.addr() + self.size
+    }
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.addr() < end && start < self.end_addr()
     }
     // アドレスからヘッダを作成
     unsafe fn new_from_addr(addr: usize) -> Box<Header> {
@@ -70,11 +102,7 @@ impl Header {
     // 切り出せない場合はNone
     // 切り出せた場合はそのアドレスをSomeで返す
     fn provide(&mut self, size: usize, align: usize) -> Option<*mut u8> {
-        // sizeとalignの調整
-        // HEADER_SIZEより小さければこれに修正
-        // 2のべき乗に切り上げ
-        let size = max(round_up_to_nearest_pow2(size).ok()?, HEADER_SIZE);
-        let align = max(align, HEADER_SIZE);
+        let (size, align) = adjust_size_and_align(size, align)?;
 
         // 要求された領域を切り出す
         if self.is_allocated() || !self.can_provide(size, align) {
@@ -130,7 +158,7 @@ impl fmt::Debug for Header {
 #[test_case]
 fn round_up_to_nearest_pow2_tests() {
     // unimplemented!("Cargo test should fail, right...?")
-    assert_eq!(round_up_to_nearest_pow2(0), Err("Out of range"));
+    assert_eq!(round_up_to_nearest_pow2(0), Err(Error::InvalidArgument));
     assert_eq!(round_up_to_nearest_pow2(1), Ok(1));
     assert_eq!(round_up_to_nearest_pow2(2), Ok(2));
     assert_eq!(round_up_to_nearest_pow2(3), Ok(4));
@@ -142,76 +170,381 @@ fn round_up_to_nearest_pow2_tests() {
     assert_eq!(round_up_to_nearest_pow2(9), Ok(16));
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub free_bytes: usize,
+    pub allocated_bytes: usize,
+    pub num_free_regions: usize,
+    pub num_allocated_regions: usize,
+}
+
+// 空き領域の選び方
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    // 最初に見つかった要求を満たす領域から切り出す
+    #[default]
+    FirstFit,
+    // 要求を満たす領域のうち最も小さいものから切り出す（大きな領域の断片化を抑える）
+    BestFit,
+}
+
+// UEFIのメモリマップのCONVENTIONAL_MEMORYのうち、どこまでをヒープに渡すか
+// ヒープに渡さなかった範囲は、他のサブシステムが決まった場所から使える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapConfig {
+    // この物理アドレスより下はヒープに入れない
+    // (APのトランポリンやレガシーなDMAのために1MiB未満を残す、など)
+    pub low_memory_limit: usize,
+    // ヒープに入れる合計のバイト数の上限。Noneなら制限しない
+    pub max_size: Option<usize>,
+}
+
+impl HeapConfig {
+    // 全てのCONVENTIONAL_MEMORYをヒープに入れる
+    pub const UNRESTRICTED: Self = Self {
+        low_memory_limit: 0,
+        max_size: None,
+    };
+}
+
+// CONVENTIONAL_MEMORYの領域を、ヒープに入れる部分(true)と入れない部分(false)に分ける
+// max_sizeはメモリマップの順に先頭の領域から使い、4KiB単位で切る
+fn partition_conventional_memory(
+    memory_map: &MemoryMapHolder,
+    config: HeapConfig,
+) -> impl Iterator<Item = (Range<usize>, bool)> + '_ {
+    let mut remaining = config.max_size.unwrap_or(usize::MAX);
+    memory_map
+        .iter()
+        .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+        .flat_map(move |e| {
+            let start = e.physical_start() as usize;
+            let end = start + e.number_of_pages() as usize * 4096;
+            let heap_start = start.max(config.low_memory_limit).min(end);
+            let heap_end = heap_start + (remaining.min(end - heap_start) & !(4096 - 1));
+            remaining -= heap_end - heap_start;
+            [
+                (start..heap_start, false),
+                (heap_start..heap_end, true),
+                (heap_end..end, false),
+            ]
+        })
+        .filter(|(range, _)| !range.is_empty())
+}
+
 // アロケータの本体
 pub struct FirstFitAllocator {
     first_header: RefCell<Option<Box<Header>>>,
+    policy: Cell<AllocPolicy>,
+    // 現在割り当てられているバイト数(Layoutのサイズの合計)と、その最大値
+    allocated_bytes: Cell<usize>,
+    peak_allocated_bytes: Cell<usize>,
+    // init_with_mmapで使った設定
+    config: Cell<HeapConfig>,
+    // config.max_sizeのうち、まだヒープに入れていないバイト数
+    remaining_heap_size: Cell<usize>,
+    // APからも呼ばれるので、ヘッダのリストとカウンタはこのスピンロックを取ってから触る
+    lock: AtomicBool,
 }
 
 // FirstFitAllocatorのインスタンス
 // global_allocator: Rustのallocのクレートがこれを使うようになる
 #[global_allocator]
-pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
-};
+pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator::new();
 
+// RefCellとCellの中身は、with_lockの中でだけ書き換える
 unsafe impl Sync for FirstFitAllocator {}
 
 unsafe impl GlobalAlloc for FirstFitAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.alloc_with_options(layout)
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let mut region = Header::from_allocated_region(ptr);
-        region.is_allocated = false;
-        Box::leak(region);
+    // UEFIから受け取ったCONVENTIONAL_MEMORYは0で初期化されている保証がないので、
+    // 常に要求されたサイズだけを1回のwrite_bytesで0埋めする
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_with_options(layout);
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_lock(|| {
+            self.allocated_bytes
+                .set(self.allocated_bytes.get().saturating_sub(layout.size()));
+            let mut region = Header::from_allocated_region(ptr);
+            region.is_allocated = false;
+            Box::leak(region);
+        })
     }
 }
 
 impl FirstFitAllocator {
+    pub const fn new() -> Self {
+        Self {
+            first_header: RefCell::new(None),
+            policy: Cell::new(AllocPolicy::FirstFit),
+            allocated_bytes: Cell::new(0),
+            peak_allocated_bytes: Cell::new(0),
+            config: Cell::new(HeapConfig::UNRESTRICTED),
+            remaining_heap_size: Cell::new(usize::MAX),
+            lock: AtomicBool::new(false),
+        }
+    }
+    // 割り込みハンドラから呼ばれても同じCPUでデッドロックしないよう、ロック中は割り込みを止める
+    // ロックは再入できないので、fの中からロックを取るメソッドを呼んではいけない
+    fn with_lock<R>(&self, f: impl FnOnce() -> R) -> R {
+        let were_interrupts_enabled = interrupts_enabled();
+        disable_interrupts();
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            busy_loop_hint();
+        }
+        let result = f();
+        self.lock.store(false, Ordering::Release);
+        if were_interrupts_enabled {
+            enable_interrupts();
+        }
+        result
+    }
+    pub fn policy(&self) -> AllocPolicy {
+        self.policy.get()
+    }
+    pub fn set_policy(&self, policy: AllocPolicy) {
+        self.policy.set(policy)
+    }
     //  メモリアロケータの処理の本体
-    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+    pub fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>> {
+        self.with_lock(|| {
+            let p = match self.policy() {
+                AllocPolicy::FirstFit => self.alloc_first_fit(layout),
+                AllocPolicy::BestFit => self.alloc_best_fit(layout),
+            }?;
+            let allocated = self.allocated_bytes.get() + layout.size();
+            self.allocated_bytes.set(allocated);
+            self.peak_allocated_bytes
+                .set(max(self.peak_allocated_bytes.get(), allocated));
+            Ok(p)
+        })
+    }
+    // これまでに同時に割り当てられていたバイト数の最大値。解放しても減らない
+    pub fn peak_usage(&self) -> usize {
+        self.peak_allocated_bytes.get()
+    }
+    // 最大値を現在の使用量に戻して、ここから計測し直す
+    pub fn reset_peak(&self) {
+        self.with_lock(|| self.peak_allocated_bytes.set(self.allocated_bytes.get()))
+    }
+    // 空き領域のリストを順に見て、provideを呼び出す
+    // メモリが確保できたら、そのアドレスを返す
+    // メモリが確保できなければOutOfMemory
+    fn alloc_first_fit(&self, layout: Layout) -> Result<NonNull<u8>> {
         let mut header = self.first_header.borrow_mut();
         let mut header = header.deref_mut();
-
-        // 空き領域のリストを順に見て、provideを呼び出す
-        // メモリが確保できたら、そのアドレスを返す
-        // メモリが確保できなければNULL
         loop {
             match header {
                 Some(e) => match e.provide(layout.size(), layout.align()) {
-                    Some(p) => break p,
+                    Some(p) => break NonNull::new(p).ok_or(Error::OutOfMemory),
                     None => {
                         header = e.next_header.borrow_mut();
                         continue;
                     }
                 },
                 None => {
-                    break null_mut::<u8>();
+                    break Err(Error::OutOfMemory);
                 }
             }
         }
     }
+    // 空き領域のリストを全て見て、要求を満たす最も小さい領域から切り出す
+    fn alloc_best_fit(&self, layout: Layout) -> Result<NonNull<u8>> {
+        let mut best: Option<(usize, usize)> = None;
+        {
+            let first_header = self.first_header.borrow();
+            let mut header = first_header.as_ref();
+            let mut index = 0;
+            while let Some(e) = header {
+                if e.can_provide_layout(layout.size(), layout.align())
+                    && best.map_or(true, |(_, size)| e.size < size)
+                {
+                    best = Some((index, e.size));
+                }
+                header = e.next_header.as_ref();
+                index += 1;
+            }
+        }
+        let (best_index, _) = best.ok_or(Error::OutOfMemory)?;
+        let mut first_header = self.first_header.borrow_mut();
+        let mut header = first_header.as_mut();
+        for _ in 0..best_index {
+            header = header.and_then(|e| e.next_header.as_mut());
+        }
+        header
+            .and_then(|e| e.provide(layout.size(), layout.align()))
+            .and_then(NonNull::new)
+            .ok_or(Error::OutOfMemory)
+    }
+    // 確保できなければNULL
+    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).map_or(null_mut(), |p| p.as_ptr())
+    }
 
-    // UEFIからのメモリマップからの初期化
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
-        for e in memory_map.iter() {
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
+    // ヘッダのリストをたどって、空き領域と使用中の領域の合計を数える
+    pub fn stats(&self) -> AllocatorStats {
+        self.with_lock(|| self.stats_locked())
+    }
+    fn stats_locked(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats::default();
+        let first_header = self.first_header.borrow();
+        let mut header = first_header.as_ref();
+        while let Some(e) = header {
+            if e.is_allocated() {
+                stats.allocated_bytes += e.size;
+                stats.num_allocated_regions += 1;
+            } else {
+                stats.free_bytes += e.size;
+                stats.num_free_regions += 1;
+            }
+            header = e.next_header.as_ref();
+        }
+        stats
+    }
+
+    // [start, start + size)を空き領域のリストから取り除き、以後割り当てられないようにする
+    // 取り除いた範囲にはヘッダを書き込まないので、フレームバッファやACPIのテーブルも予約できる
+    pub fn reserve(&self, start: usize, size: usize) -> Result<()> {
+        self.with_lock(|| self.reserve_locked(start, size))
+    }
+    fn reserve_locked(&self, start: usize, size: usize) -> Result<()> {
+        let end = start.checked_add(size).ok_or(Error::InvalidArgument)?;
+        if size == 0 {
+            return Ok(());
+        }
+        let mut first_header = self.first_header.borrow_mut();
+        // 確保済みの領域と重なる場合は、リストを変更する前にエラーにする
+        let mut header = first_header.as_ref();
+        while let Some(e) = header {
+            if e.is_allocated() && e.overlaps(start, end) {
+                return Err(Error::Failed("reserve: overlaps an allocated region"));
+            }
+            header = e.next_header.as_ref();
+        }
+        let mut cursor = first_header.deref_mut();
+        while cursor.is_some() {
+            let e = cursor.as_mut().unwrap();
+            if !e.overlaps(start, end) {
+                cursor = &mut cursor.as_mut().unwrap().next_header;
                 continue;
             }
-            self.add_free_from_descriptor(e);
+            // 予約する範囲より後ろの部分を新しい空き領域にする
+            let rest = e.next_header.take();
+            let tail_addr = (end + HEADER_SIZE - 1) & !(HEADER_SIZE - 1);
+            let tail = if tail_addr + HEADER_SIZE <= e.end_addr() {
+                let mut tail = unsafe { Header::new_from_addr(tail_addr) };
+                tail.size = e.end_addr() - tail_addr;
+                tail.next_header = rest;
+                Some(tail)
+            } else {
+                rest
+            };
+            if start >= e.addr() + HEADER_SIZE {
+                // 前の部分はヘッダごと残して縮める
+                e.size = start - e.addr();
+                e.next_header = tail;
+                cursor = &mut cursor.as_mut().unwrap().next_header;
+            } else {
+                // ヘッダ自体が予約範囲にかかるので、この領域はリストから外す
+                Box::leak(cursor.take().unwrap());
+                *cursor = tail;
+            }
+        }
+        Ok(())
+    }
+
+    // ヘッダのリストを捨てて、空き領域のない状態に戻す
+    // ヘッダはDropするとpanicするので、1つずつleakする
+    pub fn reset(&self) {
+        self.with_lock(|| self.reset_locked())
+    }
+    fn reset_locked(&self) {
+        self.allocated_bytes.set(0);
+        self.peak_allocated_bytes.set(0);
+        let mut header = self.first_header.borrow_mut().take();
+        while let Some(mut e) = header {
+            header = e.next_header.take();
+            Box::leak(e);
+        }
+    }
+
+    // テストごとに、決まった状態のヒープから始められるようにする
+    #[cfg(test)]
+    pub fn reinit_with_region(&self, start: usize, size: usize) {
+        self.reset();
+        self.add_free_region(start, size);
+    }
+
+    // UEFIからのメモリマップからの初期化
+    // configで除外した範囲は、excluded_regionsで取り出せる
+    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder, config: HeapConfig) {
+        self.config.set(config);
+        let mut remaining = config.max_size.unwrap_or(usize::MAX);
+        for (range, is_heap) in partition_conventional_memory(memory_map, config) {
+            if is_heap {
+                remaining -= self.add_free_region(range.start, range.len());
+            }
         }
+        self.remaining_heap_size.set(remaining);
     }
 
-    // Descriptorから空き領域を追加
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
-        let mut start_addr = desc.physical_start() as usize;
-        let mut size = desc.number_of_pages() as usize * 4096;
+    pub fn config(&self) -> HeapConfig {
+        self.config.get()
+    }
+
+    // init_with_mmapでヒープに入れなかったCONVENTIONAL_MEMORYの範囲
+    // memory_mapはinit_with_mmapに渡したものと同じである必要がある
+    pub fn excluded_regions<'a>(
+        &self,
+        memory_map: &'a MemoryMapHolder,
+    ) -> impl Iterator<Item = Range<usize>> + 'a {
+        partition_conventional_memory(memory_map, self.config())
+            .filter(|(_, is_heap)| !is_heap)
+            .map(|(range, _)| range)
+    }
+
+    // Descriptorの領域を空き領域として追加する
+    // ブートサービスが使っていた領域など、後から空いた領域をヒープに戻すのに使う
+    // 既にヒープにある領域と重なってはいけない
+    // config.max_sizeの残りを超える分は、4KiB単位で切ってヒープに入れない
+    pub fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
+        let range = desc.physical_range();
+        let start = max(range.start as usize, self.config().low_memory_limit);
+        let end = range.end as usize;
+        if start >= end {
+            return;
+        }
+        self.with_lock(|| {
+            let remaining = self.remaining_heap_size.get();
+            let size = remaining.min(end - start) & !(4096 - 1);
+            let added = self.add_free_region_locked(start, size);
+            self.remaining_heap_size.set(remaining - added);
+        })
+    }
+
+    // [start_addr, start_addr + size)を空き領域として追加
+    // 実際に追加したバイト数を返す
+    fn add_free_region(&self, start_addr: usize, size: usize) -> usize {
+        self.with_lock(|| self.add_free_region_locked(start_addr, size))
+    }
+    fn add_free_region_locked(&self, mut start_addr: usize, mut size: usize) -> usize {
         if start_addr == 0 {
             start_addr = 4096;
             size = size.saturating_sub(4096);
         }
         if size <= 4096 {
-            return;
+            return 0;
         }
 
         // Headerの作成
@@ -233,6 +566,7 @@ impl FirstFitAllocator {
         let mut header = self.first_header.borrow_mut();
         // headerのnextにさっきまでの先頭Headerを連結
         header.as_mut().unwrap().next_header = prev_last;
+        size
     }
 }
 
@@ -240,6 +574,7 @@ impl FirstFitAllocator {
 mod test {
     use super::*;
     use alloc::vec;
+    use alloc::vec::Vec;
 
     #[test_case]
     fn malloc_iterate_free_and_alloc() {
@@ -250,6 +585,306 @@ mod test {
         }
     }
 
+    #[test_case]
+    fn alloc_zeroed_returns_zeroed_memory() {
+        for layout in [
+            LAYOUT_PAGE_4K,
+            Layout::from_size_align(1234, 8).unwrap(),
+            Layout::from_size_align(3, 1).unwrap(),
+        ] {
+            let p = unsafe { ALLOCATOR.alloc_zeroed(layout) };
+            assert!(!p.is_null());
+            assert!((p as usize) % layout.align() == 0);
+            let bytes = unsafe { core::slice::from_raw_parts(p, layout.size()) };
+            assert!(bytes.iter().all(|b| *b == 0));
+            unsafe { ALLOCATOR.dealloc(p, layout) };
+        }
+        let v = vec![0u64; 1024];
+        assert!(v.iter().all(|e| *e == 0));
+    }
+
+    // テスト用に、グローバルなアロケータから確保した領域だけを管理する独立したアロケータを作る
+    // ヘッダはDropできないので、使い終わったらreset()すること
+    fn allocator_with_region(size: usize) -> (FirstFitAllocator, usize) {
+        let allocator = FirstFitAllocator::new();
+        let base = ALLOCATOR
+            .try_alloc(Layout::from_size_align(size, 4096).unwrap())
+            .expect("Failed to allocate a region for the test")
+            .as_ptr() as usize;
+        allocator.add_free_region(base, size);
+        (allocator, base)
+    }
+
+    #[test_case]
+    fn reset_and_reinit_gives_clean_heap() {
+        const REGION_SIZE: usize = 0x10000;
+        let (allocator, base) = allocator_with_region(REGION_SIZE);
+        for size in [16, 300, 4000] {
+            allocator
+                .try_alloc(Layout::from_size_align(size, 8).unwrap())
+                .unwrap();
+        }
+        assert!(allocator.stats().num_allocated_regions > 0);
+        allocator.reset();
+        assert_eq!(allocator.stats(), AllocatorStats::default());
+        assert_eq!(
+            allocator.try_alloc(Layout::from_size_align(8, 8).unwrap()),
+            Err(Error::OutOfMemory)
+        );
+        allocator.reinit_with_region(base, REGION_SIZE);
+        assert_eq!(
+            allocator.stats(),
+            AllocatorStats {
+                free_bytes: REGION_SIZE,
+                allocated_bytes: 0,
+                num_free_regions: 1,
+                num_allocated_regions: 0,
+            }
+        );
+        allocator.reset();
+    }
+
+    #[test_case]
+    fn lock_is_released_and_interrupt_flag_restored() {
+        let (allocator, _) = allocator_with_region(0x10000);
+        let were_interrupts_enabled = interrupts_enabled();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let p = allocator.try_alloc(layout).unwrap();
+        assert!(!allocator.lock.load(Ordering::SeqCst));
+        assert_eq!(interrupts_enabled(), were_interrupts_enabled);
+        unsafe { allocator.dealloc(p.as_ptr(), layout) };
+        assert!(!allocator.lock.load(Ordering::SeqCst));
+        assert!(allocator.reserve(0, 0).is_ok());
+        assert_eq!(allocator.stats().num_allocated_regions, 0);
+        assert_eq!(interrupts_enabled(), were_interrupts_enabled);
+        allocator.reset();
+    }
+
+    #[test_case]
+    fn peak_usage_survives_free() {
+        let (allocator, _) = allocator_with_region(0x10000);
+        assert_eq!(allocator.peak_usage(), 0);
+        let large = Layout::from_size_align(0x4000, 8).unwrap();
+        let p = allocator.try_alloc(large).unwrap();
+        assert_eq!(allocator.peak_usage(), 0x4000);
+        unsafe { allocator.dealloc(p.as_ptr(), large) };
+        let small = Layout::from_size_align(0x100, 8).unwrap();
+        let q = allocator.try_alloc(small).unwrap();
+        assert_eq!(allocator.peak_usage(), 0x4000);
+        // 計測し直すと、現在の使用量が最大値になる
+        allocator.reset_peak();
+        assert_eq!(allocator.peak_usage(), 0x100);
+        unsafe { allocator.dealloc(q.as_ptr(), small) };
+        assert_eq!(allocator.peak_usage(), 0x100);
+        allocator.reset();
+    }
+
+    #[test_case]
+    fn try_alloc_returns_err_on_exhaustion() {
+        let (allocator, base) = allocator_with_region(0x10000);
+        let layout = Layout::from_size_align(256, 64).unwrap();
+        let p = allocator.try_alloc(layout).expect("try_alloc failed");
+        assert_eq!(p.as_ptr() as usize % 64, 0);
+        assert!(base <= p.as_ptr() as usize && p.as_ptr() as usize + 256 <= base + 0x10000);
+        let mut count = 1;
+        let result = loop {
+            match allocator.try_alloc(layout) {
+                Ok(_) => count += 1,
+                Err(e) => break e,
+            }
+            assert!(count < 0x10000 / 256);
+        };
+        assert_eq!(result, Error::OutOfMemory);
+        assert!(allocator.alloc_with_options(layout).is_null());
+        allocator.reset();
+    }
+
+    #[test_case]
+    fn best_fit_preserves_large_region() {
+        const SMALL: usize = 0x2000;
+        const LARGE: usize = 0x10000;
+        let (allocator, small_base) = allocator_with_region(SMALL);
+        // 後から追加した領域がリストの先頭になるので、first-fitなら大きい領域が使われる
+        let large_base = ALLOCATOR
+            .try_alloc(Layout::from_size_align(LARGE, 4096).unwrap())
+            .unwrap()
+            .as_ptr() as usize;
+        allocator.add_free_region(large_base, LARGE);
+        assert_eq!(allocator.policy(), AllocPolicy::FirstFit);
+        allocator.set_policy(AllocPolicy::BestFit);
+
+        let p = allocator
+            .try_alloc(Layout::from_size_align(64, 8).unwrap())
+            .unwrap()
+            .as_ptr() as usize;
+        assert!((small_base..small_base + SMALL).contains(&p));
+        // 大きな領域は丸ごと残っているので、大きな要求にも応えられる
+        let big = Layout::from_size_align(0x8000, 32).unwrap();
+        let p = allocator.try_alloc(big).unwrap().as_ptr() as usize;
+        assert!((large_base..large_base + LARGE).contains(&p));
+        assert_eq!(p % 32, 0);
+        allocator.reset();
+    }
+
+    #[test_case]
+    fn reserved_range_is_never_allocated() {
+        const REGION_SIZE: usize = 0x10000;
+        let (allocator, base) = allocator_with_region(REGION_SIZE);
+        let reserved = base + 0x4000..base + 0x8000;
+        assert_eq!(allocator.reserve(reserved.start, reserved.len()), Ok(()));
+        let stats = allocator.stats();
+        assert_eq!(stats.free_bytes, REGION_SIZE - reserved.len());
+        assert_eq!(stats.num_free_regions, 2);
+
+        let layout = Layout::from_size_align(0x100, 32).unwrap();
+        let mut last = null_mut::<u8>();
+        let mut count = 0;
+        loop {
+            let p = allocator.alloc_with_options(layout);
+            if p.is_null() {
+                break;
+            }
+            let p_range = p as usize..p as usize + layout.size();
+            assert!(p_range.end <= reserved.start || reserved.end <= p_range.start);
+            assert!(base <= p_range.start && p_range.end <= base + REGION_SIZE);
+            last = p;
+            count += 1;
+        }
+        assert!(count > 0);
+        assert!(allocator.reserve(last as usize, 16).is_err());
+        allocator.reset();
+    }
+
+    // CONVENTIONAL_MEMORYの領域だけからなるメモリマップを作る
+    fn conventional_memory_map(regions: &[Range<usize>]) -> MemoryMapHolder {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(regions.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(size_of::<EfiMemoryDescriptor>() as u64).to_le_bytes());
+        for r in regions {
+            bytes.extend_from_slice(&(EfiMemoryType::CONVENTIONAL_MEMORY as u64).to_le_bytes());
+            for v in [r.start as u64, 0, (r.len() / 4096) as u64, 0] {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        MemoryMapHolder::from_bytes(&bytes).expect("Failed to build a memory map")
+    }
+
+    #[test_case]
+    fn heap_config_splits_conventional_memory() {
+        let memory_map =
+            conventional_memory_map(&[0x1000..0x9f000, 0xf0000..0x300000, 0x400000..0x800000]);
+        let config = HeapConfig {
+            low_memory_limit: 0x100000,
+            max_size: Some(0x400000),
+        };
+        let mut heap = Vec::new();
+        let mut excluded = Vec::new();
+        for (range, is_heap) in partition_conventional_memory(&memory_map, config) {
+            if is_heap {
+                heap.push(range);
+            } else {
+                excluded.push(range);
+            }
+        }
+        assert_eq!(heap, [0x100000..0x300000, 0x400000..0x600000]);
+        assert_eq!(
+            excluded,
+            [0x1000..0x9f000, 0xf0000..0x100000, 0x600000..0x800000]
+        );
+        let all: Vec<_> =
+            partition_conventional_memory(&memory_map, HeapConfig::UNRESTRICTED).collect();
+        assert_eq!(
+            all,
+            [
+                (0x1000..0x9f000, true),
+                (0xf0000..0x300000, true),
+                (0x400000..0x800000, true)
+            ]
+        );
+    }
+
+    #[test_case]
+    fn low_memory_reservation_keeps_heap_above_1mib() {
+        const REGION_SIZE: usize = 0x10000;
+        let base = ALLOCATOR
+            .try_alloc(Layout::from_size_align(REGION_SIZE, 4096).unwrap())
+            .unwrap()
+            .as_ptr() as usize;
+        assert!(base >= 0x100000);
+        // 1MiB未満の領域にはヘッダが書き込まれないので、実際に空いている必要はない
+        let low = 0x1000..0x9f000;
+        let memory_map = conventional_memory_map(&[low.clone(), base..base + REGION_SIZE]);
+        let allocator = FirstFitAllocator::new();
+        let config = HeapConfig {
+            low_memory_limit: 0x100000,
+            max_size: None,
+        };
+        allocator.init_with_mmap(&memory_map, config);
+        assert_eq!(allocator.config(), config);
+        assert_eq!(allocator.stats().free_bytes, REGION_SIZE);
+        {
+            let first_header = allocator.first_header.borrow();
+            let mut header = first_header.as_ref();
+            while let Some(e) = header {
+                assert!(e.addr() >= 0x100000);
+                header = e.next_header.as_ref();
+            }
+        }
+        let layout = Layout::from_size_align(0x100, 32).unwrap();
+        while let Ok(p) = allocator.try_alloc(layout) {
+            assert!((base..base + REGION_SIZE).contains(&(p.as_ptr() as usize)));
+        }
+        let excluded: Vec<_> = allocator.excluded_regions(&memory_map).collect();
+        assert_eq!(excluded, [low]);
+        allocator.reset();
+    }
+
+    #[test_case]
+    fn descriptors_added_later_respect_max_size() {
+        const REGION_SIZE: usize = 0x10000;
+        let layout = Layout::from_size_align(REGION_SIZE * 3, 4096).unwrap();
+        let base = ALLOCATOR.try_alloc(layout).unwrap().as_ptr() as usize;
+        let memory_map =
+            conventional_memory_map(core::slice::from_ref(&(base..base + REGION_SIZE)));
+        let allocator = FirstFitAllocator::new();
+        allocator.init_with_mmap(
+            &memory_map,
+            HeapConfig {
+                low_memory_limit: 0,
+                max_size: Some(REGION_SIZE + REGION_SIZE / 2),
+            },
+        );
+        assert_eq!(allocator.stats().free_bytes, REGION_SIZE);
+        // 後から空いた領域も、max_sizeの残りの分だけヒープに入る
+        let later = conventional_memory_map(&[
+            base + REGION_SIZE..base + REGION_SIZE * 2,
+            base + REGION_SIZE * 2..base + REGION_SIZE * 3,
+        ]);
+        for desc in later.iter() {
+            allocator.add_free_from_descriptor(desc);
+        }
+        assert_eq!(allocator.stats().free_bytes, REGION_SIZE + REGION_SIZE / 2);
+        // 1ページしかなくヒープに入らなかった領域は、max_sizeの残りを減らさない
+        allocator.reset();
+        allocator.init_with_mmap(
+            &memory_map,
+            HeapConfig {
+                low_memory_limit: 0,
+                max_size: Some(REGION_SIZE + REGION_SIZE / 2),
+            },
+        );
+        let later = conventional_memory_map(&[
+            base + REGION_SIZE..base + REGION_SIZE + 4096,
+            base + REGION_SIZE * 2..base + REGION_SIZE * 3,
+        ]);
+        for desc in later.iter() {
+            allocator.add_free_from_descriptor(desc);
+        }
+        assert_eq!(allocator.stats().free_bytes, REGION_SIZE + REGION_SIZE / 2);
+        allocator.reset();
+        unsafe { ALLOCATOR.dealloc(base as *mut u8, layout) };
+    }
+
     #[test_case]
     fn malloc_align() {
         let mut pointers = [null_mut::<u8>(); 100];
@@ -0,0 +1,216 @@
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::read_msr;
+use crate::x86::write_msr;
+use crate::x86::MSR_IA32_APIC_BASE;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+// IA32_APIC_BASEのビット
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APICのレジスタのオフセット
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS_INTERRUPT_VECTOR: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+
+// Interrupt Command Registerのビット
+const ICR_DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const SVR_APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LvtTimerMode {
+    OneShot = 0b00 << 17,
+    Periodic = 0b01 << 17,
+    TscDeadline = 0b10 << 17,
+}
+
+// 他のCPUに送るプロセッサ間割り込み
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipi {
+    Init,
+    // ベクタVVを受け取ったCPUは、リアルモードで物理アドレス0xVV000から実行を始める
+    Startup(u8),
+}
+impl Ipi {
+    // ICRに書き込む値。上位32bitがICR_HIGH(宛先のAPIC ID)、下位32bitがICR_LOW
+    // 宛先は物理モード、トリガはエッジ
+    pub fn icr(&self, apic_id: u8) -> u64 {
+        let low = match self {
+            Ipi::Init => ICR_DELIVERY_MODE_INIT,
+            Ipi::Startup(vector) => ICR_DELIVERY_MODE_STARTUP | *vector as u32,
+        } | ICR_LEVEL_ASSERT;
+        ((apic_id as u64) << 56) | low as u64
+    }
+}
+
+// Local APICのレジスタ（メモリマップドIO）
+// 各CPUは同じアドレスで自分のLocal APICにアクセスする
+pub struct LocalApic {
+    base: usize,
+}
+impl LocalApic {
+    pub fn new(base: usize) -> Self {
+        Self { base }
+    }
+    // IA32_APIC_BASE MSRから現在のCPUのLocal APICのアドレスを読む
+    pub fn current() -> Self {
+        Self::new((read_msr(MSR_IA32_APIC_BASE) & APIC_BASE_ADDR_MASK) as usize)
+    }
+    pub fn base(&self) -> usize {
+        self.base
+    }
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8
+    }
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+    pub fn spurious_interrupt_vector(&self) -> u32 {
+        self.read(REG_SPURIOUS_INTERRUPT_VECTOR)
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.spurious_interrupt_vector() & SVR_APIC_SOFTWARE_ENABLE != 0
+    }
+    // MSRのグローバル有効化ビットとSpurious Interrupt Vector Registerの有効化ビットを立てる
+    pub fn enable(&self) {
+        let apic_base = read_msr(MSR_IA32_APIC_BASE);
+        if apic_base & APIC_BASE_GLOBAL_ENABLE == 0 {
+            unsafe { write_msr(MSR_IA32_APIC_BASE, apic_base | APIC_BASE_GLOBAL_ENABLE) }
+        }
+        let svr = self.spurious_interrupt_vector() & !0xff;
+        self.write(
+            REG_SPURIOUS_INTERRUPT_VECTOR,
+            svr | SVR_APIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32,
+        );
+    }
+    // 割り込み処理の終了を通知する
+    // これを書き込まないと次の割り込みが来なくなる
+    pub fn send_eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+    pub fn set_lvt_timer(&self, vector: u8, mode: LvtTimerMode) {
+        self.write(REG_LVT_TIMER, mode as u32 | vector as u32);
+    }
+    pub fn mask_lvt_timer(&self) {
+        let lvt = self.read(REG_LVT_TIMER);
+        self.write(REG_LVT_TIMER, lvt | LVT_MASKED);
+    }
+    // ICR_LOWへの書き込みで送信されるので、先にICR_HIGHを書く
+    pub fn send_ipi(&self, apic_id: u8, ipi: Ipi) {
+        let icr = ipi.icr(apic_id);
+        self.write(REG_ICR_HIGH, (icr >> 32) as u32);
+        self.write(REG_ICR_LOW, icr as u32);
+        while self.read(REG_ICR_LOW) & ICR_DELIVERY_STATUS_PENDING != 0 {
+            busy_loop_hint();
+        }
+    }
+}
+
+// I/O APICのレジスタ
+// IOREGSELにレジスタ番号を書いてから、IOWINで読み書きする
+const IOAPIC_REG_SELECT: usize = 0x00;
+const IOAPIC_REG_WINDOW: usize = 0x10;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+// デバイスの割り込み(GSI)をLocal APICに転送するI/O APIC
+pub struct IoApic {
+    base: usize,
+    global_system_interrupt_base: u32,
+}
+impl IoApic {
+    pub fn new(base: usize, global_system_interrupt_base: u32) -> Self {
+        Self {
+            base,
+            global_system_interrupt_base,
+        }
+    }
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base + IOAPIC_REG_SELECT) as *mut u32, reg);
+            read_volatile((self.base + IOAPIC_REG_WINDOW) as *const u32)
+        }
+    }
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            write_volatile((self.base + IOAPIC_REG_SELECT) as *mut u32, reg);
+            write_volatile((self.base + IOAPIC_REG_WINDOW) as *mut u32, value);
+        }
+    }
+    // このI/O APICが受け持つGSIの数
+    pub fn num_of_entries(&self) -> u32 {
+        ((self.read(0x01) >> 16) & 0xff) + 1
+    }
+    // GSIの割り込みを、エッジトリガ・Fixedでapic_idのCPUのvectorに送る
+    pub fn redirect(&self, gsi: u32, vector: u8, apic_id: u8) -> Result<()> {
+        let index = gsi
+            .checked_sub(self.global_system_interrupt_base)
+            .filter(|i| *i < self.num_of_entries())
+            .ok_or(Error::InvalidArgument)?;
+        let reg = IOAPIC_REDIRECTION_TABLE + index * 2;
+        self.write(reg + 1, (apic_id as u32) << 24);
+        self.write(reg, vector as u32);
+        Ok(())
+    }
+    pub fn redirection_entry(&self, gsi: u32) -> Option<u64> {
+        let index = gsi.checked_sub(self.global_system_interrupt_base)?;
+        let reg = IOAPIC_REDIRECTION_TABLE + index * 2;
+        Some(((self.read(reg + 1) as u64) << 32) | self.read(reg) as u64)
+    }
+}
+
+pub fn send_eoi() {
+    LocalApic::current().send_eoi()
+}
+
+pub fn set_lvt_timer(vector: u8, mode: LvtTimerMode) {
+    LocalApic::current().set_lvt_timer(vector, mode)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn enabling_local_apic_sets_svr_enable_bit() {
+        let apic = LocalApic::current();
+        apic.enable();
+        assert!(apic.is_enabled());
+        assert_eq!(
+            apic.spurious_interrupt_vector() & 0xff,
+            SPURIOUS_INTERRUPT_VECTOR as u32
+        );
+    }
+
+    #[test_case]
+    fn ipi_encoding() {
+        // INIT: Delivery Mode 101, Level Assert, 宛先はICR_HIGHのbit 24-31
+        assert_eq!(Ipi::Init.icr(3), 0x0300_0000_0000_4500);
+        // SIPI: Delivery Mode 110, ベクタ0x08は0x8000から実行を始める
+        assert_eq!(Ipi::Startup(0x08).icr(1), 0x0100_0000_0000_4608);
+        assert_eq!(Ipi::Startup(0x9f).icr(0xff), 0xff00_0000_0000_469f);
+    }
+
+    #[test_case]
+    fn lvt_timer_mode_bits() {
+        assert_eq!(LvtTimerMode::OneShot as u32, 0);
+        assert_eq!(LvtTimerMode::Periodic as u32, 1 << 17);
+        assert_eq!(LvtTimerMode::TscDeadline as u32, 1 << 18);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::graphics::Bitmap;
+use crate::result::Error;
+use crate::result::Result;
+
+const BMP_FILE_HEADER_SIZE: usize = 14;
+const BMP_INFO_HEADER_MIN_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+
+fn read_u16(data: &[u8], ofs: usize) -> Result<u16> {
+    data.get(ofs..ofs + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(Error::Failed("BMP: unexpected end of data"))
+}
+
+fn read_u32(data: &[u8], ofs: usize) -> Result<u32> {
+    data.get(ofs..ofs + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(Error::Failed("BMP: unexpected end of data"))
+}
+
+// 無圧縮の24/32bit BMP画像
+// ピクセルデータは元のバイト列を参照する
+pub struct BmpImage<'a> {
+    pixels: &'a [u8],
+    width: i64,
+    height: i64,
+    bytes_per_pixel: usize,
+    row_stride: usize,
+    top_down: bool,
+}
+
+pub fn parse_bmp(data: &[u8]) -> Result<BmpImage> {
+    if data.get(0..2) != Some(b"BM") {
+        return Err(Error::Failed("BMP: invalid magic"));
+    }
+    let pixel_offset = read_u32(data, 10)? as usize;
+    let info_header_size = read_u32(data, BMP_FILE_HEADER_SIZE)? as usize;
+    if info_header_size < BMP_INFO_HEADER_MIN_SIZE {
+        return Err(Error::Failed("BMP: unsupported info header"));
+    }
+    let width = read_u32(data, 18)? as i32 as i64;
+    let height = read_u32(data, 22)? as i32 as i64;
+    let bpp = read_u16(data, 28)?;
+    let compression = read_u32(data, 30)?;
+    if compression != BI_RGB {
+        return Err(Error::Failed("BMP: compressed images are not supported"));
+    }
+    let bytes_per_pixel = match bpp {
+        24 => 3,
+        32 => 4,
+        _ => return Err(Error::Failed("BMP: only 24/32 bpp images are supported")),
+    };
+    if width <= 0 || height == 0 {
+        return Err(Error::Failed("BMP: invalid image size"));
+    }
+    // heightが負の場合は上の行から、正の場合は下の行から格納されている
+    let top_down = height < 0;
+    let height = height.abs();
+    // 各行は4バイト境界にパディングされる
+    // ヘッダの値は信用できないので、オーバーフローする大きさはエラーにする
+    let too_large = Error::Failed("BMP: image is too large");
+    let row_stride = (width as usize)
+        .checked_mul(bytes_per_pixel)
+        .and_then(|row| row.checked_add(3))
+        .ok_or(too_large)?
+        & !3;
+    let pixels_end = row_stride
+        .checked_mul(height as usize)
+        .and_then(|size| size.checked_add(pixel_offset))
+        .ok_or(too_large)?;
+    let pixels = data
+        .get(pixel_offset..pixels_end)
+        .ok_or(Error::Failed("BMP: pixel data is truncated"))?;
+    Ok(BmpImage {
+        pixels,
+        width,
+        height,
+        bytes_per_pixel,
+        row_stride,
+        top_down,
+    })
+}
+
+impl<'a> BmpImage<'a> {
+    pub fn width(&self) -> i64 {
+        self.width
+    }
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+    // (x, y)のピクセルの色を0x00RRGGBBで返す（yは画像の上端から）
+    pub fn pixel(&self, x: i64, y: i64) -> Option<u32> {
+        if !(0..self.width).contains(&x) || !(0..self.height).contains(&y) {
+            return None;
+        }
+        let row = if self.top_down {
+            y
+        } else {
+            self.height - 1 - y
+        } as usize;
+        let ofs = row * self.row_stride + x as usize * self.bytes_per_pixel;
+        // BMPのピクセルはB, G, Rの順に並んでいる
+        let b = self.pixels[ofs] as u32;
+        let g = self.pixels[ofs + 1] as u32;
+        let r = self.pixels[ofs + 2] as u32;
+        Some((r << 16) | (g << 8) | b)
+    }
+    // dstの(x, y)に描画する。dstからはみ出した部分は描画しない
+    pub fn blit<T: Bitmap>(&self, dst: &mut T, x: i64, y: i64) {
+        let format = dst.pixel_format();
+        for py in 0..self.height {
+            for px in 0..self.width {
+                if let (Some(color), Some(p)) =
+                    (self.pixel(px, py), dst.pixel_at_mut(x + px, y + py))
+                {
+                    *p = format.encode(color);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphics::MockBitmap;
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    // 2x2, 24bitのBMP
+    // 上段: 赤, 緑 / 下段: 青, 白
+    fn tiny_bmp() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&(54u32 + 16).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&BI_RGB.to_le_bytes());
+        data.extend_from_slice(&[0u8; 20]);
+        // 下の行から: 青, 白, パディング
+        data.extend_from_slice(&[0xff, 0x00, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00]);
+        // 上の行: 赤, 緑, パディング
+        data.extend_from_slice(&[0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00]);
+        data
+    }
+
+    #[test_case]
+    fn parse_tiny_bmp() {
+        let data = tiny_bmp();
+        let image = parse_bmp(&data).expect("parse_bmp failed");
+        assert_eq!((image.width(), image.height()), (2, 2));
+        assert_eq!(image.pixel(0, 0), Some(0xff0000));
+        assert_eq!(image.pixel(1, 0), Some(0x00ff00));
+        assert_eq!(image.pixel(0, 1), Some(0x0000ff));
+        assert_eq!(image.pixel(1, 1), Some(0xffffff));
+        assert_eq!(image.pixel(2, 0), None);
+    }
+
+    #[test_case]
+    fn blit_tiny_bmp() {
+        let data = tiny_bmp();
+        let image = parse_bmp(&data).unwrap();
+        let mut dst = MockBitmap::new(4, 4);
+        image.blit(&mut dst, 1, 2);
+        assert_eq!(dst.pixel(1, 2), 0xff0000);
+        assert_eq!(dst.pixel(2, 2), 0x00ff00);
+        assert_eq!(dst.pixel(1, 3), 0x0000ff);
+        assert_eq!(dst.pixel(2, 3), 0xffffff);
+        assert_eq!(dst.count_non_zero(0, 0, 4, 4), 4);
+        // はみ出した部分は描画されない
+        let mut dst = MockBitmap::new(4, 4);
+        image.blit(&mut dst, 3, 3);
+        assert_eq!(dst.pixel(3, 3), 0xff0000);
+        assert_eq!(dst.count_non_zero(0, 0, 4, 4), 1);
+    }
+
+    #[test_case]
+    fn reject_invalid_bmp() {
+        let mut data = tiny_bmp();
+        assert!(parse_bmp(&data[..60]).is_err());
+        data[30] = 1;
+        assert_eq!(
+            parse_bmp(&data).err(),
+            Some(Error::Failed("BMP: compressed images are not supported"))
+        );
+        data[0] = b'X';
+        assert!(parse_bmp(&data).is_err());
+        // ヘッダに極端な大きさが書かれていても、panicせずにエラーになる
+        let mut data = tiny_bmp();
+        data[18..22].copy_from_slice(&i32::MAX.to_le_bytes());
+        data[22..26].copy_from_slice(&i32::MIN.to_le_bytes());
+        data[28..30].copy_from_slice(&32u16.to_le_bytes());
+        data[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_bmp(&data).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::graphics::BitmapTextWriter;
+use crate::mutex::Mutex;
+use crate::uefi::VramBufferInfo;
+use core::fmt;
+
+// 画面に文字を出力するグローバルなコンソール
+static CONSOLE: Mutex<Option<BitmapTextWriter<VramBufferInfo>>> = Mutex::new(None);
+
+// init_vramで得たフレームバッファの所有権をコンソールに移す
+// フレームバッファは物理アドレスだが、UEFIやinit_pagingでアイデンティティマップされているので
+// 以降ずっと('static)アクセスできる
+pub fn init(vram: VramBufferInfo) {
+    let mut console = CONSOLE.lock();
+    assert!(console.is_none());
+    *console = Some(BitmapTextWriter::new(vram));
+}
+
+// 割り込みハンドラが描画中のコードに割り込んだ場合はロックが取れないので、画面には出さない
+pub fn write_fmt(args: fmt::Arguments) {
+    let Ok(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    if let Some(w) = &mut *console {
+        fmt::write(w, args).expect("Failed to write to CONSOLE");
+    }
+}
+
+// 前景色を一時的にfgに変えて書き込む
+pub fn write_fmt_with_fg(fg: u32, args: fmt::Arguments) {
+    let Ok(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    if let Some(w) = &mut *console {
+        let prev = w.fg();
+        w.set_fg(fg);
+        let result = fmt::write(w, args);
+        w.set_fg(prev);
+        result.expect("Failed to write to CONSOLE");
+    }
+}
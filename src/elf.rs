@@ -0,0 +1,353 @@
+extern crate alloc;
+
+use crate::frame_allocator::alloc_frame;
+use crate::frame_allocator::free_frame;
+use crate::frame_allocator::free_frames;
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::is_no_execute_enabled;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::read_unaligned;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_TYPE_DYN: u16 = 3;
+const ELF_MACHINE_X86_64: u16 = 62;
+
+const PROGRAM_TYPE_LOAD: u32 = 1;
+const PROGRAM_FLAG_EXECUTE: u32 = 1 << 0;
+const PROGRAM_FLAG_WRITE: u32 = 1 << 1;
+
+// ユーザープログラムは仮想アドレス空間の下半分に置く
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Elf64Header {
+    ident: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_entry_size: u16,
+    program_header_count: u16,
+    section_header_entry_size: u16,
+    section_header_count: u16,
+    section_name_index: u16,
+}
+const _: () = assert!(size_of::<Elf64Header>() == 64);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Elf64ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+const _: () = assert!(size_of::<Elf64ProgramHeader>() == 56);
+
+// 読み込んだプログラムの開始アドレス(仮想アドレス)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryPoint(pub u64);
+
+// include_bytes!のデータは揃っていないので、read_unalignedで読む
+fn read_struct<T: Copy>(data: &[u8], offset: u64) -> Result<T> {
+    let offset = usize::try_from(offset).map_err(|_| Error::Failed("ELF: offset is too large"))?;
+    if offset
+        .checked_add(size_of::<T>())
+        .map_or(true, |end| end > data.len())
+    {
+        return Err(Error::Failed("ELF: file is truncated"));
+    }
+    Ok(unsafe { read_unaligned(data.as_ptr().add(offset) as *const T) })
+}
+
+fn parse_header(data: &[u8]) -> Result<Elf64Header> {
+    let header: Elf64Header = read_struct(data, 0)?;
+    if header.ident[0..4] != ELF_MAGIC {
+        return Err(Error::Failed("ELF: invalid magic"));
+    }
+    if header.ident[4] != ELF_CLASS_64 || header.ident[5] != ELF_DATA_LITTLE_ENDIAN {
+        return Err(Error::Failed("ELF: not a little-endian ELF64 file"));
+    }
+    if header.machine != ELF_MACHINE_X86_64 {
+        return Err(Error::Failed("ELF: not an x86-64 executable"));
+    }
+    match header.file_type {
+        ELF_TYPE_EXEC => (),
+        // 再配置(リロケーション)はまだできないので、PIEは読み込めない
+        ELF_TYPE_DYN => return Err(Error::Failed("ELF: PIE is not supported")),
+        _ => return Err(Error::Failed("ELF: not an executable file")),
+    }
+    if (header.program_header_entry_size as usize) < size_of::<Elf64ProgramHeader>() {
+        return Err(Error::Failed("ELF: invalid program header size"));
+    }
+    Ok(header)
+}
+
+fn program_headers(data: &[u8], header: &Elf64Header) -> Result<Vec<Elf64ProgramHeader>> {
+    (0..header.program_header_count as u64)
+        .map(|i| {
+            let offset = i
+                .checked_mul(header.program_header_entry_size as u64)
+                .and_then(|o| o.checked_add(header.program_header_offset))
+                .ok_or(Error::Failed("ELF: invalid program header offset"))?;
+            read_struct(data, offset)
+        })
+        .collect()
+}
+
+fn page_attr(flags: u32) -> PageAttr {
+    match (
+        flags & PROGRAM_FLAG_WRITE != 0,
+        flags & PROGRAM_FLAG_EXECUTE == 0 && is_no_execute_enabled(),
+    ) {
+        (false, false) => PageAttr::ReadOnly,
+        (false, true) => PageAttr::ReadOnlyNoExecute,
+        (true, false) => PageAttr::WriteBack,
+        (true, true) => PageAttr::WriteBackNoExecute,
+    }
+}
+
+// 読み込むページ
+struct LoadPage {
+    vaddr: u64,
+    frame: u64,
+    // このページにかかる全てのセグメントのフラグ
+    flags: u32,
+}
+
+// セグメントを検証して、読み込むページの一覧を作る。フレームはまだ割り当てない
+fn plan_pages(
+    data: &[u8],
+    segments: &[Elf64ProgramHeader],
+    page_table: &PML4,
+) -> Result<Vec<LoadPage>> {
+    // vaddrとフラグ
+    let mut pages: BTreeMap<u64, u32> = BTreeMap::new();
+    let free = free_frames();
+    for ph in segments {
+        let file_end = ph
+            .offset
+            .checked_add(ph.file_size)
+            .ok_or(Error::Failed("ELF: invalid segment"))?;
+        if ph.file_size > ph.memory_size || file_end > data.len() as u64 {
+            return Err(Error::Failed("ELF: segment is out of the file"));
+        }
+        let vaddr_end = ph
+            .vaddr
+            .checked_add(ph.memory_size)
+            .filter(|end| *end <= USER_SPACE_END)
+            .ok_or(Error::Failed("ELF: segment is out of the user space"))?;
+        let page_start = ph.vaddr & !(PAGE_SIZE as u64 - 1);
+        // 割り当てられないほど大きなセグメントは、ページの一覧を作る前に弾く
+        let num_pages = (vaddr_end - page_start).div_ceil(PAGE_SIZE as u64);
+        if num_pages > free.saturating_sub(pages.len()) as u64 {
+            return Err(Error::Failed("ELF: segment is larger than the free frames"));
+        }
+        for vaddr in (page_start..vaddr_end).step_by(PAGE_SIZE) {
+            // 1つのページに2つのセグメントがかかる場合は、同じフレームを使い属性を合わせる
+            if let Some(flags) = pages.get_mut(&vaddr) {
+                *flags |= ph.flags;
+                continue;
+            }
+            if page_table.translate(vaddr).is_ok() {
+                return Err(Error::Failed("ELF: segment overlaps an existing mapping"));
+            }
+            pages.insert(vaddr, ph.flags);
+        }
+    }
+    Ok(pages
+        .into_iter()
+        .map(|(vaddr, flags)| LoadPage {
+            vaddr,
+            frame: 0,
+            flags,
+        })
+        .collect())
+}
+
+// 割り当てたフレームを返す。mappedまでのページはマップも外す
+fn release_pages(pages: &[LoadPage], mapped: usize, page_table: &mut PML4) {
+    for (i, page) in pages.iter().enumerate() {
+        if i < mapped {
+            let _ = page_table.unmap(page.vaddr as usize, PAGE_SIZE);
+        }
+        if page.frame != 0 {
+            let _ = free_frame(page.frame as usize);
+        }
+    }
+}
+
+// 静的リンクされたELF64の実行ファイルのPT_LOADセグメントを、page_tableにマップする
+// 全てのセグメントを検証してからFrameAllocatorでフレームを割り当て、ファイルの内容をコピーする
+// ファイルサイズを超える部分(.bss)は0で埋められたままにする
+// 途中で失敗した場合は、割り当てたフレームを返してマップも外す
+// ページと途中のページテーブルにはUSERビットを立てていないので、まだユーザーモードでは実行できない
+pub fn load_elf(data: &[u8], page_table: &mut PML4) -> Result<EntryPoint> {
+    let header = parse_header(data)?;
+    let segments: Vec<Elf64ProgramHeader> = program_headers(data, &header)?
+        .into_iter()
+        .filter(|ph| ph.segment_type == PROGRAM_TYPE_LOAD && ph.memory_size != 0)
+        .collect();
+    let mut pages = plan_pages(data, &segments, page_table)?;
+    for i in 0..pages.len() {
+        match alloc_frame() {
+            Ok(frame) => pages[i].frame = frame as u64,
+            Err(e) => {
+                release_pages(&pages, 0, page_table);
+                return Err(e);
+            }
+        }
+    }
+    for ph in &segments {
+        for page in pages
+            .iter()
+            .filter(|p| p.vaddr < ph.vaddr + ph.file_size && ph.vaddr < p.vaddr + PAGE_SIZE as u64)
+        {
+            // このページにかかるファイルの内容をコピーする
+            let copy_start = page.vaddr.max(ph.vaddr);
+            let copy_end = (page.vaddr + PAGE_SIZE as u64).min(ph.vaddr + ph.file_size);
+            let src = data[(ph.offset + copy_start - ph.vaddr) as usize..].as_ptr();
+            let dst = (page.frame + copy_start - page.vaddr) as *mut u8;
+            unsafe { copy_nonoverlapping(src, dst, (copy_end - copy_start) as usize) };
+        }
+    }
+    for (i, page) in pages.iter().enumerate() {
+        if let Err(e) = page_table.create_mapping(
+            page.vaddr,
+            page.vaddr + PAGE_SIZE as u64,
+            page.frame,
+            page_attr(page.flags),
+        ) {
+            release_pages(&pages, i, page_table);
+            return Err(e);
+        }
+    }
+    Ok(EntryPoint(header.entry))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame_allocator::FRAME_ALLOCATOR;
+    use crate::x86::TranslationResult;
+    use core::mem::offset_of;
+
+    const CODE: [u8; 8] = [0x48, 0xc7, 0xc0, 0x2a, 0x00, 0x00, 0x00, 0xc3];
+    const BASE: u64 = 0x40_0000;
+
+    // ELFヘッダ、PT_LOADのプログラムヘッダ1つ、コードの順に並べた最小のELF
+    // セグメントはファイル全体と、その後ろの2ページ分の.bss
+    fn minimal_elf(file_type: u16, machine: u16) -> Vec<u8> {
+        let code_offset = (size_of::<Elf64Header>() + size_of::<Elf64ProgramHeader>()) as u64;
+        let file_size = code_offset + CODE.len() as u64;
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&ELF_MAGIC);
+        elf.extend_from_slice(&[ELF_CLASS_64, ELF_DATA_LITTLE_ENDIAN, 1]);
+        elf.resize(16, 0);
+        elf.extend_from_slice(&file_type.to_le_bytes());
+        elf.extend_from_slice(&machine.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&(BASE + code_offset).to_le_bytes());
+        elf.extend_from_slice(&(size_of::<Elf64Header>() as u64).to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for v in [64u16, 56, 1, 64, 0, 0] {
+            elf.extend_from_slice(&v.to_le_bytes());
+        }
+        elf.extend_from_slice(&PROGRAM_TYPE_LOAD.to_le_bytes());
+        elf.extend_from_slice(&(PROGRAM_FLAG_EXECUTE | PROGRAM_FLAG_WRITE).to_le_bytes());
+        for v in [
+            0,
+            BASE,
+            BASE,
+            file_size,
+            file_size + 2 * PAGE_SIZE as u64,
+            PAGE_SIZE as u64,
+        ] {
+            elf.extend_from_slice(&v.to_le_bytes());
+        }
+        elf.extend_from_slice(&CODE);
+        elf
+    }
+
+    fn read_mapped(table: &PML4, vaddr: u64) -> u8 {
+        match table.translate(vaddr) {
+            Ok(TranslationResult::PageMapped4K { phys }) => unsafe { *(phys as *const u8) },
+            r => panic!("{vaddr:#X} is not mapped: {r:?}"),
+        }
+    }
+
+    #[test_case]
+    fn load_minimal_elf() {
+        let elf = minimal_elf(ELF_TYPE_EXEC, ELF_MACHINE_X86_64);
+        let mut table = PML4::new();
+        let entry = load_elf(&elf, &mut table).expect("load_elf failed");
+        assert_eq!(entry, EntryPoint(BASE + 120));
+        for (i, b) in CODE.iter().enumerate() {
+            assert_eq!(read_mapped(&table, entry.0 + i as u64), *b);
+        }
+        assert_eq!(read_mapped(&table, BASE), 0x7f);
+        // .bssは0で埋められ、最後のページまでマップされている
+        let bss = BASE + elf.len() as u64;
+        for vaddr in [bss, bss + PAGE_SIZE as u64, bss + 2 * PAGE_SIZE as u64 - 1] {
+            assert_eq!(read_mapped(&table, vaddr), 0);
+        }
+        assert!(table.translate(BASE + 3 * PAGE_SIZE as u64).is_err());
+        // 同じアドレスに重ねて読み込むことはできず、フレームも割り当てられない
+        let allocated_frames = FRAME_ALLOCATOR.lock().allocated_frames();
+        assert!(load_elf(&elf, &mut table).is_err());
+        assert_eq!(FRAME_ALLOCATOR.lock().allocated_frames(), allocated_frames);
+    }
+
+    #[test_case]
+    fn reject_unsupported_elf() {
+        let mut table = PML4::new();
+        let mut bad_magic = minimal_elf(ELF_TYPE_EXEC, ELF_MACHINE_X86_64);
+        bad_magic[1] = b'e';
+        assert!(load_elf(&bad_magic, &mut table).is_err());
+        let aarch64 = minimal_elf(ELF_TYPE_EXEC, 183);
+        assert!(load_elf(&aarch64, &mut table).is_err());
+        let pie = minimal_elf(ELF_TYPE_DYN, ELF_MACHINE_X86_64);
+        assert_eq!(
+            load_elf(&pie, &mut table),
+            Err(Error::Failed("ELF: PIE is not supported"))
+        );
+        let elf = minimal_elf(ELF_TYPE_EXEC, ELF_MACHINE_X86_64);
+        assert!(load_elf(&elf[..100], &mut table).is_err());
+        assert!(load_elf(&elf[..elf.len() - 1], &mut table).is_err());
+    }
+
+    #[test_case]
+    fn reject_segment_larger_than_free_frames() {
+        let mut table = PML4::new();
+        let mut elf = minimal_elf(ELF_TYPE_EXEC, ELF_MACHINE_X86_64);
+        // PT_LOADのmemory_size
+        let memory_size_offset =
+            size_of::<Elf64Header>() + offset_of!(Elf64ProgramHeader, memory_size);
+        let memory_size = (free_frames() as u64 + 1) * PAGE_SIZE as u64;
+        elf[memory_size_offset..memory_size_offset + 8].copy_from_slice(&memory_size.to_le_bytes());
+        assert_eq!(
+            load_elf(&elf, &mut table),
+            Err(Error::Failed("ELF: segment is larger than the free frames"))
+        );
+    }
+}
@@ -3,13 +3,25 @@ extern crate alloc;
 use crate::info;
 
 use crate::hpet::global_timestamp;
+use crate::hpet::set_oneshot_timer;
+use crate::hpet::TIMER_QUEUE_INTERRUPT_VECTOR;
+use crate::hpet::TIMER_TICK_PERIOD;
+use crate::mutex::Mutex;
+use crate::result::Error;
 use crate::result::Result;
 use crate::x86::busy_loop_hint;
+use crate::x86::interrupts_enabled;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::BinaryHeap;
 use alloc::collections::VecDeque;
+use core::cmp::max;
+use core::cmp::Reverse;
 use core::fmt::Debug;
+use core::future::poll_fn;
 use core::future::Future;
 use core::panic::Location;
+use core::pin::pin;
 use core::pin::Pin;
 use core::ptr::null;
 use core::task::Context;
@@ -20,9 +32,266 @@ use core::task::Waker;
 use core::time::Duration;
 
 use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 
+// チェック方式のプリエンプション
+// タイマー割り込みはタイムスライスを使い切ったタスクに対してフラグを立てるだけで、
+// タスクはyield_if_preempted()などの安全な地点で自発的に実行を譲る
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+static SLICE_STARTED_AT: AtomicU64 = AtomicU64::new(0);
+// 0の場合はプリエンプションしない
+static QUANTUM_TICKS: AtomicU64 = AtomicU64::new(0);
+static PREEMPT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// タイマー割り込みから呼ばれるので、アトミック変数の操作以外はしてはいけない
+pub fn on_timer_interrupt() {
+    let now = TIMER_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    let quantum = QUANTUM_TICKS.load(Ordering::Relaxed);
+    if quantum != 0 && now - SLICE_STARTED_AT.load(Ordering::Relaxed) >= quantum {
+        PREEMPT_REQUESTED.store(true, Ordering::Release);
+    }
+}
+
+pub fn preempt_requested() -> bool {
+    PREEMPT_REQUESTED.load(Ordering::Acquire)
+}
+
+fn start_time_slice() {
+    SLICE_STARTED_AT.store(TIMER_TICKS.load(Ordering::Relaxed), Ordering::Relaxed);
+    PREEMPT_REQUESTED.store(false, Ordering::Release);
+}
+
+// 割り込みハンドラからタスクを起こすためのトークン
+// 割り込みハンドラはwake_from_interrupt()でトークンをリングに積むだけで、
+// Executorがリングを読み出して、トークンを待っているタスクのWakerを呼ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakerToken(usize);
+
+const NUM_WAKER_TOKENS: usize = 32;
+const WAKER_RING_SIZE: usize = 64;
+
+// 割り当て済みのWakerTokenのビットマップ
+static ALLOCATED_WAKER_TOKENS: AtomicU32 = AtomicU32::new(0);
+const _: () = assert!(NUM_WAKER_TOKENS <= u32::BITS as usize);
+
+impl WakerToken {
+    // 返されたOwnedWakerTokenをDropすると、トークンは解放されて再び割り当てられる
+    pub fn allocate() -> Result<OwnedWakerToken> {
+        let mut allocated = ALLOCATED_WAKER_TOKENS.load(Ordering::Relaxed);
+        loop {
+            let index = (!allocated).trailing_zeros() as usize;
+            if index >= NUM_WAKER_TOKENS {
+                return Err(Error::Failed("No more WakerToken is available"));
+            }
+            match ALLOCATED_WAKER_TOKENS.compare_exchange_weak(
+                allocated,
+                allocated | (1 << index),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(OwnedWakerToken(Self(index))),
+                Err(current) => allocated = current,
+            }
+        }
+    }
+}
+
+// Dropすると解放されるWakerToken
+// 割り込みハンドラにはtoken()で得たWakerTokenを渡す
+// 解放した後に積まれたトークンは、次にそのトークンを割り当てられたタスクを余分に起こすだけ
+#[derive(Debug)]
+pub struct OwnedWakerToken(WakerToken);
+impl OwnedWakerToken {
+    pub fn token(&self) -> WakerToken {
+        self.0
+    }
+}
+impl Drop for OwnedWakerToken {
+    fn drop(&mut self) {
+        let slot = &WAKER_TOKEN_SLOTS[self.0 .0];
+        slot.signaled.store(false, Ordering::Release);
+        *slot.waker.lock() = None;
+        ALLOCATED_WAKER_TOKENS.fetch_and(!(1 << self.0 .0), Ordering::AcqRel);
+    }
+}
+
+// 最初に使われるときにWakerTokenを割り当てる、staticに置くためのトークン
+// 割り込みハンドラからはget()で読むだけなので、ロックしない
+pub struct LazyWakerToken {
+    index: AtomicUsize,
+}
+impl LazyWakerToken {
+    const UNALLOCATED: usize = usize::MAX;
+    pub const fn new() -> Self {
+        Self {
+            index: AtomicUsize::new(Self::UNALLOCATED),
+        }
+    }
+    // 割り込みハンドラから呼んでもよい
+    pub fn get(&self) -> Option<WakerToken> {
+        match self.index.load(Ordering::Acquire) {
+            Self::UNALLOCATED => None,
+            index => Some(WakerToken(index)),
+        }
+    }
+    pub fn get_or_allocate(&self) -> Result<WakerToken> {
+        if let Some(token) = self.get() {
+            return Ok(token);
+        }
+        let owned = WakerToken::allocate()?;
+        let token = owned.token();
+        match self.index.compare_exchange(
+            Self::UNALLOCATED,
+            token.0,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            // staticに置かれて解放されることはないので、Dropさせない
+            Ok(_) => {
+                core::mem::forget(owned);
+                Ok(token)
+            }
+            // 他で先に割り当てられた場合はそちらを使う（ownedはここで解放される）
+            Err(index) => Ok(WakerToken(index)),
+        }
+    }
+}
+impl Default for LazyWakerToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct WakerTokenSlot {
+    signaled: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_WAKER_TOKEN_SLOT: WakerTokenSlot = WakerTokenSlot {
+    signaled: AtomicBool::new(false),
+    waker: Mutex::new(None),
+};
+static WAKER_TOKEN_SLOTS: [WakerTokenSlot; NUM_WAKER_TOKENS] =
+    [EMPTY_WAKER_TOKEN_SLOT; NUM_WAKER_TOKENS];
+
+// 固定長のロックフリーなMPSCリングバッファ
+// 書き込み側（割り込みハンドラ）はtailをCASで進めて確保した場所に書き込む
+// 読み出し側（Executor）は1つだけなので、headは読み出し側だけが進める
+pub struct WakerTokenRing<const N: usize> {
+    entries: [AtomicUsize; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+impl<const N: usize> WakerTokenRing<N> {
+    const EMPTY: usize = usize::MAX;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_ENTRY: AtomicUsize = AtomicUsize::new(Self::EMPTY);
+    pub const fn new() -> Self {
+        Self {
+            entries: [Self::EMPTY_ENTRY; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+    // 割り込みハンドラから呼んでもよい（アロケーションもロックもしない）
+    pub fn push(&self, token: WakerToken) -> Result<()> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= N {
+                return Err(Error::Failed("WakerTokenRing is full"));
+            }
+            match self.tail.compare_exchange_weak(
+                tail,
+                tail.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => tail = current,
+            }
+        }
+        self.entries[tail % N].store(token.0, Ordering::Release);
+        Ok(())
+    }
+    // 確保されたがまだ書き込まれていない場所に当たった場合もNoneを返す（次回読み出す）
+    pub fn pop(&self) -> Option<WakerToken> {
+        let head = self.head.load(Ordering::Relaxed);
+        let value = self.entries[head % N].swap(Self::EMPTY, Ordering::Acquire);
+        if value == Self::EMPTY {
+            return None;
+        }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(WakerToken(value))
+    }
+}
+impl<const N: usize> Default for WakerTokenRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static WAKER_RING: WakerTokenRing<WAKER_RING_SIZE> = WakerTokenRing::new();
+
+// 割り込みハンドラから呼ぶ。リングが一杯の場合はトークンを捨てる
+pub fn wake_from_interrupt(token: WakerToken) {
+    let _ = WAKER_RING.push(token);
+}
+
+// リングに積まれたトークンを読み出し、待っているタスクのWakerを呼ぶ
+fn process_waker_tokens() {
+    while let Some(token) = WAKER_RING.pop() {
+        let slot = &WAKER_TOKEN_SLOTS[token.0];
+        slot.signaled.store(true, Ordering::Release);
+        if let Some(waker) = slot.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+// wake_from_interrupt(token)が呼ばれるまで待つFuture
+pub struct WakerTokenFuture {
+    token: WakerToken,
+}
+impl Future for WakerTokenFuture {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let slot = &WAKER_TOKEN_SLOTS[self.token.0];
+        if slot.signaled.swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+        *slot.waker.lock() = Some(context.waker().clone());
+        Poll::Pending
+    }
+}
+pub fn wait_for_token(token: WakerToken) -> WakerTokenFuture {
+    WakerTokenFuture { token }
+}
+
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+// Wakerに起こされたタスクのID
+static WOKEN_TASKS: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+
+fn task_raw_waker(id: usize) -> RawWaker {
+    fn clone(data: *const ()) -> RawWaker {
+        task_raw_waker(data as usize)
+    }
+    fn wake(data: *const ()) {
+        WOKEN_TASKS.lock().push_back(data as usize);
+    }
+    fn drop(_: *const ()) {}
+    let vtable = &RawWakerVTable::new(clone, wake, wake, drop);
+    RawWaker::new(id as *const (), vtable)
+}
+
+fn task_waker(id: usize) -> Waker {
+    unsafe { Waker::from_raw(task_raw_waker(id)) }
+}
+
 pub struct Task<T> {
+    id: usize,
     future: Pin<Box<dyn Future<Output = Result<T>>>>,
     created_at_file: &'static str,
     created_at_line: u32,
@@ -31,6 +300,7 @@ impl<T> Task<T> {
     #[track_caller]
     pub fn new(future: impl Future<Output = Result<T>> + 'static) -> Task<T> {
         Task {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
             future: Box::pin(future),
             created_at_file: Location::caller().file(),
             created_at_line: Location::caller().line(),
@@ -64,6 +334,7 @@ pub fn block_on<T>(future: impl Future<Output = Result<T>> + 'static) -> Result<
     loop {
         let waker = no_op_waker();
         let mut context = Context::from_waker(&waker);
+        process_waker_tokens();
         match task.poll(&mut context) {
             Poll::Ready(result) => {
                 break result;
@@ -75,10 +346,15 @@ pub fn block_on<T>(future: impl Future<Output = Result<T>> + 'static) -> Result<
 
 pub struct Executor {
     task_queue: Option<VecDeque<Task<()>>>,
+    // Wakerに起こされるのを待っているタスク
+    parked_tasks: BTreeMap<usize, Task<()>>,
 }
 impl Executor {
     pub const fn new() -> Self {
-        Self { task_queue: None }
+        Self {
+            task_queue: None,
+            parked_tasks: BTreeMap::new(),
+        }
     }
     fn task_queue(&mut self) -> &mut VecDeque<Task<()>> {
         if self.task_queue.is_none() {
@@ -89,22 +365,51 @@ impl Executor {
     pub fn enqueue(&mut self, task: Task<()>) {
         self.task_queue().push_back(task);
     }
+    // タイムスライスの長さを設定する。Duration::ZEROならプリエンプションしない
+    pub fn set_quantum(&mut self, quantum: Duration) {
+        let ticks = if quantum.is_zero() {
+            0
+        } else {
+            max(1, quantum.as_nanos() / TIMER_TICK_PERIOD.as_nanos()) as u64
+        };
+        QUANTUM_TICKS.store(ticks, Ordering::Relaxed);
+    }
+    // 起こされたタスクを待ち状態から実行キューに戻す
+    fn wake_parked_tasks(&mut self) {
+        process_waker_tokens();
+        expire_timers();
+        let woken = core::mem::take(&mut *WOKEN_TASKS.lock());
+        for id in woken {
+            if let Some(task) = self.parked_tasks.remove(&id) {
+                self.task_queue().push_back(task);
+            }
+        }
+    }
+    // キューの先頭のタスクを1回pollする。キューが空ならfalseを返す
+    pub(crate) fn run_once(&mut self) -> bool {
+        self.wake_parked_tasks();
+        let Some(mut task) = self.task_queue().pop_front() else {
+            return false;
+        };
+        let waker = task_waker(task.id);
+        let mut context = Context::from_waker(&waker);
+        start_time_slice();
+        match task.poll(&mut context) {
+            Poll::Ready(result) => {
+                info!("Task completed: {:?}: {:?}", task, result);
+            }
+            // Pendingを返したタスクは、Wakerで起こされるまで待たせる
+            // poll中に起こされていれば、次のwake_parked_tasksで実行キューに戻る
+            Poll::Pending => {
+                self.parked_tasks.insert(task.id, task);
+            }
+        }
+        true
+    }
     pub fn run(mut executor: Self) -> ! {
         info!("Executor starts running...");
         loop {
-            let task = executor.task_queue().pop_front();
-            if let Some(mut task) = task {
-                let waker = no_op_waker();
-                let mut context = Context::from_waker(&waker);
-                match task.poll(&mut context) {
-                    Poll::Ready(result) => {
-                        info!("Task completed: {:?}: {:?}", task, result);
-                    }
-                    Poll::Pending => {
-                        executor.task_queue().push_back(task);
-                    }
-                }
-            }
+            executor.run_once();
         }
     }
 }
@@ -120,10 +425,12 @@ pub struct Yield {
 }
 impl Future for Yield {
     type Output = ();
-    fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
         if self.polled.fetch_or(true, Ordering::SeqCst) {
             Poll::Ready(())
         } else {
+            // 待つものはないので、すぐに実行キューの末尾に戻してもらう
+            context.waker().wake_by_ref();
             Poll::Pending
         }
     }
@@ -131,24 +438,426 @@ impl Future for Yield {
 pub async fn yield_execution() {
     Yield::default().await
 }
+// タイムスライスを使い切っていれば実行を譲る
+pub async fn yield_if_preempted() {
+    if preempt_requested() {
+        yield_execution().await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+// 期限の近い順にタイマーを取り出すキュー
+// 一番近い期限だけをコンパレータに設定し、コンパレータの割り込みが来たら
+// 期限切れのタイマーをまとめて取り出してWakerを呼ぶ
+pub struct TimerQueue {
+    deadlines: BinaryHeap<Reverse<(Duration, TimerId)>>,
+    // 期限切れになっていないタイマーと、そのタイマーを待っているWaker
+    pending: BTreeMap<TimerId, Option<Waker>>,
+    next_id: u64,
+    // 現在コンパレータに設定されている期限
+    comparator: Option<Duration>,
+    comparator_armed: bool,
+    program_comparator: fn(Duration) -> Result<()>,
+}
+impl TimerQueue {
+    pub fn new(program_comparator: fn(Duration) -> Result<()>) -> Self {
+        Self {
+            deadlines: BinaryHeap::new(),
+            pending: BTreeMap::new(),
+            next_id: 0,
+            comparator: None,
+            comparator_armed: false,
+            program_comparator,
+        }
+    }
+    pub fn insert(&mut self, deadline: Duration) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.deadlines.push(Reverse((deadline, id)));
+        self.pending.insert(id, None);
+        self.reprogram();
+        id
+    }
+    // タイマーが期限切れになったときに呼ぶWakerを登録する
+    // 既に期限切れか取り消されている場合はfalseを返す
+    pub fn set_waker(&mut self, id: TimerId, waker: &Waker) -> bool {
+        match self.pending.get_mut(&id) {
+            Some(slot) => {
+                *slot = Some(waker.clone());
+                true
+            }
+            None => false,
+        }
+    }
+    // BinaryHeapからは途中の要素を消せないので、取り出すときに読み飛ばす
+    pub fn cancel(&mut self, id: TimerId) {
+        self.pending.remove(&id);
+    }
+    pub fn next_deadline(&mut self) -> Option<Duration> {
+        while let Some(Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.pending.contains_key(id) {
+                return Some(*deadline);
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+    pub fn comparator(&self) -> Option<Duration> {
+        self.comparator
+    }
+    // コンパレータの割り込みが使えない場合は、Executorが毎回期限を確認する必要がある
+    pub fn is_comparator_armed(&self) -> bool {
+        self.comparator_armed
+    }
+    // nowまでに期限切れになったタイマーを全て取り出してWakerを呼び、取り出した数を返す
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let mut expired = 0;
+        while let Some(Reverse((deadline, id))) = self.deadlines.peek() {
+            if *deadline > now {
+                break;
+            }
+            let id = *id;
+            self.deadlines.pop();
+            if let Some(waker) = self.pending.remove(&id) {
+                expired += 1;
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+        self.reprogram();
+        expired
+    }
+    fn reprogram(&mut self) {
+        let next = self.next_deadline();
+        if next == self.comparator {
+            return;
+        }
+        self.comparator = next;
+        if let Some(deadline) = next {
+            self.comparator_armed = (self.program_comparator)(deadline).is_ok();
+        }
+    }
+}
+
+static TIMER_QUEUE: Mutex<Option<TimerQueue>> = Mutex::new(None);
+static TIMER_QUEUE_FIRED: AtomicBool = AtomicBool::new(false);
+
+// コンパレータの割り込みから呼ばれるので、フラグを立てるだけにする
+pub fn on_timer_queue_interrupt() {
+    TIMER_QUEUE_FIRED.store(true, Ordering::Release);
+}
+
+fn program_timer_comparator(deadline: Duration) -> Result<()> {
+    if !interrupts_enabled() {
+        return Err(Error::Failed("Interrupts are disabled"));
+    }
+    set_oneshot_timer(deadline, TIMER_QUEUE_INTERRUPT_VECTOR)?;
+    // 設定する前に期限が過ぎていた場合、コンパレータは発火しないので自分でフラグを立てる
+    if global_timestamp() >= deadline {
+        TIMER_QUEUE_FIRED.store(true, Ordering::Release);
+    }
+    Ok(())
+}
+
+fn with_timer_queue<R>(f: impl FnOnce(&mut TimerQueue) -> R) -> R {
+    let mut queue = TIMER_QUEUE.lock();
+    f(queue.get_or_insert_with(|| TimerQueue::new(program_timer_comparator)))
+}
+
+// コンパレータの割り込みが来ていれば、期限切れのタイマーのWakerを呼ぶ
+fn expire_timers() {
+    let fired = TIMER_QUEUE_FIRED.swap(false, Ordering::AcqRel);
+    let mut queue = TIMER_QUEUE.lock();
+    let Some(queue) = queue.as_mut() else {
+        return;
+    };
+    if !fired && queue.is_comparator_armed() {
+        return;
+    }
+    if queue
+        .next_deadline()
+        .is_some_and(|deadline| deadline <= global_timestamp())
+    {
+        queue.expire(global_timestamp());
+    }
+}
 
 pub struct TimeoutFuture {
     time_out: Duration,
+    id: TimerId,
 }
 impl TimeoutFuture {
     pub fn new(duration: Duration) -> Self {
+        let time_out = global_timestamp() + duration;
         Self {
-            time_out: global_timestamp() + duration,
+            time_out,
+            id: with_timer_queue(|queue| queue.insert(time_out)),
         }
     }
 }
 impl Future for TimeoutFuture {
     type Output = ();
-    fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
-        if self.time_out < global_timestamp() {
-            Poll::Ready(())
-        } else {
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.time_out <= global_timestamp()
+            || !with_timer_queue(|queue| queue.set_waker(self.id, context.waker()))
+        {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+impl Drop for TimeoutFuture {
+    fn drop(&mut self) {
+        with_timer_queue(|queue| queue.cancel(self.id));
+    }
+}
+
+// futとTimeoutFutureを一緒にpollし、先にfutが完了すればその結果を返す
+// limitまでに完了しなければ、futを捨ててError::Timeoutを返す
+// limitが0の場合も、futを1回だけはpollする
+pub async fn with_timeout<F: Future>(fut: F, limit: Duration) -> Result<F::Output> {
+    let mut fut = pin!(fut);
+    let mut timeout = TimeoutFuture::new(limit);
+    poll_fn(|context| {
+        if let Poll::Ready(output) = fut.as_mut().poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut timeout).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Error::Timeout)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    #[test_case]
+    fn waker_token_ring_is_fifo_and_bounded() {
+        let ring = WakerTokenRing::<4>::new();
+        assert_eq!(ring.pop(), None);
+        for i in 0..4 {
+            assert_eq!(ring.push(WakerToken(i)), Ok(()));
+        }
+        assert!(ring.push(WakerToken(4)).is_err());
+        assert_eq!(ring.pop(), Some(WakerToken(0)));
+        assert_eq!(ring.push(WakerToken(5)), Ok(()));
+        assert_eq!(ring.pop(), Some(WakerToken(1)));
+        assert_eq!(ring.pop(), Some(WakerToken(2)));
+        assert_eq!(ring.pop(), Some(WakerToken(3)));
+        assert_eq!(ring.pop(), Some(WakerToken(5)));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test_case]
+    fn interrupt_token_wakes_parked_task() {
+        let owned = WakerToken::allocate().expect("Failed to allocate WakerToken");
+        let token = owned.token();
+        let mut executor = Executor::new();
+        let done = Rc::new(Cell::new(false));
+        {
+            let done = done.clone();
+            executor.enqueue(Task::new(async move {
+                wait_for_token(token).await;
+                done.set(true);
+                Ok(())
+            }));
+        }
+        // タスクは待ち状態になり、キューは空になる
+        assert!(executor.run_once());
+        assert!(!executor.run_once());
+        assert_eq!(executor.parked_tasks.len(), 1);
+        assert!(!done.get());
+        // 割り込みハンドラからトークンが積まれたことを模擬する
+        wake_from_interrupt(token);
+        assert!(executor.run_once());
+        assert!(done.get());
+        assert!(executor.parked_tasks.is_empty());
+        assert!(!executor.run_once());
+    }
+
+    #[test_case]
+    fn task_woken_during_poll_is_not_parked() {
+        let owned = WakerToken::allocate().expect("Failed to allocate WakerToken");
+        let token = owned.token();
+        let mut executor = Executor::new();
+        let polls = Rc::new(Cell::new(0));
+        {
+            let polls = polls.clone();
+            executor.enqueue(Task::new(async move {
+                let mut wait = wait_for_token(token);
+                poll_fn(|context| {
+                    polls.set(polls.get() + 1);
+                    // トークンを待ちながら、3回目のpollまでは自分で起こす
+                    let result = Pin::new(&mut wait).poll(context);
+                    if polls.get() < 3 {
+                        context.waker().wake_by_ref();
+                    }
+                    result
+                })
+                .await;
+                Ok(())
+            }));
+        }
+        for _ in 0..3 {
+            assert!(executor.run_once());
+        }
+        assert!(!executor.run_once());
+        assert_eq!(polls.get(), 3);
+        assert_eq!(executor.parked_tasks.len(), 1);
+        wake_from_interrupt(token);
+        assert!(executor.run_once());
+        assert_eq!(polls.get(), 4);
+        assert!(executor.parked_tasks.is_empty());
+    }
+
+    #[test_case]
+    fn dropped_waker_tokens_are_reused() {
+        // 解放されなければ、NUM_WAKER_TOKENSを超えたところで割り当てられなくなる
+        for _ in 0..NUM_WAKER_TOKENS * 2 {
+            let owned = WakerToken::allocate().expect("Failed to allocate WakerToken");
+            wake_from_interrupt(owned.token());
+            process_waker_tokens();
+        }
+        let first = WakerToken::allocate().unwrap();
+        let index = first.token();
+        drop(first);
+        let second = WakerToken::allocate().unwrap();
+        assert_eq!(second.token(), index);
+        // 前の持ち主への通知は残らない
+        assert!(!WAKER_TOKEN_SLOTS[index.0].signaled.load(Ordering::Acquire));
+    }
+
+    static LAST_PROGRAMMED: Mutex<Option<Duration>> = Mutex::new(None);
+    fn record_comparator(deadline: Duration) -> Result<()> {
+        *LAST_PROGRAMMED.lock() = Some(deadline);
+        Ok(())
+    }
+
+    #[test_case]
+    fn timer_queue_expires_in_deadline_order() {
+        const N: u64 = 100;
+        *LAST_PROGRAMMED.lock() = None;
+        WOKEN_TASKS.lock().clear();
+        let mut queue = TimerQueue::new(record_comparator);
+        // 37はNと互いに素なので、0..Nの期限がばらばらの順番で一度ずつ登録される
+        for i in 0..N {
+            let ms = i * 37 % N;
+            let id = queue.insert(Duration::from_millis(ms));
+            assert!(queue.set_waker(id, &task_waker(ms as usize)));
+        }
+        assert!(queue.is_comparator_armed());
+        for ms in 0..N {
+            let deadline = Duration::from_millis(ms);
+            assert_eq!(queue.comparator(), Some(deadline));
+            assert_eq!(*LAST_PROGRAMMED.lock(), Some(deadline));
+            // 期限の直前では何も取り出されない
+            if let Some(before) = deadline.checked_sub(Duration::from_nanos(1)) {
+                assert_eq!(queue.expire(before), 0);
+            }
+            assert_eq!(queue.expire(deadline), 1);
+            let woken = core::mem::take(&mut *WOKEN_TASKS.lock());
+            assert_eq!(woken, [ms as usize]);
+        }
+        assert_eq!(queue.comparator(), None);
+        assert_eq!(queue.next_deadline(), None);
+    }
+
+    #[test_case]
+    fn timer_queue_skips_cancelled_timers() {
+        let mut queue = TimerQueue::new(record_comparator);
+        let first = queue.insert(Duration::from_millis(1));
+        queue.insert(Duration::from_millis(2));
+        queue.cancel(first);
+        assert!(!queue.set_waker(first, &no_op_waker()));
+        assert_eq!(queue.next_deadline(), Some(Duration::from_millis(2)));
+        assert_eq!(queue.expire(Duration::from_millis(1)), 0);
+        assert_eq!(queue.comparator(), Some(Duration::from_millis(2)));
+        assert_eq!(queue.expire(Duration::from_millis(5)), 1);
+    }
+
+    // pollされた回数を数える、完了しないFuture
+    struct NeverReady {
+        polls: Rc<Cell<usize>>,
+    }
+    impl Future for NeverReady {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            self.polls.set(self.polls.get() + 1);
             Poll::Pending
         }
     }
+
+    #[test_case]
+    fn with_timeout_gives_up_on_pending_future() {
+        let polls = Rc::new(Cell::new(0));
+        let never = NeverReady {
+            polls: polls.clone(),
+        };
+        assert_eq!(
+            block_on(with_timeout(never, Duration::from_millis(10))),
+            Err(Error::Timeout)
+        );
+        assert!(polls.get() >= 1);
+        // 最初のpollで完了すれば、期限に関係なくその結果を返す
+        assert_eq!(
+            block_on(with_timeout(core::future::ready(42), Duration::ZERO)),
+            Ok(42)
+        );
+        let polls = Rc::new(Cell::new(0));
+        let never = NeverReady {
+            polls: polls.clone(),
+        };
+        assert_eq!(
+            block_on(with_timeout(never, Duration::ZERO)),
+            Err(Error::Timeout)
+        );
+        assert_eq!(polls.get(), 1);
+    }
+
+    #[test_case]
+    fn preemption_rotates_spinning_task() {
+        const SPINS: usize = 30;
+        let mut executor = Executor::new();
+        executor.set_quantum(TIMER_TICK_PERIOD * 3);
+        let spins = Rc::new(Cell::new(0));
+        let others = Rc::new(Cell::new(0));
+        {
+            let spins = spins.clone();
+            executor.enqueue(Task::new(async move {
+                while spins.get() < SPINS {
+                    spins.set(spins.get() + 1);
+                    // テストではタイマー割り込みが来ないので、ここで割り込みを模擬する
+                    on_timer_interrupt();
+                    yield_if_preempted().await;
+                }
+                Ok(())
+            }));
+        }
+        {
+            let spins = spins.clone();
+            let others = others.clone();
+            executor.enqueue(Task::new(async move {
+                loop {
+                    others.set(others.get() + 1);
+                    if spins.get() >= SPINS {
+                        break Ok(());
+                    }
+                    yield_execution().await
+                }
+            }));
+        }
+        while executor.run_once() {}
+        executor.set_quantum(Duration::ZERO);
+        assert_eq!(spins.get(), SPINS);
+        // 3 tickごとに切り替わるので、もう一方のタスクもタイムスライスごとに進んでいる
+        assert!(others.get() >= SPINS / 3 - 1);
+    }
 }
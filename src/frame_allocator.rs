@@ -0,0 +1,165 @@
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::PAGE_SIZE;
+use core::ops::Range;
+use core::ptr::write_bytes;
+
+// ページテーブル用に確保しておくフレームの数 (32MiB)
+pub const FRAME_POOL_PAGES: usize = 8192;
+
+// ページテーブル用の4KiBの物理フレームを管理するアロケータ
+// ヒープとは別の領域からフレームを切り出すので、ページテーブルとヒープのオブジェクトが混ざらない
+// 解放されたフレームは、先頭8バイトに次の空きフレームのアドレスを書いてリストにつなぐ
+pub struct FrameAllocator {
+    range: Range<usize>,
+    // まだ一度も割り当てていないフレームの先頭
+    next_unused: usize,
+    free_list: Option<usize>,
+    allocated_frames: usize,
+}
+impl FrameAllocator {
+    pub const fn new() -> Self {
+        Self {
+            range: 0..0,
+            next_unused: 0,
+            free_list: None,
+            allocated_frames: 0,
+        }
+    }
+    // rangeの物理フレームを管理対象にする。rangeはアイデンティティマップされている必要がある
+    pub fn init(&mut self, range: Range<usize>) -> Result<()> {
+        // 0番地は空きリストの終端として使うので、管理対象にできない
+        if range.start == 0
+            || range.start % PAGE_SIZE != 0
+            || range.end % PAGE_SIZE != 0
+            || range.is_empty()
+        {
+            return Err(Error::InvalidArgument);
+        }
+        self.next_unused = range.start;
+        self.range = range;
+        self.free_list = None;
+        self.allocated_frames = 0;
+        Ok(())
+    }
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated_frames
+    }
+    // まだ割り当てられるフレームの数
+    pub fn free_frames(&self) -> usize {
+        self.range.len() / PAGE_SIZE - self.allocated_frames
+    }
+    // 0で埋めた4KiBのフレームを割り当てる
+    pub fn alloc_frame(&mut self) -> Result<usize> {
+        let frame = if let Some(frame) = self.free_list {
+            self.free_list = match unsafe { *(frame as *const usize) } {
+                0 => None,
+                next => Some(next),
+            };
+            frame
+        } else if self.next_unused < self.range.end {
+            let frame = self.next_unused;
+            self.next_unused += PAGE_SIZE;
+            frame
+        } else {
+            return Err(Error::OutOfMemory);
+        };
+        unsafe { write_bytes(frame as *mut u8, 0, PAGE_SIZE) };
+        self.allocated_frames += 1;
+        Ok(frame)
+    }
+    pub fn free_frame(&mut self, addr: usize) -> Result<()> {
+        if addr % PAGE_SIZE != 0 || !(self.range.start..self.next_unused).contains(&addr) {
+            return Err(Error::InvalidArgument);
+        }
+        unsafe { *(addr as *mut usize) = self.free_list.unwrap_or(0) };
+        self.free_list = Some(addr);
+        self.allocated_frames -= 1;
+        Ok(())
+    }
+}
+impl Default for FrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
+
+pub fn alloc_frame() -> Result<usize> {
+    FRAME_ALLOCATOR.lock().alloc_frame()
+}
+
+pub fn free_frame(addr: usize) -> Result<()> {
+    FRAME_ALLOCATOR.lock().free_frame(addr)
+}
+
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames()
+}
+
+// 一番大きな空き領域の末尾からpagesページを切り出す
+pub fn find_frame_pool(memory_map: &MemoryMapHolder, pages: usize) -> Option<Range<usize>> {
+    let e = memory_map
+        .iter()
+        .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+        .max_by_key(|e| e.number_of_pages())?;
+    if (e.number_of_pages() as usize) < pages {
+        return None;
+    }
+    let end = e.physical_start() as usize + e.number_of_pages() as usize * PAGE_SIZE;
+    Some(end - pages * PAGE_SIZE..end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use crate::allocator::ALLOCATOR;
+    use alloc::vec::Vec;
+    use core::alloc::GlobalAlloc;
+    use core::alloc::Layout;
+
+    #[test_case]
+    fn alloc_frame_returns_zeroed_distinct_frames() {
+        const PAGES: usize = 4;
+        let layout = Layout::from_size_align(PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let region = unsafe { ALLOCATOR.alloc(layout) } as usize;
+        assert_ne!(region, 0);
+        let mut frames = FrameAllocator::new();
+        frames
+            .init(region..region + PAGES * PAGE_SIZE)
+            .expect("init failed");
+        let allocated: Vec<usize> = (0..PAGES)
+            .map(|_| frames.alloc_frame().expect("alloc_frame failed"))
+            .collect();
+        for (i, frame) in allocated.iter().enumerate() {
+            assert_eq!(frame % PAGE_SIZE, 0);
+            assert!(frames.range().contains(frame));
+            assert!(!allocated[..i].contains(frame));
+            let bytes = unsafe { core::slice::from_raw_parts(*frame as *const u8, PAGE_SIZE) };
+            assert!(bytes.iter().all(|b| *b == 0));
+        }
+        assert_eq!(frames.allocated_frames(), PAGES);
+        assert_eq!(frames.alloc_frame(), Err(Error::OutOfMemory));
+
+        // 解放したフレームは、汚れていても0で埋め直して再利用される
+        let reused = allocated[2];
+        unsafe { write_bytes(reused as *mut u8, 0xcc, PAGE_SIZE) };
+        frames.free_frame(reused).expect("free_frame failed");
+        assert_eq!(frames.allocated_frames(), PAGES - 1);
+        assert_eq!(frames.alloc_frame(), Ok(reused));
+        let bytes = unsafe { core::slice::from_raw_parts(reused as *const u8, PAGE_SIZE) };
+        assert!(bytes.iter().all(|b| *b == 0));
+        assert!(frames.free_frame(reused + 8).is_err());
+        assert!(frames.free_frame(region + PAGES * PAGE_SIZE).is_err());
+
+        unsafe { ALLOCATOR.dealloc(region as *mut u8, layout) };
+    }
+}
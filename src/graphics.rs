@@ -1,5 +1,64 @@
+extern crate alloc;
+
 use crate::result::Result;
-use core::{cmp::min, fmt};
+use core::{
+    cmp::{max, min},
+    fmt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsError {
+    OutOfBounds,
+    UnsupportedPixelFormat,
+}
+
+// フレームバッファの1ピクセル(32bit)中の色の並び
+// 描画関数は色を0x00RRGGBBで受け取り、encode()でフレームバッファの並びに変換する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelFormat {
+    // メモリ上でR, G, B, 予約の順に並ぶ
+    Rgb,
+    // メモリ上でB, G, R, 予約の順に並ぶ（0x00RRGGBBそのまま）
+    #[default]
+    Bgr,
+    // 各色のビット位置がマスクで指定される
+    Bitmask {
+        red: u32,
+        green: u32,
+        blue: u32,
+    },
+}
+impl PixelFormat {
+    pub fn encode(self, color: u32) -> u32 {
+        let r = (color >> 16) & 0xff;
+        let g = (color >> 8) & 0xff;
+        let b = color & 0xff;
+        match self {
+            PixelFormat::Rgb => (b << 16) | (g << 8) | r,
+            PixelFormat::Bgr => color & 0xffffff,
+            PixelFormat::Bitmask { red, green, blue } => {
+                place_in_mask(r, red) | place_in_mask(g, green) | place_in_mask(b, blue)
+            }
+        }
+    }
+}
+
+// 8bitの色の値を、maskのビット幅に合わせて伸縮してmaskの位置に置く
+// 8bitより広い場合は、下位のビットを値の上位ビットの繰り返しで埋めて0xffが最大値になるようにする
+fn place_in_mask(value: u32, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let bits = mask.count_ones();
+    let mut repeated = 0u64;
+    let mut filled = 0;
+    while filled < bits {
+        repeated = (repeated << 8) | value as u64;
+        filled += 8;
+    }
+    let value = (repeated >> (filled - bits)) as u32;
+    (value << mask.trailing_zeros()) & mask
+}
 
 pub trait Bitmap {
     fn bytes_per_pixel(&self) -> i64;
@@ -7,6 +66,9 @@ pub trait Bitmap {
     fn width(&self) -> i64;
     fn height(&self) -> i64;
     fn buf_mut(&mut self) -> *mut u8;
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Bgr
+    }
 
     /// # Safety
     unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
@@ -32,12 +94,65 @@ pub trait Bitmap {
     }
 }
 
+// 親のBitmapの一部の矩形を、(0, 0)を左上とする別のBitmapとして扱う
+// ピクセルはコピーせず、座標をずらして親のバッファに直接描く
+pub struct SubBitmap<'a, T: Bitmap> {
+    parent: &'a mut T,
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+}
+impl<'a, T: Bitmap> SubBitmap<'a, T> {
+    // 親からはみ出す部分は切り詰める。左上が親の外にある場合はエラー
+    pub fn new(parent: &'a mut T, x: i64, y: i64, width: i64, height: i64) -> Result<Self> {
+        if !parent.is_in_x_range(x) || !parent.is_in_y_range(y) || width < 0 || height < 0 {
+            return Err(GraphicsError::OutOfBounds.into());
+        }
+        let width = min(width, min(parent.width(), parent.pixels_per_line()) - x);
+        let height = min(height, parent.height() - y);
+        Ok(Self {
+            parent,
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+    pub fn origin(&self) -> (i64, i64) {
+        (self.x, self.y)
+    }
+}
+impl<'a, T: Bitmap> Bitmap for SubBitmap<'a, T> {
+    fn bytes_per_pixel(&self) -> i64 {
+        self.parent.bytes_per_pixel()
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.parent.pixels_per_line()
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    // 左上のピクセルのアドレス。行の間隔は親と同じ
+    fn buf_mut(&mut self) -> *mut u8 {
+        unsafe { self.parent.unchecked_pixel_at_mut(self.x, self.y) as *mut u8 }
+    }
+    fn pixel_format(&self) -> PixelFormat {
+        self.parent.pixel_format()
+    }
+}
+
 unsafe fn unchecked_draw_point<T: Bitmap>(buf: &mut T, color: u32, x: i64, y: i64) {
+    let color = buf.pixel_format().encode(color);
     *buf.unchecked_pixel_at_mut(x, y) = color;
 }
 
 fn draw_point<T: Bitmap>(buf: &mut T, color: u32, x: i64, y: i64) -> Result<()> {
-    *(buf.pixel_at_mut(x, y).ok_or("Out of Range")?) = color;
+    let color = buf.pixel_format().encode(color);
+    *(buf.pixel_at_mut(x, y).ok_or(GraphicsError::OutOfBounds)?) = color;
     Ok(())
 }
 
@@ -54,7 +169,7 @@ pub fn fill_rect<T: Bitmap>(
         || !buf.is_in_x_range(px + w - 1)
         || !buf.is_in_y_range(py + h - 1)
     {
-        return Err("Out of Range");
+        return Err(GraphicsError::OutOfBounds.into());
     }
 
     for y in py..py + h {
@@ -80,13 +195,20 @@ fn calc_slope_point(da: i64, db: i64, ia: i64) -> Option<i64> {
     }
 }
 
-fn draw_line<T: Bitmap>(buf: &mut T, color: u32, x0: i64, y0: i64, x1: i64, y1: i64) -> Result<()> {
+pub fn draw_line<T: Bitmap>(
+    buf: &mut T,
+    color: u32,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+) -> Result<()> {
     if !buf.is_in_x_range(x0)
         || !buf.is_in_y_range(y0)
         || !buf.is_in_x_range(x1)
         || !buf.is_in_y_range(y1)
     {
-        return Err("Out of Range");
+        return Err(GraphicsError::OutOfBounds.into());
     }
 
     let dx = (x1 - x0).abs();
@@ -107,6 +229,9 @@ fn draw_line<T: Bitmap>(buf: &mut T, color: u32, x0: i64, y0: i64, x1: i64, y1:
     Ok(())
 }
 
+const FONT_WIDTH: i64 = 8;
+const FONT_HEIGHT: i64 = 16;
+
 fn lookup_font(c: char) -> Option<[[char; 8]; 16]> {
     const FONT_SOURCE: &str = include_str!("./font.txt");
     static mut FONT_CACHE: Option<[[[char; 8]; 16]; 256]> = None;
@@ -159,50 +284,115 @@ fn lookup_font(c: char) -> Option<[[char; 8]; 16]> {
     }
 }
 
-pub fn draw_font_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, c: char) {
+// はみ出したピクセルは描画しない。グリフ全体が画面外の場合はOutOfBounds
+pub fn draw_font_fg<T: Bitmap>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: u32,
+    c: char,
+) -> core::result::Result<(), GraphicsError> {
+    let is_visible = (x..x + FONT_WIDTH).any(|x| buf.is_in_x_range(x))
+        && (y..y + FONT_HEIGHT).any(|y| buf.is_in_y_range(y));
+    if !is_visible {
+        return Err(GraphicsError::OutOfBounds);
+    }
+    let color = buf.pixel_format().encode(color);
     if let Some(font) = lookup_font(c) {
         for (dy, row) in font.iter().enumerate() {
             for (dx, pixel) in row.iter().enumerate() {
-                let color = match pixel {
-                    '*' => color,
-                    _ => continue,
-                };
-                let _ = draw_point(buf, color, x + dx as i64, y + dy as i64);
+                if *pixel != '*' {
+                    continue;
+                }
+                if let Some(p) = buf.pixel_at_mut(x + dx as i64, y + dy as i64) {
+                    *p = color;
+                }
             }
         }
     }
+    Ok(())
 }
 
 fn draw_str_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, s: &str) {
     for (i, c) in s.chars().enumerate() {
-        draw_font_fg(buf, x + i as i64 * 8, y, color, c)
+        if draw_font_fg(buf, x + i as i64 * FONT_WIDTH, y, color, c).is_err() {
+            break;
+        }
+    }
+}
+
+const TEST_PATTERN_SIZE: i64 = 128;
+const TEST_PATTERN_BAR_HEIGHT: i64 = 64;
+const TEST_PATTERN_COLORS: [u32; 4] = [0x000000, 0xff0000, 0x00ff00, 0x0000ff];
+
+// テストパターンの(x, y)の色。左上を原点とした129x256の領域で、それ以外はNone
+// 幅128の4色の帯(右半分は反転色)の上に、(0, 0)と(128, 128)を角とする正方形の辺と対角線を白で描く
+pub fn test_pattern_pixel(x: i64, y: i64) -> Option<u32> {
+    let w = TEST_PATTERN_SIZE;
+    let h = TEST_PATTERN_BAR_HEIGHT;
+    let num_bars = TEST_PATTERN_COLORS.len() as i64;
+    if !(0..=w).contains(&x) || !(0..h * num_bars).contains(&y) {
+        return None;
+    }
+    let on_square = y <= w && (x == 0 || x == w || y == 0 || y == w || x == y || x + y == w);
+    if on_square {
+        return Some(0xffffff);
     }
+    // 帯はx == wの列にはない
+    if x == w {
+        return None;
+    }
+    let c = TEST_PATTERN_COLORS[(y / h) as usize];
+    Some(if x < h { c } else { !c & 0xffffff })
 }
 
+// 画面の右上にテストパターンを描く。はみ出した部分は描かない
 pub fn draw_test_pattern<T: Bitmap>(buf: &mut T) {
-    let w = 128;
-    let left = buf.width() - w - 1;
-    let colors = [0x000000, 0xff0000, 0x00ff00, 0x0000ff];
-    let h = 64;
-    for (i, c) in colors.iter().enumerate() {
-        let y = i as i64 * h;
-        fill_rect(buf, *c, left, y, h, h).expect("fill_rect failed");
-        fill_rect(buf, !*c, left + h, y, h, h).expect("fill_rect failed");
+    let left = max(0, buf.width() - TEST_PATTERN_SIZE - 1);
+    let bottom = TEST_PATTERN_BAR_HEIGHT * TEST_PATTERN_COLORS.len() as i64;
+    for y in 0..bottom {
+        for x in 0..=TEST_PATTERN_SIZE {
+            if let Some(c) = test_pattern_pixel(x, y) {
+                let _ = draw_point(buf, c, left + x, y);
+            }
+        }
     }
-    let points = [(0, 0), (0, w), (w, 0), (w, w)];
-    for (x0, y0) in points.iter() {
-        for (x1, y1) in points.iter() {
-            let _ = draw_line(buf, 0xffffff, left + *x0, *y0, left + *x1, *y1);
+    draw_str_fg(buf, left, bottom, 0x00ff00, "0123456789");
+    draw_str_fg(buf, left, bottom + FONT_HEIGHT, 0x00ff00, "ABCDEF");
+}
+
+// 4隅の色[左上, 右上, 左下, 右下]の間を双線形補間したグラデーションで全体を塗る
+pub fn draw_gradient<T: Bitmap>(buf: &mut T, corner_colors: [u32; 4]) {
+    let w = min(buf.width(), buf.pixels_per_line());
+    let h = buf.height();
+    // 端から端までの距離。1ピクセルしかない場合は0除算を避ける
+    let dx = max(1, w - 1);
+    let dy = max(1, h - 1);
+    let channel = |c: u32, shift: u32| ((c >> shift) & 0xff) as i64;
+    for y in 0..h {
+        for x in 0..w {
+            let mut color = 0;
+            for shift in [16, 8, 0] {
+                let [tl, tr, bl, br] = corner_colors.map(|c| channel(c, shift));
+                let v =
+                    tl * (dx - x) * (dy - y) + tr * x * (dy - y) + bl * (dx - x) * y + br * x * y;
+                // 四捨五入する
+                let v = (v + dx * dy / 2) / (dx * dy);
+                color |= (v as u32) << shift;
+            }
+            // (x, y)は範囲内なので失敗しない
+            let _ = draw_point(buf, color, x, y);
         }
     }
-    draw_str_fg(buf, left, h * colors.len() as i64, 0x00ff00, "0123456789");
-    draw_str_fg(buf, left, h * colors.len() as i64 + 16, 0x00ff00, "ABCDEF");
 }
 
 pub struct BitmapTextWriter<T> {
     buf: T,
     cursor_x: i64,
     cursor_y: i64,
+    fg: u32,
+    // Noneの場合は文字の背景を塗らずに、前景だけを重ねて描く
+    bg: Option<u32>,
 }
 impl<T: Bitmap> BitmapTextWriter<T> {
     pub fn new(buf: T) -> Self {
@@ -210,20 +400,398 @@ impl<T: Bitmap> BitmapTextWriter<T> {
             buf,
             cursor_x: 0,
             cursor_y: 0,
+            fg: 0xffffff,
+            bg: None,
+        }
+    }
+    pub fn cursor(&self) -> (i64, i64) {
+        (self.cursor_x, self.cursor_y)
+    }
+    pub fn fg(&self) -> u32 {
+        self.fg
+    }
+    pub fn set_fg(&mut self, color: u32) {
+        self.fg = color;
+    }
+    pub fn bg(&self) -> Option<u32> {
+        self.bg
+    }
+    pub fn set_bg(&mut self, color: Option<u32>) {
+        self.bg = color;
+    }
+    // 消去やスクロールで空いた部分を塗る色
+    fn clear_color(&self) -> u32 {
+        self.bg.unwrap_or(0x000000)
+    }
+    fn width(&self) -> i64 {
+        min(self.buf.width(), self.buf.pixels_per_line())
+    }
+    // 画面の下端を超える場合は1行分スクロールする
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        self.cursor_y += FONT_HEIGHT;
+        if self.cursor_y + FONT_HEIGHT > self.buf.height() {
+            self.scroll_up();
+            self.cursor_y -= FONT_HEIGHT;
+        }
+    }
+    fn scroll_up(&mut self) {
+        let w = self.width();
+        let h = self.buf.height();
+        if h <= FONT_HEIGHT {
+            return;
+        }
+        for y in 0..h - FONT_HEIGHT {
+            unsafe {
+                let src = self.buf.unchecked_pixel_at_mut(0, y + FONT_HEIGHT);
+                let dst = self.buf.unchecked_pixel_at_mut(0, y);
+                core::ptr::copy(src, dst, w as usize);
+            }
         }
+        let color = self.clear_color();
+        let _ = fill_rect(&mut self.buf, color, 0, h - FONT_HEIGHT, w, FONT_HEIGHT);
     }
 }
 impl<T: Bitmap> fmt::Write for BitmapTextWriter<T> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
-            if c == '\n' {
-                self.cursor_y += 16;
-                self.cursor_x = 0;
-                continue;
+            match c {
+                '\n' => {
+                    self.new_line();
+                    continue;
+                }
+                '\r' => {
+                    self.cursor_x = 0;
+                    continue;
+                }
+                '\x08' => {
+                    // 1文字戻って、そのセルを背景色で消す
+                    if self.cursor_x >= FONT_WIDTH {
+                        self.cursor_x -= FONT_WIDTH;
+                        let color = self.clear_color();
+                        let _ = fill_rect(
+                            &mut self.buf,
+                            color,
+                            self.cursor_x,
+                            self.cursor_y,
+                            FONT_WIDTH,
+                            FONT_HEIGHT,
+                        );
+                    }
+                    continue;
+                }
+                '\t' => {
+                    // 次の8文字境界まで進める
+                    let tab_width = FONT_WIDTH * 8;
+                    self.cursor_x = (self.cursor_x / tab_width + 1) * tab_width;
+                    if self.cursor_x >= self.width() {
+                        self.new_line();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if self.cursor_x + FONT_WIDTH > self.width() {
+                self.new_line();
+            }
+            if let Some(bg) = self.bg {
+                let _ = fill_rect(
+                    &mut self.buf,
+                    bg,
+                    self.cursor_x,
+                    self.cursor_y,
+                    FONT_WIDTH,
+                    FONT_HEIGHT,
+                );
             }
-            draw_font_fg(&mut self.buf, self.cursor_x, self.cursor_y, 0xffffff, c);
-            self.cursor_x += 8;
+            let _ = draw_font_fg(&mut self.buf, self.cursor_x, self.cursor_y, self.fg, c);
+            self.cursor_x += FONT_WIDTH;
         }
         Ok(())
     }
 }
+
+// テスト用のメモリ上のBitmap
+#[cfg(test)]
+pub struct MockBitmap {
+    width: i64,
+    height: i64,
+    buf: alloc::vec::Vec<u32>,
+    format: PixelFormat,
+}
+#[cfg(test)]
+impl MockBitmap {
+    pub fn new(width: i64, height: i64) -> Self {
+        Self::with_format(width, height, PixelFormat::Bgr)
+    }
+    pub fn with_format(width: i64, height: i64, format: PixelFormat) -> Self {
+        Self {
+            width,
+            height,
+            buf: alloc::vec![0; (width * height) as usize],
+            format,
+        }
+    }
+    // フレームバッファのメモリ上の並びでピクセルのバイト列を返す
+    pub fn pixel_bytes(&self, x: i64, y: i64) -> [u8; 4] {
+        self.pixel(x, y).to_le_bytes()
+    }
+    pub fn pixel(&self, x: i64, y: i64) -> u32 {
+        self.buf[(y * self.width + x) as usize]
+    }
+    // 指定した矩形内で0でないピクセルの数
+    pub fn count_non_zero(&self, px: i64, py: i64, w: i64, h: i64) -> usize {
+        (py..py + h)
+            .flat_map(|y| (px..px + w).map(move |x| (x, y)))
+            .filter(|(x, y)| self.pixel(*x, *y) != 0)
+            .count()
+    }
+}
+#[cfg(test)]
+impl Bitmap for MockBitmap {
+    fn bytes_per_pixel(&self) -> i64 {
+        4
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.width
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr() as *mut u8
+    }
+    fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn fill_rect_respects_pixel_format() {
+        let mut bgr = MockBitmap::with_format(2, 2, PixelFormat::Bgr);
+        fill_rect(&mut bgr, 0xff0000, 0, 0, 1, 1).unwrap();
+        assert_eq!(bgr.pixel_bytes(0, 0), [0x00, 0x00, 0xff, 0x00]);
+        let mut rgb = MockBitmap::with_format(2, 2, PixelFormat::Rgb);
+        fill_rect(&mut rgb, 0xff0000, 0, 0, 1, 1).unwrap();
+        assert_eq!(rgb.pixel_bytes(0, 0), [0xff, 0x00, 0x00, 0x00]);
+        assert_eq!(rgb.pixel_bytes(1, 0), [0x00, 0x00, 0x00, 0x00]);
+        // 5:6:5のビットマスク
+        let rgb565 = PixelFormat::Bitmask {
+            red: 0xf800,
+            green: 0x07e0,
+            blue: 0x001f,
+        };
+        assert_eq!(rgb565.encode(0xff0000), 0xf800);
+        assert_eq!(rgb565.encode(0x00ff00), 0x07e0);
+        assert_eq!(rgb565.encode(0x0000ff), 0x001f);
+        // 10bitのチャンネルでは、0xffは0x3ffになる
+        let rgb101010 = PixelFormat::Bitmask {
+            red: 0x3ff0_0000,
+            green: 0x000f_fc00,
+            blue: 0x0000_03ff,
+        };
+        assert_eq!(rgb101010.encode(0xffffff), 0x3fff_ffff);
+        assert_eq!(rgb101010.encode(0x800000), 0x2020_0000);
+        assert_eq!(rgb101010.encode(0x000000), 0);
+    }
+
+    #[test_case]
+    fn gradient_interpolates_corner_colors() {
+        let mut buf = MockBitmap::new(5, 5);
+        draw_gradient(&mut buf, [0x000000, 0x800040, 0x008040, 0x808000]);
+        assert_eq!(buf.pixel(0, 0), 0x000000);
+        assert_eq!(buf.pixel(4, 0), 0x800040);
+        assert_eq!(buf.pixel(0, 4), 0x008040);
+        assert_eq!(buf.pixel(4, 4), 0x808000);
+        // 中央は4隅の平均
+        assert_eq!(buf.pixel(2, 2), 0x404020);
+        // 上端の中央は左上と右上の平均
+        assert_eq!(buf.pixel(2, 0), 0x400020);
+    }
+
+    #[test_case]
+    fn test_pattern_is_deterministic() {
+        let mut buf = MockBitmap::new(200, 300);
+        draw_test_pattern(&mut buf);
+        let left = 200 - 128 - 1;
+        for y in 0..256 {
+            for x in 0..=128 {
+                assert_eq!(
+                    buf.pixel(left + x, y),
+                    test_pattern_pixel(x, y).unwrap_or(0)
+                );
+            }
+        }
+        assert_eq!(test_pattern_pixel(10, 70), Some(0xff0000));
+        assert_eq!(test_pattern_pixel(100, 70), Some(0x00ffff));
+        assert_eq!(test_pattern_pixel(0, 100), Some(0xffffff));
+        assert_eq!(test_pattern_pixel(0, 200), Some(0x0000ff));
+        // 右端の辺はx == 128に描かれる
+        assert_eq!(test_pattern_pixel(128, 0), Some(0xffffff));
+        assert_eq!(test_pattern_pixel(128, 128), Some(0xffffff));
+        assert_eq!(test_pattern_pixel(128, 129), None);
+        assert_eq!(test_pattern_pixel(129, 0), None);
+        // 小さなバッファでもpanicせず、はみ出した部分を描かない
+        let mut small = MockBitmap::new(16, 16);
+        draw_test_pattern(&mut small);
+        assert_eq!(small.pixel(0, 0), 0xffffff);
+    }
+
+    #[test_case]
+    fn text_writer_wraps_and_scrolls() {
+        // 4文字 x 3行
+        let mut w = BitmapTextWriter::new(MockBitmap::new(32, 48));
+        write!(w, "abcde").unwrap();
+        assert_eq!(w.cursor(), (8, 16));
+        writeln!(w).unwrap();
+        assert_eq!(w.cursor(), (0, 32));
+        assert!(w.buf.count_non_zero(0, 0, 32, 16) > 0);
+        // 最終行で改行するとスクロールする
+        writeln!(w).unwrap();
+        assert_eq!(w.cursor(), (0, 32));
+        // 1行目の"abcd"は消え、2行目の"e"が1行目に移る
+        assert!(w.buf.count_non_zero(0, 0, 8, 16) > 0);
+        assert_eq!(w.buf.count_non_zero(8, 0, 24, 16), 0);
+        assert_eq!(w.buf.count_non_zero(0, 16, 32, 32), 0);
+    }
+
+    #[test_case]
+    fn text_writer_handles_control_characters() {
+        let mut w = BitmapTextWriter::new(MockBitmap::new(160, 32));
+        write!(w, "ab\x08c").unwrap();
+        assert_eq!(w.cursor(), (16, 0));
+        // "b"は消されて、同じセルに"c"だけが描かれている
+        let mut expected = BitmapTextWriter::new(MockBitmap::new(160, 32));
+        write!(expected, "ac").unwrap();
+        for y in 0..16 {
+            for x in 0..24 {
+                assert_eq!(w.buf.pixel(x, y), expected.buf.pixel(x, y));
+            }
+        }
+        // 0桁目でのバックスペースは何もしない
+        write!(w, "\r").unwrap();
+        assert_eq!(w.cursor(), (0, 0));
+        write!(w, "\x08").unwrap();
+        assert_eq!(w.cursor(), (0, 0));
+        assert!(w.buf.count_non_zero(0, 0, 8, 16) > 0);
+        // タブは次の8文字境界まで進める
+        write!(w, "x\t").unwrap();
+        assert_eq!(w.cursor(), (64, 0));
+        write!(w, "\t").unwrap();
+        assert_eq!(w.cursor(), (128, 0));
+        write!(w, "\t").unwrap();
+        assert_eq!(w.cursor(), (0, 16));
+    }
+
+    #[test_case]
+    fn text_writer_draws_with_fg_and_bg() {
+        let mut w = BitmapTextWriter::new(MockBitmap::new(32, 16));
+        fill_rect(&mut w.buf, 0x123456, 0, 0, 32, 16).unwrap();
+        w.set_fg(0xff0000);
+        w.set_bg(Some(0x000080));
+        write!(w, "A").unwrap();
+        let mut glyph = MockBitmap::new(8, 16);
+        draw_font_fg(&mut glyph, 0, 0, 0xffffff, 'A').unwrap();
+        for y in 0..16 {
+            for x in 0..8 {
+                let expected = if glyph.pixel(x, y) != 0 {
+                    0xff0000
+                } else {
+                    0x000080
+                };
+                assert_eq!(w.buf.pixel(x, y), expected);
+            }
+        }
+        // セルの外は塗られない
+        assert_eq!(w.buf.pixel(8, 0), 0x123456);
+        // 背景がNoneなら、グリフ以外の部分は元のまま残る
+        w.set_bg(None);
+        write!(w, "A").unwrap();
+        for y in 0..16 {
+            for x in 0..8 {
+                let expected = if glyph.pixel(x, y) != 0 {
+                    0xff0000
+                } else {
+                    0x123456
+                };
+                assert_eq!(w.buf.pixel(x + 8, y), expected);
+            }
+        }
+    }
+
+    #[test_case]
+    fn sub_bitmap_translates_and_clips() {
+        let mut parent = MockBitmap::new(32, 32);
+        {
+            let mut sub = SubBitmap::new(&mut parent, 8, 4, 8, 16).unwrap();
+            assert_eq!((sub.width(), sub.height()), (8, 16));
+            assert_eq!(fill_rect(&mut sub, 0x00ff00, 0, 0, 8, 16), Ok(()));
+            // サブ領域の外にはみ出す描画はしない
+            assert!(fill_rect(&mut sub, 0xff0000, 4, 0, 8, 1).is_err());
+            assert!(draw_line(&mut sub, 0xff0000, 0, 0, 8, 0).is_err());
+            assert_eq!(draw_font_fg(&mut sub, 4, 0, 0xff0000, 'W'), Ok(()));
+        }
+        for y in 0..32 {
+            for x in 0..32 {
+                let inside = (8..16).contains(&x) && (4..20).contains(&y);
+                if !inside {
+                    assert_eq!(parent.pixel(x, y), 0);
+                } else if x < 12 {
+                    assert_eq!(parent.pixel(x, y), 0x00ff00);
+                }
+            }
+        }
+        // "W"の左半分だけが(12, 4)から描かれている
+        let mut glyph = MockBitmap::new(8, 16);
+        draw_font_fg(&mut glyph, 0, 0, 0xff0000, 'W').unwrap();
+        for y in 0..16 {
+            for x in 0..4 {
+                let expected = if glyph.pixel(x, y) != 0 {
+                    0xff0000
+                } else {
+                    0x00ff00
+                };
+                assert_eq!(parent.pixel(x + 12, y + 4), expected);
+            }
+        }
+        // 親からはみ出すサブ領域は切り詰められる
+        let sub = SubBitmap::new(&mut parent, 24, 24, 16, 16).unwrap();
+        assert_eq!((sub.width(), sub.height()), (8, 8));
+        assert!(SubBitmap::new(&mut parent, 32, 0, 1, 1).is_err());
+    }
+
+    #[test_case]
+    fn draw_font_fg_clips_at_right_edge() {
+        let mut full = MockBitmap::new(16, 16);
+        assert_eq!(draw_font_fg(&mut full, 4, 0, 0xffffff, 'W'), Ok(()));
+        // グリフの右半分が画面外になる
+        let mut clipped = MockBitmap::new(8, 16);
+        assert_eq!(draw_font_fg(&mut clipped, 4, 0, 0xffffff, 'W'), Ok(()));
+        for y in 0..16 {
+            for x in 0..8 {
+                assert_eq!(clipped.pixel(x, y), full.pixel(x, y));
+            }
+        }
+        assert!(clipped.count_non_zero(4, 0, 4, 16) > 0);
+        assert_eq!(clipped.count_non_zero(0, 0, 4, 16), 0);
+        // 全体が画面外なら何も描画せずにエラー
+        let mut empty = MockBitmap::new(8, 16);
+        assert_eq!(
+            draw_font_fg(&mut empty, 8, 0, 0xffffff, 'W'),
+            Err(GraphicsError::OutOfBounds)
+        );
+        assert_eq!(
+            draw_font_fg(&mut empty, 0, -16, 0xffffff, 'W'),
+            Err(GraphicsError::OutOfBounds)
+        );
+        assert_eq!(empty.count_non_zero(0, 0, 8, 16), 0);
+    }
+}
@@ -1,23 +1,43 @@
+use crate::mmio::Mmio;
 use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
 use core::mem::size_of;
-use core::ptr::read_volatile;
-use core::ptr::write_volatile;
 use core::time::Duration;
 
 const TIMER_CONFIG_LEVEL_TRIGGER: u64 = 1 << 1;
 const TIMER_CONFIG_INT_ENABLE: u64 = 1 << 2;
 const TIMER_CONFIG_USE_PERIODIC_MODE: u64 = 1 << 3;
+const TIMER_CAP_PERIODIC: u64 = 1 << 4;
+const TIMER_CONFIG_VAL_SET: u64 = 1 << 6;
+const TIMER_CONFIG_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_CAP_FSB_DELIVERY: u64 = 1 << 15;
+// FSB(MSI)で割り込みを送る先: APIC ID 0 (BSP) のLocal APIC
+const MSI_ADDRESS_BSP: u64 = 0xfee0_0000;
+
+// 周期タイマー割り込みのベクタ番号と周期
+pub const TIMER_INTERRUPT_VECTOR: u8 = 32;
+pub const TIMER_TICK_PERIOD: Duration = Duration::from_millis(1);
+// ExecutorのTimerQueueが使うワンショットタイマー割り込みのベクタ番号
+pub const TIMER_QUEUE_INTERRUPT_VECTOR: u8 = 33;
+// 仕様上、カウンタの周期は100ns以下
+pub const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
 
 #[repr(C)]
 struct TimerRegister {
-    configuration_and_capability: u64,
-    _reserved: [u64; 3],
+    configuration_and_capability: Mmio<u64>,
+    comparator_value: Mmio<u64>,
+    fsb_interrupt_route: Mmio<u64>,
+    _reserved: u64,
 }
 const _: () = assert!(size_of::<TimerRegister>() == 0x20);
 
 impl TimerRegister {
-    unsafe fn write_config(&mut self, config: u64) {
-        write_volatile(&mut self.configuration_and_capability, config);
+    fn config(&self) -> u64 {
+        self.configuration_and_capability.read()
+    }
+    fn write_config(&mut self, config: u64) {
+        self.configuration_and_capability.write(config);
     }
 }
 
@@ -25,75 +45,172 @@ impl TimerRegister {
 // HPETのレジスタの参照（メモリマップドIO）
 #[repr(C)]
 pub struct HpetRegisters {
-    capabilities_and_id: u64,
+    capabilities_and_id: Mmio<u64>,
     _reserved0: u64,
-    configuration: u64,
+    configuration: Mmio<u64>,
     _reserved1: [u64; 27],
-    main_counter_value: u64,
+    main_counter_value: Mmio<u64>,
     _reserved2: u64,
     timers: [TimerRegister; 32],
 }
 const _: () = assert!(size_of::<HpetRegisters>() == 0x500);
 
+// General Capabilities and ID Register の各フィールド
+impl HpetRegisters {
+    fn capabilities(&self) -> u64 {
+        self.capabilities_and_id.read()
+    }
+    // 5bitのNUM_TIM_CAPは、最後のタイマーの番号
+    pub fn num_timers(&self) -> usize {
+        ((self.capabilities() >> 8) & 0b11111) as usize + 1
+    }
+    // 上位32bit: メインカウンタが1増えるまでの時間(フェムト秒)
+    pub fn counter_period_fs(&self) -> u64 {
+        self.capabilities() >> 32
+    }
+    pub fn is_64bit(&self) -> bool {
+        self.capabilities() & (1 << 13) != 0
+    }
+    pub fn vendor_id(&self) -> u16 {
+        (self.capabilities() >> 16) as u16
+    }
+    pub fn revision_id(&self) -> u8 {
+        self.capabilities() as u8
+    }
+    // 周期が0や仕様の上限を超えるHPETは使えない
+    pub fn validate(&self) -> Result<()> {
+        let period = self.counter_period_fs();
+        if period == 0 || period > HPET_MAX_PERIOD_FS {
+            return Err(Error::Failed("HPET: invalid counter period"));
+        }
+        Ok(())
+    }
+}
+
 pub struct Hpet {
     registers: &'static mut HpetRegisters,
-    #[allow(unused)]
     num_of_timers: usize,
     freq: u64,
 }
 impl Hpet {
     // HPETのインスタンスの初期化
-    pub fn new(registers: &'static mut HpetRegisters) -> Self {
-        // 上位32bitにHPETのカウンタがアップする周期
-        // 1/1fs あたりにカウントがどれだけ増加するか
-        let fs_per_count = registers.capabilities_and_id >> 32;
-        let num_of_timers = ((registers.capabilities_and_id >> 8) & 0b11111) as usize + 1;
+    pub fn new(registers: &'static mut HpetRegisters) -> Result<Self> {
+        registers.validate()?;
+        let num_of_timers = registers.num_timers();
         // タイマーの周波数
-        let freq = 1_000_000_000_000_000 / fs_per_count;
+        let freq = 1_000_000_000_000_000 / registers.counter_period_fs();
         let mut hpet = Self {
             registers,
             num_of_timers,
             freq,
         };
-        unsafe {
-            // HPETの無効化
-            hpet.globally_disable();
-            for i in 0..hpet.num_of_timers {
-                let timer = &mut hpet.registers.timers[i];
-                let mut config = read_volatile(&timer.configuration_and_capability);
-                config &= !(TIMER_CONFIG_INT_ENABLE
+        // HPETの無効化
+        hpet.globally_disable();
+        for i in 0..hpet.num_of_timers {
+            let timer = &mut hpet.registers.timers[i];
+            let config = timer.config()
+                & !(TIMER_CONFIG_INT_ENABLE
                     | TIMER_CONFIG_USE_PERIODIC_MODE
                     | TIMER_CONFIG_LEVEL_TRIGGER
                     | (0b11111 << 9));
-                timer.write_config(config);
-            }
-            // HPETの各タイマーで利用される大元のカウントmain_counter_valueの値を0に初期化
-            write_volatile(&mut hpet.registers.main_counter_value, 0);
-            // HPETを有効化
-            hpet.globally_enable();
+            timer.write_config(config);
         }
-        hpet
+        // HPETの各タイマーで利用される大元のカウントmain_counter_valueの値を0に初期化
+        hpet.registers.main_counter_value.write(0);
+        // HPETを有効化
+        hpet.globally_enable();
+        Ok(hpet)
     }
-    unsafe fn globally_disable(&mut self) {
-        let config = read_volatile(&self.registers.configuration) & !0b11;
-        write_volatile(&mut self.registers.configuration, config);
+    fn globally_disable(&mut self) {
+        self.registers.configuration.update(|config| config & !0b11);
     }
-    unsafe fn globally_enable(&mut self) {
-        let config = read_volatile(&self.registers.configuration) | 0b01;
-        write_volatile(&mut self.registers.configuration, config);
+    fn globally_enable(&mut self) {
+        self.registers.configuration.update(|config| config | 0b01);
     }
     pub fn main_counter(&self) -> u64 {
-        unsafe { read_volatile(&self.registers.main_counter_value) }
+        self.registers.main_counter_value.read()
     }
     pub fn freq(&self) -> u64 {
         self.freq
     }
+    // タイマー0から、periodごとにvectorの割り込みをFSB経由でBSPに送らせる
+    pub fn start_periodic_timer(&mut self, period: Duration, vector: u8) -> Result<()> {
+        let ticks = (period.as_nanos() * self.freq as u128 / 1_000_000_000) as u64;
+        if ticks == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let now = self.main_counter();
+        self.globally_disable();
+        let timer = &mut self.registers.timers[0];
+        let config = timer.config();
+        if config & TIMER_CAP_PERIODIC == 0 || config & TIMER_CAP_FSB_DELIVERY == 0 {
+            self.globally_enable();
+            return Err(Error::Failed(
+                "HPET: timer 0 does not support periodic FSB interrupts",
+            ));
+        }
+        timer
+            .fsb_interrupt_route
+            .write((MSI_ADDRESS_BSP << 32) | vector as u64);
+        timer.write_config(
+            (config & !TIMER_CONFIG_LEVEL_TRIGGER)
+                | TIMER_CONFIG_INT_ENABLE
+                | TIMER_CONFIG_USE_PERIODIC_MODE
+                | TIMER_CONFIG_VAL_SET
+                | TIMER_CONFIG_FSB_ENABLE,
+        );
+        // VAL_SETを立てた後は、1回目の書き込みで最初の発火時刻、2回目で周期が設定される
+        timer.comparator_value.write(now + ticks);
+        timer.comparator_value.write(ticks);
+        self.globally_enable();
+        Ok(())
+    }
+    // タイマー1から、main_counterがdeadlineに達したときにvectorの割り込みを1回だけ送らせる
+    pub fn set_oneshot_timer(&mut self, deadline: Duration, vector: u8) -> Result<()> {
+        // 早く発火しないよう切り上げる
+        let ticks = (deadline.as_nanos() * self.freq as u128).div_ceil(1_000_000_000) as u64;
+        if self.num_of_timers < 2 {
+            return Err(Error::Failed("HPET: timer 1 is not available"));
+        }
+        let timer = &mut self.registers.timers[1];
+        let config = timer.config();
+        if config & TIMER_CAP_FSB_DELIVERY == 0 {
+            return Err(Error::Failed(
+                "HPET: timer 1 does not support FSB interrupts",
+            ));
+        }
+        timer
+            .fsb_interrupt_route
+            .write((MSI_ADDRESS_BSP << 32) | vector as u64);
+        timer.comparator_value.write(ticks);
+        timer.write_config(
+            (config & !(TIMER_CONFIG_LEVEL_TRIGGER | TIMER_CONFIG_USE_PERIODIC_MODE))
+                | TIMER_CONFIG_INT_ENABLE
+                | TIMER_CONFIG_FSB_ENABLE,
+        );
+        Ok(())
+    }
 }
 static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
 pub fn set_global_hpet(hpet: Hpet) {
     assert!(HPET.lock().is_none());
     *HPET.lock() = Some(hpet)
 }
+pub fn is_global_hpet_initialized() -> bool {
+    HPET.lock().is_some()
+}
+pub fn start_periodic_timer(period: Duration, vector: u8) -> Result<()> {
+    HPET.lock()
+        .as_mut()
+        .ok_or(Error::Failed("HPET is not initialized"))?
+        .start_periodic_timer(period, vector)
+}
+pub fn set_oneshot_timer(deadline: Duration, vector: u8) -> Result<()> {
+    HPET.lock()
+        .as_mut()
+        .ok_or(Error::Failed("HPET is not initialized"))?
+        .set_oneshot_timer(deadline, vector)
+}
 pub fn global_timestamp() -> Duration {
     if let Some(hpet) = &*HPET.lock() {
         let ns = hpet.main_counter() as u128 * 1_000_000_000 / hpet.freq() as u128;
@@ -102,3 +219,40 @@ pub fn global_timestamp() -> Duration {
         Duration::ZERO
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::MaybeUninit;
+
+    fn registers_with_capabilities(capabilities: u64) -> HpetRegisters {
+        let mut registers: HpetRegisters = unsafe { MaybeUninit::zeroed().assume_init() };
+        registers.capabilities_and_id.write(capabilities);
+        registers
+    }
+
+    #[test_case]
+    fn decode_capabilities() {
+        // QEMUのHPET: 周期10ns, ベンダー0x8086, 64bitカウンタ, タイマー3個, リビジョン1
+        let registers = registers_with_capabilities(0x0098_9680_8086_a201);
+        assert_eq!(registers.counter_period_fs(), 10_000_000);
+        assert_eq!(registers.vendor_id(), 0x8086);
+        assert!(registers.is_64bit());
+        assert_eq!(registers.num_timers(), 3);
+        assert_eq!(registers.revision_id(), 1);
+        assert_eq!(registers.validate(), Ok(()));
+
+        let registers = registers_with_capabilities(0x0000_0001_1022_1f01);
+        assert_eq!(registers.counter_period_fs(), 1);
+        assert_eq!(registers.vendor_id(), 0x1022);
+        assert!(!registers.is_64bit());
+        assert_eq!(registers.num_timers(), 32);
+
+        assert!(registers_with_capabilities(0x0000_0000_8086_a201)
+            .validate()
+            .is_err());
+        assert!(registers_with_capabilities((HPET_MAX_PERIOD_FS + 1) << 32)
+            .validate()
+            .is_err());
+    }
+}
@@ -1,16 +1,35 @@
 extern crate alloc;
 
 use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::MadtEntry;
+use crate::allocator::HeapConfig;
 use crate::allocator::ALLOCATOR;
+use crate::apic::IoApic;
+use crate::apic::LocalApic;
+use crate::frame_allocator::find_frame_pool;
+use crate::frame_allocator::FRAME_ALLOCATOR;
+use crate::frame_allocator::FRAME_POOL_PAGES;
 use crate::hpet::set_global_hpet;
+use crate::hpet::start_periodic_timer;
 use crate::hpet::Hpet;
+use crate::hpet::TIMER_INTERRUPT_VECTOR;
+use crate::hpet::TIMER_TICK_PERIOD;
 use crate::info;
+use crate::mtrr::set_write_combining;
 use crate::pci::Pci;
+use crate::result::Error;
+use crate::result::Result;
+use crate::serial::SerialPort;
+use crate::serial::COM1_IRQ;
+use crate::serial::SERIAL_INTERRUPT_VECTOR;
+use crate::smp::start_aps;
+use crate::tsc::calibrate_tsc;
 use crate::uefi::exit_from_boot_services;
 use crate::uefi::EfiHandle;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
 use crate::uefi::VramBufferInfo;
+use crate::warn;
 
 use crate::uefi::EfiMemoryType;
 use crate::uefi::EfiMemoryType::*;
@@ -18,12 +37,25 @@ use crate::uefi::EfiMemoryType::*;
 use crate::graphics::draw_test_pattern;
 use crate::graphics::fill_rect;
 use crate::graphics::Bitmap;
+use crate::x86::enable_interrupts;
+use crate::x86::enable_write_combining;
+use crate::x86::set_stack_guard_page;
 use crate::x86::write_cr3;
 use crate::x86::PageAttr;
+use crate::x86::LARGE_PAGE_SIZE;
 use crate::x86::PAGE_SIZE;
 use crate::x86::PML4;
+use alloc::alloc::Layout;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::cmp::max;
+use core::ops::Range;
+
+// 1MiB未満はAPのトランポリンやレガシーなデバイスのために、ヒープに入れずに残しておく
+pub const HEAP_CONFIG: HeapConfig = HeapConfig {
+    low_memory_limit: 0x10_0000,
+    max_size: None,
+};
 
 pub fn init_basic_runtime(
     image_handle: EfiHandle,
@@ -31,41 +63,225 @@ pub fn init_basic_runtime(
 ) -> MemoryMapHolder {
     let mut memory_map = MemoryMapHolder::new();
     exit_from_boot_services(image_handle, efi_system_table, &mut memory_map);
-    ALLOCATOR.init_with_mmap(&memory_map);
+    // ページテーブル用のフレームは、ヒープに渡す前に空き領域から切り出しておく
+    let frame_pool = find_frame_pool(&memory_map, FRAME_POOL_PAGES)
+        .expect("No memory region is large enough for the frame pool");
+    FRAME_ALLOCATOR
+        .lock()
+        .init(frame_pool.clone())
+        .expect("Failed to initialize FrameAllocator");
+    ALLOCATOR.init_with_mmap(&memory_map, HEAP_CONFIG);
+    ALLOCATOR
+        .reserve(frame_pool.start, frame_pool.len())
+        .expect("Failed to exclude the frame pool from the heap");
+    memory_map
+}
+
+// メモリマップに載っている領域の末尾を、種類を問わず求める
+// MMIOの領域(Local APICやHPETなど)はメモリマップに載らないことがあるので、最低でも4GiBとする
+pub fn end_of_physical_memory(memory_map: &MemoryMapHolder) -> u64 {
+    memory_map
+        .iter()
+        .map(|e| e.physical_start() + e.number_of_pages() * (PAGE_SIZE as u64))
+        .fold(0x1_0000_0000u64, max)
+}
+
+// Local APIC、I/O APIC、HPETのレジスタが置かれる領域
+// メモリマップに載らないことがあるので、常にキャッシュ無効でマップする
+pub const APIC_MMIO_RANGE: Range<u64> = 0xfec0_0000..0x1_0000_0000;
+
+// メモリマップのMMIOの領域とAPIC_MMIO_RANGE
+pub fn mmio_ranges(memory_map: &MemoryMapHolder) -> Vec<Range<u64>> {
     memory_map
+        .iter()
+        .filter(|e| {
+            matches!(
+                e.memory_type(),
+                MEMORY_MAPPED_IO | MEMORY_MAPPED_IO_PORT_SPACE
+            )
+        })
+        .map(|e| e.physical_range())
+        .chain(core::iter::once(APIC_MMIO_RANGE))
+        .collect()
 }
 
-pub fn init_paging(memory_map: &MemoryMapHolder) {
+// [0, end_of_mem)を2MiBページでアイデンティティマップしたページテーブルを作る
+// mmioの領域はキャッシュ無効、フレームバッファはライトコンバインにし、
+// 0番地のページはNULL参照を検出するためにマップしない
+pub fn build_identity_map(
+    end_of_mem: u64,
+    frame_buffer: Range<usize>,
+    mmio: &[Range<u64>],
+) -> Result<Box<PML4>> {
     let mut table = PML4::new();
-    let mut end_of_mem = 0x1_0000_0000u64;
-    for e in memory_map.iter() {
-        match e.memory_type() {
-            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA => {
-                end_of_mem = max(
-                    end_of_mem,
-                    e.physical_start() + e.number_of_pages() * (PAGE_SIZE as u64),
-                );
-            }
-            _ => (),
-        }
+    let end_of_mem = end_of_mem.div_ceil(LARGE_PAGE_SIZE as u64) * LARGE_PAGE_SIZE as u64;
+    table.create_mapping_2m(0, end_of_mem, 0, PageAttr::WriteBack)?;
+    for range in mmio {
+        let start = range.start & !(PAGE_SIZE as u64 - 1);
+        let end = (range.end + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+        table.create_mapping(start, end, start, PageAttr::Uncacheable)?;
     }
-    table
-        .create_mapping(0, end_of_mem, 0, PageAttr::ReadWriteKernel)
-        .expect("Failed to create initial page mapping");
-    table
-        .create_mapping(0, 4096, 0, PageAttr::NotPresent)
-        .expect("Failed to unmap page 0");
+    let fb_start = frame_buffer.start as u64 & !(PAGE_SIZE as u64 - 1);
+    let fb_end = (frame_buffer.end as u64 + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+    table.create_mapping(fb_start, fb_end, fb_start, PageAttr::WriteCombining)?;
+    table.unmap(0, PAGE_SIZE)?;
+    Ok(table)
+}
+
+// 返されたKernelStackには、switch_stackで切り替えて使う
+pub fn init_paging(memory_map: &MemoryMapHolder, frame_buffer: Range<usize>) -> KernelStack {
+    // フレームバッファへの書き込みはライトコンバインでまとめて行う
+    enable_write_combining();
+    let mut table = build_identity_map(
+        end_of_physical_memory(memory_map),
+        frame_buffer,
+        &mmio_ranges(memory_map),
+    )
+    .expect("Failed to create initial page mapping");
+    let stack = KernelStack::alloc(KERNEL_STACK_SIZE).expect("Failed to allocate the kernel stack");
+    install_stack_guard(&mut table, &stack).expect("Failed to map the stack guard page");
     unsafe { write_cr3(Box::into_raw(table)) }
+    stack
+}
+
+// ページの属性に加えて、MTRRでもフレームバッファの物理アドレスの範囲をライトコンバインにする
+pub fn init_frame_buffer_mtrr(frame_buffer: Range<usize>) {
+    match set_write_combining(frame_buffer.start as u64..frame_buffer.end as u64) {
+        Ok(indices) => info!("Frame buffer is set to write-combining by MTRR{indices:?}"),
+        Err(e) => warn!("Frame buffer MTRR is not set: {e:?}"),
+    }
+}
+
+pub const KERNEL_STACK_SIZE: usize = 512 * 1024;
+
+// 直下にガードページを持つスタック
+// UEFIから引き継いだスタックは下端が分からないので、ヒープから確保したものに切り替えて使う
+pub struct KernelStack {
+    guard: u64,
+    size: usize,
+}
+impl KernelStack {
+    // カーネルが動いている間は使い続けるので、確保した領域は解放しない
+    pub fn alloc(size: usize) -> Result<Self> {
+        let size = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let layout = Layout::from_size_align(size + PAGE_SIZE, PAGE_SIZE)
+            .map_err(|_| Error::InvalidArgument)?;
+        let guard = ALLOCATOR.try_alloc(layout)?.as_ptr() as u64;
+        Ok(Self { guard, size })
+    }
+    pub fn guard_page(&self) -> u64 {
+        self.guard
+    }
+    pub fn bottom(&self) -> u64 {
+        self.guard + PAGE_SIZE as u64
+    }
+    pub fn top(&self) -> u64 {
+        self.bottom() + self.size as u64
+    }
+}
+
+// スタックの直下のページをNotPresentにして、スタックオーバーフローを#PFで検出する
+pub fn install_stack_guard(table: &mut PML4, stack: &KernelStack) -> Result<()> {
+    let guard = stack.guard_page();
+    table.create_mapping(guard, stack.bottom(), guard, PageAttr::NotPresent)?;
+    set_stack_guard_page(guard);
+    info!(
+        "Stack guard page is at {guard:#018X} (stack: {:#018X}..{:#018X})",
+        stack.bottom(),
+        stack.top()
+    );
+    Ok(())
+}
+
+pub fn init_hpet(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let hpet = acpi
+        .hpet()
+        .ok_or(Error::Failed("HPET is not found in ACPI"))?
+        .base_address()?;
+    info!(
+        "HPET is at {hpet:#p}: vendor {:#06X}, period {} fs, {} timers, {}-bit counter",
+        hpet.vendor_id(),
+        hpet.counter_period_fs(),
+        hpet.num_timers(),
+        if hpet.is_64bit() { 64 } else { 32 }
+    );
+    set_global_hpet(Hpet::new(hpet)?);
+    Ok(())
+}
+
+pub fn init_tsc() {
+    match calibrate_tsc() {
+        Ok(freq) => info!("TSC is calibrated: {} MHz", freq / 1_000_000),
+        Err(e) => warn!("TSC is not used, falling back to HPET: {e:?}"),
+    }
+}
+
+// HPETの周期タイマー割り込みを有効にする（Executorのプリエンプションに使う）
+pub fn init_timer_interrupt() {
+    match start_periodic_timer(TIMER_TICK_PERIOD, TIMER_INTERRUPT_VECTOR) {
+        Ok(()) => {
+            enable_interrupts();
+            info!("Periodic timer interrupt is enabled: {TIMER_TICK_PERIOD:?}");
+        }
+        Err(e) => warn!("Periodic timer interrupt is not available: {e:?}"),
+    }
 }
 
-pub fn init_hpet(acpi: &AcpiRsdpStruct) {
-    let hpet = acpi.hpet().expect("Failed to get HPET from ACPI");
-    let hpet = hpet
-        .base_address()
-        .expect("Failed to get HPET base address");
-    info!("HPET is at {hpet:#p}");
-    let hpet = Hpet::new(hpet);
-    set_global_hpet(hpet);
+// COM1の受信割り込み(ISA IRQ4)をI/O APIC経由でBSPに送らせる
+// ISA IRQ4にはInterrupt Source Overrideがない前提で、GSI 4として扱う
+pub fn init_serial_interrupt(acpi: &AcpiRsdpStruct) {
+    let Some(madt) = acpi.madt() else {
+        warn!("Serial interrupt is not enabled: MADT is not found");
+        return;
+    };
+    let Some(io_apic) = madt.iter().find_map(|e| match e {
+        MadtEntry::IoApic {
+            address,
+            global_system_interrupt_base,
+            ..
+        } => Some(IoApic::new(address as usize, global_system_interrupt_base)),
+        _ => None,
+    }) else {
+        warn!("Serial interrupt is not enabled: I/O APIC is not found");
+        return;
+    };
+    if let Err(e) = io_apic.redirect(COM1_IRQ, SERIAL_INTERRUPT_VECTOR, 0) {
+        warn!("Serial interrupt is not enabled: {e:?}");
+        return;
+    }
+    SerialPort::default().enable_receive_interrupt();
+    info!(
+        "Serial interrupt is enabled: IRQ{COM1_IRQ} -> vector {SERIAL_INTERRUPT_VECTOR} ({:#018X})",
+        io_apic.redirection_entry(COM1_IRQ).unwrap_or_default()
+    );
+}
+
+pub fn init_local_apic(acpi: &AcpiRsdpStruct) -> LocalApic {
+    let apic = LocalApic::current();
+    if let Some(madt) = acpi.madt() {
+        if madt.local_apic_address() != apic.base() {
+            warn!(
+                "Local APIC address mismatch: MADT = {:#X}, MSR = {:#X}",
+                madt.local_apic_address(),
+                apic.base()
+            );
+        }
+    }
+    apic.enable();
+    info!("Local APIC is enabled at {:#X}", apic.base());
+    apic
+}
+
+// MADTに載っているBSP以外のCPUを起動する
+pub fn init_smp(acpi: &AcpiRsdpStruct, memory_map: &MemoryMapHolder, entry: fn() -> !) {
+    let Some(madt) = acpi.madt() else {
+        warn!("APs are not started: MADT is not found");
+        return;
+    };
+    match start_aps(madt, memory_map, entry) {
+        Ok(n) => info!("{n} APs are started"),
+        Err(e) => warn!("APs are not started: {e:?}"),
+    }
 }
 
 pub fn init_allocator(memory_map: &MemoryMapHolder) {
@@ -81,6 +297,25 @@ pub fn init_allocator(memory_map: &MemoryMapHolder) {
     info!("Total: {total_memory_pages} pages = {total_memory_size_mib} MiB");
 }
 
+// フレームバッファとACPIのテーブルがある領域をアロケータから除外する
+pub fn reserve_firmware_regions(memory_map: &MemoryMapHolder, frame_buffer: Range<usize>) {
+    let acpi_regions = memory_map
+        .iter()
+        .filter(|e| matches!(e.memory_type(), ACPI_RECLAIM_MEMORY | ACIP_MEMORY_NVS))
+        .map(|e| {
+            let start = e.physical_start() as usize;
+            start..start + e.number_of_pages() as usize * PAGE_SIZE
+        });
+    for range in core::iter::once(frame_buffer).chain(acpi_regions) {
+        if let Err(e) = ALLOCATOR.reserve(range.start, range.len()) {
+            warn!(
+                "Failed to reserve {:#X}..{:#X}: {e:?}",
+                range.start, range.end
+            );
+        }
+    }
+}
+
 pub fn init_display(vram: &mut VramBufferInfo) {
     let vw = vram.width();
     let vh = vram.height();
@@ -99,3 +334,96 @@ pub fn init_pci(acpi: &AcpiRsdpStruct) {
         pci.probe_devices();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::is_stack_guard_fault;
+    use crate::x86::TranslationResult;
+    use alloc::format;
+    use core::alloc::GlobalAlloc;
+
+    #[test_case]
+    fn identity_map_covers_firmware_regions() {
+        // QEMU+OVMFでのフレームバッファとACPIのテーブルの典型的な物理アドレス
+        let frame_buffer = 0x8000_0000usize..0x8000_0000 + 1024 * 768 * 4;
+        let acpi_table = 0x7FB7_E014u64;
+        let pci_bar = 0xc000_0000u64..0xc000_4000;
+        let table = build_identity_map(
+            0x1_0000_0000,
+            frame_buffer.clone(),
+            &[pci_bar.clone(), APIC_MMIO_RANGE],
+        )
+        .expect("build_identity_map failed");
+        assert_eq!(
+            table.translate(frame_buffer.start as u64),
+            Ok(TranslationResult::PageMapped4K {
+                phys: frame_buffer.start as u64
+            })
+        );
+        assert_eq!(
+            table.translate(acpi_table),
+            Ok(TranslationResult::PageMapped2M { phys: acpi_table })
+        );
+        // デバイスのレジスタはキャッシュ無効
+        for mmio in [
+            pci_bar.start,
+            pci_bar.end - 1,
+            0xFEC0_0000,
+            0xFED0_0000,
+            0xFEE0_0020,
+        ] {
+            assert_eq!(
+                table.translate(mmio),
+                Ok(TranslationResult::PageMapped4K { phys: mmio })
+            );
+            let pte = table.leaf_entry(mmio).expect("PTE not found");
+            assert!(
+                format!("{pte:?}").ends_with("[PRESENT|WRITABLE|WRITE_THROUGH|CACHE_DISABLE] }")
+            );
+        }
+        assert_eq!(
+            table.translate(pci_bar.end),
+            Ok(TranslationResult::PageMapped2M { phys: pci_bar.end })
+        );
+        assert!(table.translate(0).is_err());
+        assert!(table.translate(0x1_0000_0000).is_err());
+    }
+
+    #[test_case]
+    fn stack_guard_is_directly_below_the_stack() {
+        let stack = KernelStack::alloc(4 * PAGE_SIZE).expect("Failed to allocate a stack");
+        assert_eq!(stack.bottom(), stack.guard_page() + PAGE_SIZE as u64);
+        assert_eq!(stack.top() - stack.bottom(), 4 * PAGE_SIZE as u64);
+        let mut table = PML4::new();
+        table
+            .create_mapping(
+                stack.guard_page(),
+                stack.top(),
+                stack.guard_page(),
+                PageAttr::WriteBack,
+            )
+            .expect("create_mapping failed");
+        install_stack_guard(&mut table, &stack).expect("install_stack_guard failed");
+        assert!(table.translate(stack.guard_page()).is_err());
+        assert!(table.translate(stack.bottom() - 8).is_err());
+        assert!(is_stack_guard_fault(stack.bottom() - 8));
+        // スタックの最も下のバイトから上は使える
+        assert_eq!(
+            table.translate(stack.bottom()),
+            Ok(TranslationResult::PageMapped4K {
+                phys: stack.bottom()
+            })
+        );
+        assert!(!is_stack_guard_fault(stack.bottom()));
+        assert_eq!(
+            table.translate(stack.top() - 8),
+            Ok(TranslationResult::PageMapped4K {
+                phys: stack.top() - 8
+            })
+        );
+        set_stack_guard_page(0);
+        let layout = Layout::from_size_align(4 * PAGE_SIZE + PAGE_SIZE, PAGE_SIZE).unwrap();
+        unsafe { ALLOCATOR.dealloc(stack.guard_page() as *mut u8, layout) };
+    }
+}
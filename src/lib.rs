@@ -1,24 +1,39 @@
 #![no_std]
 #![feature(offset_of)]
+#![feature(asm_const)]
 #![feature(custom_test_frameworks)]
 #![feature(sync_unsafe_cell)]
 #![feature(const_caller_location)]
 #![feature(const_location_fields)]
+#![feature(panic_info_message)]
 #![test_runner(crate::test_runner::test_runner)]
 #![reexport_test_harness_main = "run_unit_tests"]
 #![no_main]
 pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod bmp;
+pub mod console;
+pub mod elf;
 pub mod executor;
+pub mod frame_allocator;
 pub mod graphics;
 pub mod hpet;
 pub mod init;
+pub mod mmio;
+pub mod mtrr;
 pub mod mutex;
 pub mod pci;
+pub mod percpu;
+pub mod power;
 pub mod print;
+pub mod ps2;
 pub mod qemu;
 pub mod result;
+pub mod rtc;
 pub mod serial;
+pub mod smp;
+pub mod tsc;
 pub mod uefi;
 pub mod x86;
 
@@ -28,7 +43,12 @@ pub mod test_runner;
 #[cfg(test)]
 #[no_mangle]
 pub fn efi_main(image_handle: uefi::EfiHandle, efi_system_table: &uefi::EfiSystemTable) {
+    let acpi = efi_system_table.acpi_table();
     init::init_basic_runtime(image_handle, efi_system_table);
+    // HPETを使うテストのために初期化しておく
+    if let Ok(acpi) = acpi {
+        init::init_hpet(acpi).expect("Failed to initialize HPET");
+    }
 
     run_unit_tests()
 }
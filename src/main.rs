@@ -1,10 +1,12 @@
 #![no_std]
 #![feature(offset_of)]
+#![feature(panic_info_message)]
 #![no_main]
 
 use core::panic::PanicInfo;
 use core::time::Duration;
 
+use wasabi::console;
 use wasabi::executor::Executor;
 use wasabi::executor::Task;
 use wasabi::executor::TimeoutFuture;
@@ -12,24 +14,36 @@ use wasabi::executor::TimeoutFuture;
 use wasabi::info;
 use wasabi::init::init_allocator;
 use wasabi::init::init_display;
+use wasabi::init::init_frame_buffer_mtrr;
 use wasabi::init::init_hpet;
+use wasabi::init::init_local_apic;
 use wasabi::init::init_paging;
 use wasabi::init::init_pci;
+use wasabi::init::init_serial_interrupt;
+use wasabi::init::init_smp;
+use wasabi::init::init_timer_interrupt;
+use wasabi::init::init_tsc;
+use wasabi::init::reserve_firmware_regions;
+use wasabi::percpu::init_percpu;
 use wasabi::qemu::exit_qemu;
 use wasabi::qemu::QemuExitCode;
+use wasabi::rtc::read_rtc;
 
+use wasabi::serial::read_serial_byte;
 use wasabi::serial::SerialPort;
 use wasabi::uefi::init_vram;
 use wasabi::uefi::locate_loaded_image_protocol;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiSystemTable;
+use wasabi::x86::hlt;
+use wasabi::x86::switch_stack;
 
 use wasabi::warn;
 
 use wasabi::error;
 use wasabi::init::init_basic_runtime;
 use wasabi::print::hexdump;
-use wasabi::print::set_global_vram;
+use wasabi::print::write_panic_info;
 use wasabi::println;
 
 use wasabi::x86::init_exceptions;
@@ -38,6 +52,9 @@ use wasabi::hpet::global_timestamp;
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+    SerialPort::default()
+        .init(115200)
+        .expect("Failed to initialize COM1");
     println!("Booting WasabiOS...");
     println!("image_handle: {:#018X}", image_handle);
     println!("efi_system_table: {:#p}", efi_system_table);
@@ -52,68 +69,101 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     let mut vram = init_vram(efi_system_table).expect("init_vram failed");
     let acpi = efi_system_table.acpi_table().expect("ACPI table not found");
     info!("{acpi:#p}");
+    info!(
+        "ACPI OEM ID: {}, revision: {}",
+        acpi.oem_id(),
+        acpi.revision()
+    );
+    for signature in acpi.list_tables() {
+        info!(
+            "ACPI table found: {}",
+            core::str::from_utf8(&signature).unwrap_or("????")
+        );
+    }
     hexdump(acpi);
 
     init_display(&mut vram);
 
-    set_global_vram(vram);
+    let frame_buffer = vram.frame_buffer_range();
+    console::init(vram);
     let memory_map = init_basic_runtime(image_handle, efi_system_table);
     init_allocator(&memory_map);
+    reserve_firmware_regions(&memory_map, frame_buffer.clone());
     info!("Hello, Non-UEFI world!\nThis is test");
 
     // 例外の初期化
     let (_gdt, _idt) = init_exceptions();
 
-    init_paging(&memory_map);
-
-    init_hpet(acpi);
-    init_pci(acpi);
-    let t0 = global_timestamp();
-
-    let task1 = Task::new(async move {
-        for i in 100..=103 {
-            info!("{i} hpet.main_counter = {:?}", global_timestamp() - t0);
-            TimeoutFuture::new(Duration::from_secs(1)).await
+    let stack = init_paging(&memory_map, frame_buffer.clone());
+    // 以降は直下にガードページのあるカーネルスタックで実行する
+    switch_stack(stack.top(), move || {
+        init_frame_buffer_mtrr(frame_buffer);
+
+        let apic = init_local_apic(acpi);
+        init_percpu(0, apic.id());
+
+        init_hpet(acpi).expect("Failed to initialize HPET");
+        init_tsc();
+        init_smp(acpi, &memory_map, ap_idle);
+        init_timer_interrupt();
+        // 受信割り込みを有効にするとループバックのデータを割り込みハンドラが読んでしまうので、先に確認する
+        if let Err(e) = SerialPort::default().loopback_test() {
+            error!("serial: loopback test failed: {e:?}");
         }
-        Ok(())
-    });
+        init_serial_interrupt(acpi);
+        init_pci(acpi);
+        info!("RTC: {}", read_rtc());
+        let t0 = global_timestamp();
+
+        let task1 = Task::new(async move {
+            for i in 100..=103 {
+                info!("{i} hpet.main_counter = {:?}", global_timestamp() - t0);
+                TimeoutFuture::new(Duration::from_secs(1)).await
+            }
+            Ok(())
+        });
 
-    let task2 = Task::new(async move {
-        for i in 200..=203 {
-            info!("{i} hpet.main_counter = {:?}", global_timestamp() - t0);
-            TimeoutFuture::new(Duration::from_secs(2)).await
-        }
-        Ok(())
-    });
-    let serial_task = Task::new(async {
-        let sp = SerialPort::default();
-        if let Err(e) = sp.loopback_test() {
-            error!("{e:?}");
-            return Err("serial: loopback test failed");
-        }
-        info!("Started to monitor serial port");
-        loop {
-            if let Some(v) = sp.try_read() {
+        let task2 = Task::new(async move {
+            for i in 200..=203 {
+                info!("{i} hpet.main_counter = {:?}", global_timestamp() - t0);
+                TimeoutFuture::new(Duration::from_secs(2)).await
+            }
+            Ok(())
+        });
+        let serial_task = Task::new(async {
+            info!("Started to monitor serial port");
+            loop {
+                let v = read_serial_byte().await?;
                 let c = char::from_u32(v as u32);
                 info!("serial input: {v:#04X} = {c:?}");
             }
-            TimeoutFuture::new(Duration::from_millis(20)).await;
-        }
-    });
+        });
+
+        let mut executor = Executor::new();
+        executor.set_quantum(Duration::from_millis(10));
+        executor.enqueue(task1);
+        executor.enqueue(task2);
+        executor.enqueue(serial_task);
+        Executor::run(executor)
+    })
+}
 
-    let mut executor = Executor::new();
-    executor.enqueue(task1);
-    executor.enqueue(task2);
-    executor.enqueue(serial_task);
-    Executor::run(executor)
+// APはまだ割り込みを受け付けないので、起動したら止めておく
+fn ap_idle() -> ! {
+    loop {
+        hlt()
+    }
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
     // loop {
     //     hlt()
     // }
 
+    // println!はロックを取るので、シリアルポートに直接書き込む
+    let mut sw = SerialPort::default();
+    let _ = write_panic_info(&mut sw, info.message(), info.location());
     exit_qemu(QemuExitCode::Fail)
 }
 
@@ -0,0 +1,46 @@
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+// メモリマップドIOのレジスタ
+// 普通のフィールドへのアクセスはコンパイラにまとめられたり省かれたりするので、
+// 必ずread_volatile/write_volatileを通して、書いた順番通りにデバイスにアクセスする
+#[repr(transparent)]
+pub struct Mmio<T: Copy> {
+    value: T,
+}
+impl<T: Copy> Mmio<T> {
+    pub fn read(&self) -> T {
+        unsafe { read_volatile(&self.value) }
+    }
+    pub fn write(&mut self, value: T) {
+        unsafe { write_volatile(&mut self.value, value) }
+    }
+    // 読んだ値をfで変えて書き戻す
+    pub fn update(&mut self, f: impl FnOnce(T) -> T) {
+        let value = self.read();
+        self.write(f(value));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::align_of;
+    use core::mem::size_of;
+
+    #[test_case]
+    fn mmio_round_trips_through_backing_buffer() {
+        assert_eq!(size_of::<Mmio<u64>>(), size_of::<u64>());
+        assert_eq!(align_of::<Mmio<u64>>(), align_of::<u64>());
+        assert_eq!(size_of::<Mmio<u32>>(), size_of::<u32>());
+        // MMIOの代わりのメモリ
+        let mut backing = [0u64; 4];
+        let registers = unsafe { &mut *(backing.as_mut_ptr() as *mut [Mmio<u64>; 4]) };
+        registers[1].write(0x1234_5678_9abc_def0);
+        assert_eq!(registers[1].read(), 0x1234_5678_9abc_def0);
+        registers[2].update(|v| v | 0b101);
+        registers[2].update(|v| v & !0b001);
+        assert_eq!(registers[2].read(), 0b100);
+        assert_eq!(backing, [0, 0x1234_5678_9abc_def0, 0b100, 0]);
+    }
+}
@@ -0,0 +1,374 @@
+extern crate alloc;
+
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
+use crate::x86::flush_tlb;
+use crate::x86::interrupts_enabled;
+use crate::x86::physical_address_bits;
+use crate::x86::read_cr0;
+use crate::x86::read_msr;
+use crate::x86::wbinvd;
+use crate::x86::write_cr0;
+use crate::x86::write_msr;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const MSR_IA32_MTRRCAP: u32 = 0xfe;
+const MSR_IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const MSR_IA32_MTRR_PHYSMASK0: u32 = 0x201;
+const MSR_IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+
+// IA32_MTRRCAPのビット
+const MTRRCAP_VCNT_MASK: u64 = 0xff;
+const MTRRCAP_WC: u64 = 1 << 10;
+// IA32_MTRR_DEF_TYPEのMTRR有効化ビット
+const MTRR_DEF_TYPE_ENABLE: u64 = 1 << 11;
+// IA32_MTRR_PHYSMASKnの有効ビット
+const MTRR_PHYSMASK_VALID: u64 = 1 << 11;
+
+const CR0_NOT_WRITE_THROUGH: u64 = 1 << 29;
+const CR0_CACHE_DISABLE: u64 = 1 << 30;
+
+// MTRRの領域は4KiB単位
+const MTRR_PAGE_MASK: u64 = 0xfff;
+// MtrrSettingsに保存できる可変長MTRRの数
+const MAX_VARIABLE_MTRRS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+}
+
+// 可変長MTRRは2の冪のサイズで、サイズの倍数に揃ったアドレスの領域しか表せない
+// rangeを含むページを、そのような領域に先頭から分割して(base, size)で返す
+pub fn aligned_power_of_two_regions(range: Range<u64>) -> Result<Vec<(u64, u64)>> {
+    if range.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+    let mut base = range.start & !MTRR_PAGE_MASK;
+    let end = range
+        .end
+        .checked_add(MTRR_PAGE_MASK)
+        .ok_or(Error::InvalidArgument)?
+        & !MTRR_PAGE_MASK;
+    let mut regions = Vec::new();
+    while base < end {
+        // baseの揃っている境界と、残りの長さの両方に収まる最大の2の冪
+        let align = if base == 0 {
+            1 << 63
+        } else {
+            1 << base.trailing_zeros()
+        };
+        let remaining = 1 << (63 - (end - base).leading_zeros());
+        let size: u64 = align.min(remaining);
+        regions.push((base, size));
+        base += size;
+    }
+    Ok(regions)
+}
+
+pub fn phys_base(base: u64, memory_type: MemoryType) -> u64 {
+    (base & !MTRR_PAGE_MASK) | memory_type as u64
+}
+
+// 物理アドレスのビット数の範囲で、sizeより上位のビットを立てたマスク
+pub fn phys_mask(size: u64, physical_address_bits: u32) -> u64 {
+    let address_mask = (1u64 << physical_address_bits) - 1;
+    (!(size - 1) & address_mask & !MTRR_PAGE_MASK) | MTRR_PHYSMASK_VALID
+}
+
+// IA32_MTRR_PHYSBASEnとIA32_MTRR_PHYSMASKnが表す領域
+// 無効なMTRRならNoneを返す
+pub fn variable_mtrr_region(
+    base: u64,
+    mask: u64,
+    physical_address_bits: u32,
+) -> Option<Range<u64>> {
+    if mask & MTRR_PHYSMASK_VALID == 0 {
+        return None;
+    }
+    let address_mask = (1u64 << physical_address_bits) - 1;
+    let base = base & address_mask & !MTRR_PAGE_MASK;
+    let size = (!(mask & !MTRR_PAGE_MASK) & address_mask) + 1;
+    Some(base..base + size)
+}
+
+// rangeをライトコンバインにする可変長MTRRの領域を(base, size)で返す
+// 1つの2の冪の領域に丸めるとフレームバッファ以外のメモリまでライトコンバインになってしまうので、
+// rangeを含むページを複数の領域に分けて覆う
+// max_regions個に収まらない場合は、大きい領域から順にmax_regions個だけ使う
+// existingの領域と重なる場合はエラーにする
+pub fn write_combining_regions(
+    range: Range<u64>,
+    existing: impl IntoIterator<Item = Range<u64>>,
+    max_regions: usize,
+) -> Result<Vec<(u64, u64)>> {
+    let mut regions = aligned_power_of_two_regions(range)?;
+    let start = regions[0].0;
+    let (last_base, last_size) = regions[regions.len() - 1];
+    let end = last_base + last_size;
+    if existing.into_iter().any(|r| r.start < end && start < r.end) {
+        return Err(Error::Failed("MTRR: region overlaps an existing MTRR"));
+    }
+    if max_regions == 0 {
+        return Err(Error::Failed("MTRR: no free variable MTRR"));
+    }
+    regions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    regions.truncate(max_regions);
+    regions.sort();
+    Ok(regions)
+}
+
+fn num_variable_mtrrs() -> u32 {
+    (read_msr(MSR_IA32_MTRRCAP) & MTRRCAP_VCNT_MASK) as u32
+}
+
+// 使われていない可変長MTRRの番号
+fn free_variable_mtrrs() -> Vec<u32> {
+    (0..num_variable_mtrrs())
+        .filter(|i| read_msr(MSR_IA32_MTRR_PHYSMASK0 + i * 2) & MTRR_PHYSMASK_VALID == 0)
+        .collect()
+}
+
+// 有効な可変長MTRRの領域
+fn valid_variable_mtrr_regions() -> impl Iterator<Item = Range<u64>> {
+    let bits = physical_address_bits();
+    (0..num_variable_mtrrs()).filter_map(move |i| {
+        variable_mtrr_region(
+            read_msr(MSR_IA32_MTRR_PHYSBASE0 + i * 2),
+            read_msr(MSR_IA32_MTRR_PHYSMASK0 + i * 2),
+            bits,
+        )
+    })
+}
+
+// Intel SDM 11.11.7.2 の手順で、キャッシュとMTRRを無効化してからfでMTRRを書き換え、
+// IA32_MTRR_DEF_TYPEをdef_typeにして有効に戻す
+fn update_mtrrs(def_type: u64, f: impl FnOnce()) {
+    let were_interrupts_enabled = interrupts_enabled();
+    disable_interrupts();
+    let cr0 = read_cr0();
+    unsafe {
+        write_cr0((cr0 | CR0_CACHE_DISABLE) & !CR0_NOT_WRITE_THROUGH);
+    }
+    wbinvd();
+    flush_tlb();
+    unsafe {
+        write_msr(
+            MSR_IA32_MTRR_DEF_TYPE,
+            read_msr(MSR_IA32_MTRR_DEF_TYPE) & !MTRR_DEF_TYPE_ENABLE,
+        );
+    }
+    f();
+    wbinvd();
+    flush_tlb();
+    unsafe {
+        write_msr(MSR_IA32_MTRR_DEF_TYPE, def_type);
+        write_cr0(cr0);
+    }
+    if were_interrupts_enabled {
+        enable_interrupts();
+    }
+}
+
+// (番号, IA32_MTRR_PHYSBASEn, IA32_MTRR_PHYSMASKn)の可変長MTRRをまとめて書き換える
+fn update_variable_mtrrs(mtrrs: &[(u32, u64, u64)]) {
+    update_mtrrs(read_msr(MSR_IA32_MTRR_DEF_TYPE), || {
+        for (index, base, mask) in mtrrs {
+            unsafe {
+                write_msr(MSR_IA32_MTRR_PHYSBASE0 + index * 2, *base);
+                write_msr(MSR_IA32_MTRR_PHYSMASK0 + index * 2, *mask);
+            }
+        }
+    });
+}
+
+// 可変長MTRRとIA32_MTRR_DEF_TYPEの設定
+// 全てのCPUで同じ設定にする必要があるので、BSPで読み出したものをAPに書き込む
+// 固定長MTRRはファームウェアが全てのCPUで設定しているので扱わない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtrrSettings {
+    def_type: u64,
+    num_variable_mtrrs: usize,
+    // (IA32_MTRR_PHYSBASEn, IA32_MTRR_PHYSMASKn)
+    variable_mtrrs: [(u64, u64); MAX_VARIABLE_MTRRS],
+}
+impl MtrrSettings {
+    pub fn read() -> Self {
+        let mut settings = Self {
+            def_type: read_msr(MSR_IA32_MTRR_DEF_TYPE),
+            num_variable_mtrrs: (num_variable_mtrrs() as usize).min(MAX_VARIABLE_MTRRS),
+            variable_mtrrs: [(0, 0); MAX_VARIABLE_MTRRS],
+        };
+        for (i, e) in settings.variable_mtrrs[..settings.num_variable_mtrrs]
+            .iter_mut()
+            .enumerate()
+        {
+            let i = i as u32;
+            *e = (
+                read_msr(MSR_IA32_MTRR_PHYSBASE0 + i * 2),
+                read_msr(MSR_IA32_MTRR_PHYSMASK0 + i * 2),
+            );
+        }
+        settings
+    }
+    // 現在のCPUのMTRRをこの設定にする
+    pub fn apply(&self) {
+        update_mtrrs(self.def_type, || {
+            for (i, (base, mask)) in self.variable_mtrrs[..self.num_variable_mtrrs]
+                .iter()
+                .enumerate()
+            {
+                let i = i as u32;
+                unsafe {
+                    write_msr(MSR_IA32_MTRR_PHYSBASE0 + i * 2, *base);
+                    write_msr(MSR_IA32_MTRR_PHYSMASK0 + i * 2, *mask);
+                }
+            }
+        });
+    }
+}
+
+// rangeを、空いている可変長MTRRでライトコンバインにする
+// 空きが足りない場合はrangeの一部だけをライトコンバインにする
+// 既存のMTRRと重なる場合は何もせずにエラーを返す
+// 使ったMTRRの番号を返す
+pub fn set_write_combining(range: Range<u64>) -> Result<Vec<u32>> {
+    if read_msr(MSR_IA32_MTRRCAP) & MTRRCAP_WC == 0 {
+        return Err(Error::Failed("MTRR: write-combining is not supported"));
+    }
+    let free = free_variable_mtrrs();
+    let regions = write_combining_regions(range, valid_variable_mtrr_regions(), free.len())?;
+    let bits = physical_address_bits();
+    let mtrrs: Vec<(u32, u64, u64)> = free
+        .iter()
+        .zip(regions.iter())
+        .map(|(index, (base, size))| {
+            (
+                *index,
+                phys_base(*base, MemoryType::WriteCombining),
+                phys_mask(*size, bits),
+            )
+        })
+        .collect();
+    update_variable_mtrrs(&mtrrs);
+    Ok(mtrrs.iter().map(|(index, _, _)| *index).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn aligned_power_of_two_regions_cover_the_pages() {
+        assert_eq!(
+            aligned_power_of_two_regions(0x8000_0000..0x8100_0000),
+            Ok(vec![(0x8000_0000, 0x100_0000)])
+        );
+        // 800x600x4バイトのフレームバッファ
+        assert_eq!(
+            aligned_power_of_two_regions(0x8000_0000..0x8000_0000 + 0x1d_4c00),
+            Ok(vec![
+                (0x8000_0000, 0x10_0000),
+                (0x8010_0000, 0x8_0000),
+                (0x8018_0000, 0x4_0000),
+                (0x801c_0000, 0x1_0000),
+                (0x801d_0000, 0x4000),
+                (0x801d_4000, 0x1000),
+            ])
+        );
+        // 先頭が大きな境界に揃っていない場合は、小さい領域から始まる
+        assert_eq!(
+            aligned_power_of_two_regions(0x8000_1000..0x8000_4000),
+            Ok(vec![(0x8000_1000, 0x1000), (0x8000_2000, 0x2000)])
+        );
+        assert_eq!(
+            aligned_power_of_two_regions(0x1000..0x1010),
+            Ok(vec![(0x1000, 0x1000)])
+        );
+        assert!(aligned_power_of_two_regions(0x1000..0x1000).is_err());
+    }
+
+    #[test_case]
+    fn phys_base_and_mask() {
+        assert_eq!(
+            phys_base(0x8000_0000, MemoryType::WriteCombining),
+            0x8000_0001
+        );
+        // 39bitの物理アドレスで16MiBの領域
+        let mask = phys_mask(0x100_0000, 39);
+        assert_eq!(mask, 0x7F_FF00_0800);
+        assert_ne!(mask & MTRR_PHYSMASK_VALID, 0);
+        // 領域内のアドレスは、マスクを取るとbaseと一致する
+        let base = 0x8000_0000u64;
+        let mask = mask & !MTRR_PHYSMASK_VALID;
+        assert_eq!((base + 0xff_ffff) & mask, base & mask);
+        assert_ne!((base + 0x100_0000) & mask, base & mask);
+    }
+
+    #[test_case]
+    fn variable_mtrr_region_from_msrs() {
+        let base = phys_base(0x8000_0000, MemoryType::WriteCombining);
+        let mask = phys_mask(0x100_0000, 39);
+        assert_eq!(
+            variable_mtrr_region(base, mask, 39),
+            Some(0x8000_0000..0x8100_0000)
+        );
+        assert_eq!(
+            variable_mtrr_region(base, mask & !MTRR_PHYSMASK_VALID, 39),
+            None
+        );
+    }
+
+    #[test_case]
+    fn write_combining_regions_stay_inside_range() {
+        assert_eq!(
+            write_combining_regions(0x8000_0000..0x8100_0000, [], 8),
+            Ok(vec![(0x8000_0000, 0x100_0000)])
+        );
+        // 末尾がページの途中でも、そのページまでは含めてよい
+        assert_eq!(
+            write_combining_regions(0x8000_0000..0x8000_0ff0, [], 8),
+            Ok(vec![(0x8000_0000, 0x1000)])
+        );
+        // 800x600x4バイトのフレームバッファは、後ろのメモリを含まないよう分割して覆う
+        let regions = write_combining_regions(0x8000_0000..0x8000_0000 + 0x1d_4c00, [], 8).unwrap();
+        assert_eq!(regions.len(), 6);
+        assert_eq!(regions.iter().map(|(_, size)| size).sum::<u64>(), 0x1d_5000);
+        // MTRRが足りなければ、大きい領域から使う
+        assert_eq!(
+            write_combining_regions(0x8000_0000..0x8000_0000 + 0x1d_4c00, [], 2),
+            Ok(vec![(0x8000_0000, 0x10_0000), (0x8010_0000, 0x8_0000)])
+        );
+        assert!(write_combining_regions(0x8000_0000..0x8100_0000, [], 0).is_err());
+        // 既存のMTRRと重なる
+        assert!(write_combining_regions(
+            0x8000_0000..0x8100_0000,
+            core::iter::once(0x80f0_0000..0x8100_0000),
+            8
+        )
+        .is_err());
+        // 隣接しているだけなら重ならない
+        assert!(write_combining_regions(
+            0x8000_0000..0x8100_0000,
+            core::iter::once(0x8100_0000..0x8200_0000),
+            8
+        )
+        .is_ok());
+    }
+
+    #[test_case]
+    fn mtrr_settings_round_trip() {
+        let settings = MtrrSettings::read();
+        settings.apply();
+        assert_eq!(MtrrSettings::read(), settings);
+    }
+}
@@ -11,6 +11,7 @@
 //! is unique so taking a mutable reference
 //! to it will be safe.
 
+use crate::result::Error;
 use crate::result::Result;
 use core::cell::SyncUnsafeCell;
 use core::fmt::Debug;
@@ -88,7 +89,7 @@ impl<T: Sized> Mutex<T> {
         }
     }
     #[track_caller]
-    fn try_lock(&self) -> Result<MutexGuard<T>> {
+    pub fn try_lock(&self) -> Result<MutexGuard<T>> {
         if self
             .is_taken
             .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
@@ -98,7 +99,7 @@ impl<T: Sized> Mutex<T> {
                 .store(Location::caller().line(), Ordering::SeqCst);
             Ok(unsafe { MutexGuard::new(self, &self.data) })
         } else {
-            Err("Lock failed")
+            Err(Error::Failed("Lock failed"))
         }
     }
     #[track_caller]
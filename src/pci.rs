@@ -1,6 +1,8 @@
 use crate::acpi::AcpiMcfgDescriptor;
 use crate::info;
+use crate::result::Error;
 use crate::result::Result;
+use crate::warn;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem::size_of;
@@ -37,18 +39,18 @@ pub struct BusDeviceFunction {
     id: u16,
 }
 const MASK_BUS: usize = 0b1111_1111_0000_0000;
-const SHIFT_BUS: usize = 0;
+const SHIFT_BUS: usize = 8;
 const MASK_DEVICE: usize = 0b0000_0000_1111_1000;
 const SHIFT_DEVICE: usize = 3;
 const MASK_FUNCTION: usize = 0b0000_0000_0000_0111;
 const SHIFT_FUNCTION: usize = 0;
 impl BusDeviceFunction {
     pub fn new(bus: usize, device: usize, function: usize) -> Result<Self> {
-        if !(0..256).contains(&bus) || !(0..32).contains(&bus) || !(0..8).contains(&bus) {
-            Err("PCI bus device function out of range")
+        if !(0..256).contains(&bus) || !(0..32).contains(&device) || !(0..8).contains(&function) {
+            Err(Error::Failed("PCI bus device function out of range"))
         } else {
             Ok(Self {
-                id: ((bus << SHIFT_BUS) | (device << SHIFT_DEVICE) | (function << SHIFT_DEVICE))
+                id: ((bus << SHIFT_BUS) | (device << SHIFT_DEVICE) | (function << SHIFT_FUNCTION))
                     as u16,
             })
         }
@@ -62,6 +64,10 @@ impl BusDeviceFunction {
     pub fn function(&self) -> usize {
         ((self.id as usize) & MASK_FUNCTION) >> SHIFT_FUNCTION
     }
+    // ECAM上でのコンフィグ空間のオフセット（1ファンクションあたり4KiB）
+    pub fn ecam_offset(&self) -> usize {
+        (self.id as usize) << 12
+    }
     pub fn iter() -> BusDeviceFunctionIterator {
         BusDeviceFunctionIterator { next_id: 0 }
     }
@@ -109,13 +115,168 @@ struct ConfigRegisters<T> {
 impl<T> ConfigRegisters<T> {
     fn read(ecm_base: *mut T, byte_offset: usize) -> Result<T> {
         if !(0..256).contains(&byte_offset) || byte_offset % size_of::<T>() != 0 {
-            Err("PCI COnfigRegisters read out of range")
+            Err(Error::Failed("PCI COnfigRegisters read out of range"))
         } else {
             unsafe { Ok(read_volatile(ecm_base.add(byte_offset / size_of::<T>()))) }
         }
     }
 }
 
+const CONFIG_VENDOR_ID: usize = 0x00;
+const CONFIG_CLASS_CODE: usize = 0x08;
+const CONFIG_HEADER_TYPE: usize = 0x0c;
+const CONFIG_BAR0: usize = 0x10;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+const NUM_OF_BARS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, prefetchable: bool },
+    Io { port: u32 },
+}
+
+// コンフィグ空間のヘッダから読み出したPCIデバイスの情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    bdf: BusDeviceFunction,
+    vendor_device: VendorDeviceId,
+    class: u8,
+    subclass: u8,
+    prog_if: u8,
+    header_type: u8,
+    config_base: usize,
+}
+impl PciDevice {
+    // vendor IDが0xFFFFのファンクションは存在しない
+    fn probe(ecam_base: usize, bdf: BusDeviceFunction) -> Option<Self> {
+        let config_base = ecam_base + bdf.ecam_offset();
+        let id = ConfigRegisters::<u32>::read(config_base as *mut u32, CONFIG_VENDOR_ID).ok()?;
+        let vendor = id as u16;
+        if vendor == 0xFFFF {
+            return None;
+        }
+        let class_code =
+            ConfigRegisters::<u32>::read(config_base as *mut u32, CONFIG_CLASS_CODE).ok()?;
+        let header =
+            ConfigRegisters::<u32>::read(config_base as *mut u32, CONFIG_HEADER_TYPE).ok()?;
+        Some(Self {
+            bdf,
+            vendor_device: VendorDeviceId {
+                vendor,
+                device: (id >> 16) as u16,
+            },
+            class: (class_code >> 24) as u8,
+            subclass: (class_code >> 16) as u8,
+            prog_if: (class_code >> 8) as u8,
+            header_type: (header >> 16) as u8,
+            config_base,
+        })
+    }
+    pub fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+    pub fn vendor_device(&self) -> VendorDeviceId {
+        self.vendor_device
+    }
+    pub fn class(&self) -> u8 {
+        self.class
+    }
+    pub fn subclass(&self) -> u8 {
+        self.subclass
+    }
+    pub fn prog_if(&self) -> u8 {
+        self.prog_if
+    }
+    pub fn header_type(&self) -> u8 {
+        self.header_type & !HEADER_TYPE_MULTI_FUNCTION
+    }
+    pub fn is_multi_function(&self) -> bool {
+        self.header_type & HEADER_TYPE_MULTI_FUNCTION != 0
+    }
+    pub fn read_config_u32(&self, byte_offset: usize) -> Result<u32> {
+        ConfigRegisters::read(self.config_base as *mut u32, byte_offset)
+    }
+    pub fn read_bar_raw(&self, index: usize) -> Result<u32> {
+        if index >= NUM_OF_BARS || self.header_type() != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        self.read_config_u32(CONFIG_BAR0 + index * 4)
+    }
+    // 64bitのメモリBARは次のBARが上位32bitになる
+    pub fn bar(&self, index: usize) -> Result<Bar> {
+        let bar = self.read_bar_raw(index)?;
+        if bar & 1 != 0 {
+            return Ok(Bar::Io { port: bar & !0b11 });
+        }
+        let prefetchable = bar & 0b1000 != 0;
+        let low = (bar & !0b1111) as u64;
+        let address = if is_64bit_memory_bar(bar) {
+            ((self.read_bar_raw(index + 1)? as u64) << 32) | low
+        } else {
+            low
+        };
+        Ok(Bar::Memory {
+            address,
+            prefetchable,
+        })
+    }
+    // BARを番号と一緒に順に返す
+    // 64bitのメモリBARの上位32bitは、独立したBARとしては返さない
+    pub fn bars(&self) -> impl Iterator<Item = (usize, Result<Bar>)> + '_ {
+        let mut index = 0;
+        core::iter::from_fn(move || {
+            if index >= NUM_OF_BARS {
+                return None;
+            }
+            let i = index;
+            index += match self.read_bar_raw(i) {
+                Ok(bar) if is_64bit_memory_bar(bar) => 2,
+                _ => 1,
+            };
+            Some((i, self.bar(i)))
+        })
+    }
+}
+
+fn is_64bit_memory_bar(bar: u32) -> bool {
+    bar & 1 == 0 && (bar >> 1) & 0b11 == 0b10
+}
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} class: {:#04X}, subclass: {:#04X}",
+            self.bdf, self.vendor_device, self.class, self.subclass
+        )
+    }
+}
+
+// ECAMの各バス・デバイス・ファンクションのヘッダを読み、存在するデバイスを列挙する
+pub fn scan(ecam_base: usize) -> impl Iterator<Item = PciDevice> {
+    scan_buses(ecam_base, 0..256)
+}
+
+pub fn scan_buses(ecam_base: usize, buses: Range<usize>) -> impl Iterator<Item = PciDevice> {
+    buses.flat_map(move |bus| {
+        (0..32).flat_map(move |device| {
+            let f0 = BusDeviceFunction::new(bus, device, 0)
+                .ok()
+                .and_then(|bdf| PciDevice::probe(ecam_base, bdf));
+            // ファンクション0がマルチファンクションでなければ1..8は見ない
+            let num_of_functions = match f0 {
+                Some(d) if d.is_multi_function() => 8,
+                _ => 1,
+            };
+            f0.into_iter()
+                .chain((1..num_of_functions).filter_map(move |function| {
+                    BusDeviceFunction::new(bus, device, function)
+                        .ok()
+                        .and_then(|bdf| PciDevice::probe(ecam_base, bdf))
+                }))
+        })
+    })
+}
+
 pub struct Pci {
     ecm_range: Range<usize>,
 }
@@ -144,10 +305,136 @@ impl Pci {
         }
     }
     pub fn probe_devices(&self) {
-        for bdf in BusDeviceFunction::iter() {
-            if let Some(vd) = self.read_vendor_id_and_device_id(bdf) {
-                info!("{vd}");
+        let num_of_buses = (self.ecm_range.end - self.ecm_range.start) >> 20;
+        for device in scan_buses(self.ecm_range.start, 0..num_of_buses) {
+            info!("{device}");
+            if device.header_type() != 0 {
+                continue;
+            }
+            for (i, bar) in device.bars() {
+                match bar {
+                    Ok(Bar::Memory { address: 0, .. }) | Ok(Bar::Io { port: 0 }) => (),
+                    Ok(bar) => info!("  BAR{i}: {bar:?}"),
+                    Err(e) => warn!("  BAR{i}: {e:?}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    // 1バス分(32デバイス * 8ファンクション * 4KiB)のECAMを模したバッファ
+    struct MockEcam {
+        buf: Vec<u32>,
+    }
+    impl MockEcam {
+        fn new() -> Self {
+            Self {
+                buf: vec![0xFFFF_FFFF; 32 * 8 * 4096 / 4],
             }
         }
+        fn base(&self) -> usize {
+            self.buf.as_ptr() as usize
+        }
+        fn set(&mut self, device: usize, function: usize, byte_offset: usize, value: u32) {
+            let bdf = BusDeviceFunction::new(0, device, function).unwrap();
+            self.buf[(bdf.ecam_offset() + byte_offset) / 4] = value;
+        }
+        fn add_function(&mut self, device: usize, function: usize, id: u32, header_type: u8) {
+            self.set(device, function, CONFIG_VENDOR_ID, id);
+            // AHCI: class 0x01, subclass 0x06, prog_if 0x01
+            self.set(device, function, CONFIG_CLASS_CODE, 0x0106_0102);
+            self.set(
+                device,
+                function,
+                CONFIG_HEADER_TYPE,
+                (header_type as u32) << 16,
+            );
+        }
+    }
+
+    #[test_case]
+    fn bdf_fields() {
+        let bdf = BusDeviceFunction::new(0x12, 0x1f, 0x7).unwrap();
+        assert_eq!(bdf.bus(), 0x12);
+        assert_eq!(bdf.device(), 0x1f);
+        assert_eq!(bdf.function(), 0x7);
+        assert_eq!(bdf.ecam_offset(), (0x12 << 20) | (0x1f << 15) | (0x7 << 12));
+        assert!(BusDeviceFunction::new(0, 32, 0).is_err());
+        assert!(BusDeviceFunction::new(0, 0, 8).is_err());
+    }
+
+    #[test_case]
+    fn scan_finds_single_device() {
+        let mut ecam = MockEcam::new();
+        ecam.add_function(3, 0, 0x2922_8086, 0x00);
+        ecam.set(3, 0, CONFIG_BAR0 + 5 * 4, 0xfebf_1000);
+        let devices: Vec<PciDevice> = scan_buses(ecam.base(), 0..1).collect();
+        assert_eq!(devices.len(), 1);
+        let d = devices[0];
+        assert_eq!(d.bdf(), BusDeviceFunction::new(0, 3, 0).unwrap());
+        assert_eq!(
+            d.vendor_device(),
+            VendorDeviceId {
+                vendor: 0x8086,
+                device: 0x2922
+            }
+        );
+        assert_eq!(d.class(), 0x01);
+        assert_eq!(d.subclass(), 0x06);
+        assert_eq!(d.prog_if(), 0x01);
+        assert_eq!(
+            d.bar(5),
+            Ok(Bar::Memory {
+                address: 0xfebf_1000,
+                prefetchable: false
+            })
+        );
+        assert!(d.bar(6).is_err());
+    }
+
+    #[test_case]
+    fn upper_half_of_64bit_bar_is_skipped() {
+        let mut ecam = MockEcam::new();
+        ecam.add_function(3, 0, 0x1111_1234, 0x00);
+        // BAR0/1: 64bitのプリフェッチ可能なメモリBAR, BAR2: I/O BAR
+        ecam.set(3, 0, CONFIG_BAR0, 0xc000_000c);
+        ecam.set(3, 0, CONFIG_BAR0 + 4, 0x1);
+        ecam.set(3, 0, CONFIG_BAR0 + 2 * 4, 0xc001);
+        let d = scan_buses(ecam.base(), 0..1).next().unwrap();
+        let bars: Vec<(usize, Result<Bar>)> = d.bars().take(2).collect();
+        assert_eq!(
+            bars,
+            [
+                (
+                    0,
+                    Ok(Bar::Memory {
+                        address: 0x1_c000_0000,
+                        prefetchable: true
+                    })
+                ),
+                (2, Ok(Bar::Io { port: 0xc000 })),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn scan_respects_multi_function_bit() {
+        let mut ecam = MockEcam::new();
+        // マルチファンクションでないデバイスのファンクション1は無視される
+        ecam.add_function(1, 0, 0x0001_1234, 0x00);
+        ecam.add_function(1, 1, 0x0002_1234, 0x00);
+        ecam.add_function(2, 0, 0x0003_1234, HEADER_TYPE_MULTI_FUNCTION);
+        ecam.add_function(2, 5, 0x0004_1234, 0x00);
+        let ids: Vec<u16> = scan_buses(ecam.base(), 0..1)
+            .map(|d| d.vendor_device().device)
+            .collect();
+        assert_eq!(ids, [1, 3, 4]);
     }
 }
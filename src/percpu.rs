@@ -0,0 +1,102 @@
+extern crate alloc;
+
+use crate::x86::read_msr;
+use crate::x86::write_msr;
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::ptr::null;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
+
+pub const MSR_IA32_GS_BASE: u32 = 0xc000_0101;
+pub const MSR_IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+// CPUごとのデータ
+// GSのベースアドレスをこの構造体にしておき、ロックを取らずにgs:相対で読む
+#[repr(C)]
+pub struct PerCpu {
+    // gs:[0]から自分自身のアドレスを読めるよう、先頭に置く
+    self_ptr: *const PerCpu,
+    pub cpu_id: u32,
+    pub apic_id: u8,
+    // そのCPUだけが使う一時的なポインタ
+    pub scratch: AtomicPtr<u8>,
+}
+const _: () = assert!(core::mem::offset_of!(PerCpu, self_ptr) == 0);
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    // CPUが終了することはないので、確保したデータは解放しない
+    pub fn alloc(cpu_id: u32, apic_id: u8) -> &'static PerCpu {
+        let percpu = Box::leak(Box::new(PerCpu {
+            self_ptr: null(),
+            cpu_id,
+            apic_id,
+            scratch: AtomicPtr::new(null_mut()),
+        }));
+        percpu.self_ptr = percpu;
+        percpu
+    }
+}
+
+// 現在のCPUのGSのベースアドレスをpercpuにする
+// swapgsでユーザーのGSと入れ替えても戻せるよう、KERNEL_GS_BASEにも同じ値を入れておく
+pub fn install_percpu(percpu: &'static PerCpu) {
+    let addr = percpu as *const PerCpu as u64;
+    unsafe {
+        write_msr(MSR_IA32_GS_BASE, addr);
+        write_msr(MSR_IA32_KERNEL_GS_BASE, addr);
+    }
+}
+
+pub fn init_percpu(cpu_id: u32, apic_id: u8) -> &'static PerCpu {
+    let percpu = PerCpu::alloc(cpu_id, apic_id);
+    install_percpu(percpu);
+    percpu
+}
+
+// install_percpuの前に呼ぶとgs:[0]が0番地を指すので#PFになる
+pub fn this_cpu() -> &'static PerCpu {
+    let percpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]",
+                out(reg) percpu,
+                options(nostack, readonly, preserves_flags))
+    }
+    unsafe { &*percpu }
+}
+
+// install_percpuされていなければNone
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if read_msr(MSR_IA32_GS_BASE) == 0 {
+        None
+    } else {
+        Some(this_cpu())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::Ordering;
+
+    #[test_case]
+    fn this_cpu_reads_through_gs_base() {
+        let saved_gs_base = read_msr(MSR_IA32_GS_BASE);
+        let saved_kernel_gs_base = read_msr(MSR_IA32_KERNEL_GS_BASE);
+        let percpu = PerCpu::alloc(7, 3);
+        install_percpu(percpu);
+        assert_eq!(read_msr(MSR_IA32_GS_BASE), percpu as *const PerCpu as u64);
+        assert_eq!(this_cpu().cpu_id, 7);
+        assert_eq!(this_cpu().apic_id, 3);
+        assert!(core::ptr::eq(this_cpu(), percpu));
+        let mut value = 42u8;
+        this_cpu().scratch.store(&mut value, Ordering::SeqCst);
+        assert_eq!(unsafe { *percpu.scratch.load(Ordering::SeqCst) }, 42);
+        assert!(try_this_cpu().is_some_and(|p| p.cpu_id == 7));
+        unsafe {
+            write_msr(MSR_IA32_GS_BASE, saved_gs_base);
+            write_msr(MSR_IA32_KERNEL_GS_BASE, saved_kernel_gs_base);
+        }
+    }
+}
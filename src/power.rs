@@ -0,0 +1,54 @@
+use crate::acpi::AcpiRsdpStruct;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+use crate::x86::hlt;
+use crate::x86::read_io_port_u16;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u8;
+
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 1 << 1;
+const KBC_CMD_PULSE_RESET: u8 = 0xfe;
+
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0x7 << PM1_CNT_SLP_TYP_SHIFT;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+fn halt_forever() -> ! {
+    loop {
+        hlt()
+    }
+}
+
+// キーボードコントローラ(8042)のリセット線をパルスしてCPUをリセットする
+pub fn reboot() -> ! {
+    while read_io_port_u8(KBC_STATUS_PORT) & KBC_STATUS_INPUT_FULL != 0 {
+        busy_loop_hint();
+    }
+    write_io_port_u8(KBC_STATUS_PORT, KBC_CMD_PULSE_RESET);
+    halt_forever()
+}
+
+fn enter_s5(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let fadt = acpi.fadt().ok_or("FADT is not found")?;
+    let port = fadt.pm1a_control_block()?;
+    let slp_typ = fadt.s5_sleep_type()?;
+    // ファームウェアが残したSLP_TYPと混ざらないように、消してから書く
+    let value = read_io_port_u16(port) & !PM1_CNT_SLP_TYP_MASK;
+    write_io_port_u16(
+        port,
+        value | ((slp_typ << PM1_CNT_SLP_TYP_SHIFT) & PM1_CNT_SLP_TYP_MASK) | PM1_CNT_SLP_EN,
+    );
+    Ok(())
+}
+
+// FADTのPM1a制御ブロックにSLP_TYPa/SLP_ENを書いてS5(ソフトオフ)に入る
+// 使えない場合は停止する
+pub fn shutdown(acpi: &AcpiRsdpStruct) -> ! {
+    if let Err(e) = enter_s5(acpi) {
+        warn!("ACPI shutdown failed: {e:?}");
+    }
+    halt_forever()
+}
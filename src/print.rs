@@ -1,27 +1,145 @@
-use crate::graphics::BitmapTextWriter;
+extern crate alloc;
+
+use crate::console;
 use crate::mutex::Mutex;
 use crate::serial::SerialPort;
-use crate::uefi::VramBufferInfo;
+#[cfg(test)]
+use alloc::collections::BTreeMap;
+#[cfg(test)]
+use alloc::string::String;
 use core::fmt;
 use core::mem::size_of;
+use core::panic::Location;
 use core::slice;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Info = 0,
+    Warn = 1,
+    Error = 2,
+}
+
+// これより低いレベルのログは出力しない
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::SeqCst) {
+        0 => LogLevel::Info,
+        1 => LogLevel::Warn,
+        _ => LogLevel::Error,
+    }
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level >= log_level()
+}
+
+impl LogLevel {
+    // 画面に表示するときの文字の色
+    pub fn color(&self) -> u32 {
+        match self {
+            LogLevel::Info => 0xffffff,
+            LogLevel::Warn => 0xffff00,
+            LogLevel::Error => 0xff0000,
+        }
+    }
+}
+
+// global_printの出力先のシリアルポート。Noneの場合はCOM1に出力する
+static SERIAL_OUTPUT: Mutex<Option<SerialPort>> = Mutex::new(None);
+
+pub fn set_serial_output(port: SerialPort) {
+    *SERIAL_OUTPUT.lock() = Some(port);
+}
 
-static GLOBAL_VRAM_WRITTER: Mutex<Option<BitmapTextWriter<VramBufferInfo>>> = Mutex::new(None);
+pub fn serial_output() -> SerialPort {
+    SERIAL_OUTPUT.lock().unwrap_or_default()
+}
+
+// テスト中にglobal_printの出力を横取りするためのバッファ
+#[cfg(test)]
+static GLOBAL_PRINT_CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+
+#[cfg(test)]
+pub fn start_capture() {
+    *GLOBAL_PRINT_CAPTURE.lock() = Some(String::new());
+}
+
+#[cfg(test)]
+pub fn take_capture() -> String {
+    GLOBAL_PRINT_CAPTURE.lock().take().unwrap_or_default()
+}
+
+// シリアルポートごとに、そのポートに送られた出力を横取りする
+#[cfg(test)]
+static SERIAL_CAPTURE: Mutex<Option<BTreeMap<u16, String>>> = Mutex::new(None);
+
+#[cfg(test)]
+pub fn start_serial_capture() {
+    *SERIAL_CAPTURE.lock() = Some(BTreeMap::new());
+}
 
-pub fn set_global_vram(vram: VramBufferInfo) {
-    assert!(GLOBAL_VRAM_WRITTER.lock().is_none());
-    let w = BitmapTextWriter::new(vram);
-    *GLOBAL_VRAM_WRITTER.lock() = Some(w);
+#[cfg(test)]
+pub fn take_serial_capture() -> BTreeMap<u16, String> {
+    SERIAL_CAPTURE.lock().take().unwrap_or_default()
+}
+
+// 1回のglobal_printの出力が、他の出力と混ざらないようにするためのロック
+static PRINT_LOCK: Mutex<()> = Mutex::new(());
+// ロックを取らずに出力した回数
+static UNLOCKED_PRINTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn num_of_unlocked_prints() -> usize {
+    UNLOCKED_PRINTS.load(Ordering::Relaxed)
 }
 
 pub fn global_print(args: fmt::Arguments) {
-    let mut writer = SerialPort::default();
-    fmt::write(&mut writer, args).unwrap();
-    if let Some(w) = &mut *GLOBAL_VRAM_WRITTER.lock() {
-        fmt::write(w, args).expect("Failede to write to GLOBAL_VRAM_WRITTER");
+    print_with_fg(args, None);
+}
+
+// ログはレベルに応じた色で画面に出す
+pub fn log_print(level: LogLevel, args: fmt::Arguments) {
+    print_with_fg(args, Some(level.color()));
+}
+
+fn print_with_fg(args: fmt::Arguments, fg: Option<u32>) {
+    #[cfg(test)]
+    if let Some(captured) = &mut *GLOBAL_PRINT_CAPTURE.lock() {
+        fmt::write(captured, args).unwrap();
+    }
+    let Ok(_locked) = PRINT_LOCK.try_lock() else {
+        // 出力中のコードに割り込んだ割り込みハンドラからの出力なので、待ってもロックは解放されない
+        // ロックを取らずに、シリアルポートに直接書き込む
+        let port = SERIAL_OUTPUT
+            .try_lock()
+            .map_or(SerialPort::default(), |port| port.unwrap_or_default());
+        serial_print(port, args);
+        UNLOCKED_PRINTS.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    serial_print(serial_output(), args);
+    match fg {
+        Some(fg) => console::write_fmt_with_fg(fg, args),
+        None => console::write_fmt(args),
     }
 }
 
+fn serial_print(mut writer: SerialPort, args: fmt::Arguments) {
+    #[cfg(test)]
+    if let Some(captured) = &mut *SERIAL_CAPTURE.lock() {
+        fmt::write(captured.entry(writer.base()).or_default(), args).unwrap();
+    }
+    fmt::write(&mut writer, args).unwrap();
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::print::global_print(format_args!($($arg)*)));
@@ -35,72 +153,200 @@ macro_rules! println {
 
 #[macro_export]
 macro_rules! info {
-    ($($arg:tt)*) => ($crate::print!("[INFO]    {}:{:<3}  {}\n", file!(), line!(), format_args!($($arg)*)))
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Info) {
+            $crate::print::log_print(
+                $crate::print::LogLevel::Info,
+                format_args!("[INFO]    {}:{:<3}  {}\n", file!(), line!(), format_args!($($arg)*)),
+            )
+        }
+    };
 }
 
 #[macro_export]
 macro_rules! warn {
-    ($($arg:tt)*) => ($crate::print!("[WARN]    {}:{:<3}  {}\n", file!(), line!(), format_args!($($arg)*)))
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Warn) {
+            $crate::print::log_print(
+                $crate::print::LogLevel::Warn,
+                format_args!("[WARN]    {}:{:<3}  {}\n", file!(), line!(), format_args!($($arg)*)),
+            )
+        }
+    };
 }
 
 #[macro_export]
 macro_rules! error {
-    ($($arg:tt)*) => ($crate::print!("[ERROR]    {}:{:<3}  {}\n", file!(), line!(), format_args!($($arg)*)))
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Error) {
+            $crate::print::log_print(
+                $crate::print::LogLevel::Error,
+                format_args!("[ERROR]    {}:{:<3}  {}\n", file!(), line!(), format_args!($($arg)*)),
+            )
+        }
+    };
 }
 
-fn hexdump_bytes(bytes: &[u8]) {
-    let mut i = 0;
-    let mut ascii = [0u8; 16];
-    let mut offset = 0;
-
-    for v in bytes.iter() {
-        if i == 0 {
-            print!("{offset:08X}: ");
-        }
-        print!("{:02X} ", v);
-        ascii[i] = *v;
-        i += 1;
-        if i == 16 {
-            print!("|");
-            for c in ascii.iter() {
-                print!(
-                    "{}",
-                    match c {
-                        0x20..=0x7e => {
-                            *c as char
-                        }
-                        _ => {
-                            '.'
-                        }
-                    }
-                );
+// 1行16バイトで、左にアドレス、右に表示可能なASCII文字を出力する
+pub fn write_hexdump<W: fmt::Write>(w: &mut W, bytes: &[u8], base_addr: usize) -> fmt::Result {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        write!(w, "{:016X}: ", base_addr + i * 16)?;
+        for k in 0..16 {
+            if k == 8 {
+                write!(w, " ")?;
+            }
+            match row.get(k) {
+                Some(v) => write!(w, "{v:02X} ")?,
+                None => write!(w, "   ")?,
             }
-            println!("|");
-            offset += 16;
-            i = 0;
-        }
-    }
-    if i != 0 {
-        let old_i = i;
-        while i < 16 {
-            print!("    ");
-            i += 1;
         }
-        print!("|");
-        for c in ascii[0..old_i].iter() {
-            print!(
-                "{}",
-                if (0x20u8..=0x7fu8).contains(c) {
-                    *c as char
-                } else {
-                    '.'
-                }
-            );
+        write!(w, "|")?;
+        for c in row {
+            let c = if (0x20..=0x7e).contains(c) {
+                *c as char
+            } else {
+                '.'
+            };
+            write!(w, "{c}")?;
         }
-        println!("|");
+        writeln!(w, "|")?;
     }
+    Ok(())
+}
+
+struct GlobalPrintWriter;
+impl fmt::Write for GlobalPrintWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        global_print(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+pub fn hexdump_bytes(bytes: &[u8]) {
+    write_hexdump(&mut GlobalPrintWriter, bytes, bytes.as_ptr() as usize).unwrap()
+}
+
+// panic_handlerから呼ばれるので、ヒープを使わずにwに直接書き込む
+pub fn write_panic_info<W: fmt::Write>(
+    w: &mut W,
+    message: Option<&fmt::Arguments>,
+    location: Option<&Location>,
+) -> fmt::Result {
+    write!(w, "PANIC")?;
+    if let Some(location) = location {
+        write!(
+            w,
+            " at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+    if let Some(message) = message {
+        write!(w, ": {message}")?;
+    }
+    writeln!(w)
 }
 
 pub fn hexdump<T: Sized>(data: &T) {
     hexdump_bytes(unsafe { slice::from_raw_parts(data as *const T as *const u8, size_of::<T>()) })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error;
+    use crate::info;
+    use crate::println;
+    use crate::serial::COM1_BASE;
+    use crate::serial::COM2_BASE;
+
+    #[test_case]
+    fn log_level_filters_lower_levels() {
+        set_log_level(LogLevel::Error);
+        start_capture();
+        info!("this info should be filtered");
+        error!("this error should be printed");
+        let captured = take_capture();
+        set_log_level(LogLevel::Info);
+        assert!(!captured.contains("this info should be filtered"));
+        assert!(captured.contains("[ERROR]"));
+        assert!(captured.contains("this error should be printed"));
+    }
+
+    #[test_case]
+    fn println_goes_to_selected_serial_port() {
+        start_serial_capture();
+        println!("to com1");
+        set_serial_output(SerialPort::new_for_com2());
+        println!("to com2");
+        set_serial_output(SerialPort::new_for_com1());
+        let captured = take_serial_capture();
+        assert_eq!(
+            captured.get(&COM1_BASE).map(|s| s.as_str()),
+            Some("to com1\n")
+        );
+        assert_eq!(
+            captured.get(&COM2_BASE).map(|s| s.as_str()),
+            Some("to com2\n")
+        );
+        assert_eq!(serial_output(), SerialPort::new_for_com1());
+    }
+
+    #[test_case]
+    fn print_does_not_wait_for_held_lock() {
+        // ログの出力中に割り込まれた状態を再現する
+        let locked = PRINT_LOCK.lock();
+        let unlocked_prints = num_of_unlocked_prints();
+        start_serial_capture();
+        error!("printed while the lock is held");
+        let captured = take_serial_capture();
+        drop(locked);
+        assert_eq!(num_of_unlocked_prints(), unlocked_prints + 1);
+        assert!(captured
+            .get(&COM1_BASE)
+            .is_some_and(|s| s.contains("printed while the lock is held")));
+        assert!(PRINT_LOCK.try_lock().is_ok());
+    }
+
+    #[test_case]
+    fn hexdump_layout() {
+        let bytes = *b"RSD PTR \x01\x02ABCD\x00\x7fHello, wasabi!\r\n";
+        let addr = bytes.as_ptr() as usize;
+        start_capture();
+        hexdump_bytes(&bytes);
+        let captured = take_capture();
+        let expected = alloc::format!(
+            "{:016X}: 52 53 44 20 50 54 52 20  01 02 41 42 43 44 00 7F |RSD PTR ..ABCD..|\n\
+             {:016X}: 48 65 6C 6C 6F 2C 20 77  61 73 61 62 69 21 0D 0A |Hello, wasabi!..|\n",
+            addr,
+            addr + 16
+        );
+        assert_eq!(captured, expected);
+    }
+
+    #[test_case]
+    fn hexdump_pads_partial_row() {
+        let mut s = String::new();
+        write_hexdump(&mut s, b"ABC", 0x1000).unwrap();
+        assert_eq!(
+            s,
+            "0000000000001000: 41 42 43                                         |ABC|\n"
+        );
+    }
+
+    #[test_case]
+    fn panic_info_contains_message_and_location() {
+        let (location, line) = (Location::caller(), line!());
+        let mut s = String::new();
+        write_panic_info(
+            &mut s,
+            Some(&format_args!("known message {}", 42)),
+            Some(location),
+        )
+        .unwrap();
+        assert!(s.starts_with("PANIC at "));
+        assert!(s.contains(&alloc::format!("{}:{}:", file!(), line)));
+        assert!(s.ends_with(": known message 42\n"));
+    }
+}
@@ -0,0 +1,195 @@
+use crate::mutex::Mutex;
+use crate::x86::read_io_port_u8;
+
+const PS2_DATA_PORT: u16 = 0x60;
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_STATUS_OUTPUT_FULL: u8 = 0x01;
+
+const SCANCODE_EXTENDED: u8 = 0xe0;
+const SCANCODE_RELEASED: u8 = 0x80;
+const SCANCODE_LEFT_SHIFT: u8 = 0x2a;
+const SCANCODE_RIGHT_SHIFT: u8 = 0x36;
+const SCANCODE_CAPS_LOCK: u8 = 0x3a;
+
+// Scancode Set 1 (0x00..0x3A) から文字への変換テーブル
+// 0は対応する文字がないキー
+const KEYMAP_NORMAL: &[u8; 0x3a] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFTED: &[u8; 0x3a] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+    pub shift: bool,
+}
+impl KeyEvent {
+    pub fn ch(&self) -> Option<char> {
+        if let Key::Char(c) = self.key {
+            Some(c)
+        } else {
+            None
+        }
+    }
+}
+
+// スキャンコードを順に受け取り、Shift/CapsLockの状態を保持しながらKeyEventに変換する
+#[derive(Debug, Default)]
+pub struct ScancodeDecoder {
+    left_shift: bool,
+    right_shift: bool,
+    caps_lock: bool,
+    extended: bool,
+}
+impl ScancodeDecoder {
+    pub const fn new() -> Self {
+        Self {
+            left_shift: false,
+            right_shift: false,
+            caps_lock: false,
+            extended: false,
+        }
+    }
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+    pub fn feed(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if scancode == SCANCODE_EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        let pressed = scancode & SCANCODE_RELEASED == 0;
+        let code = scancode & !SCANCODE_RELEASED;
+        if self.extended {
+            self.extended = false;
+            let key = match code {
+                0x48 => Key::ArrowUp,
+                0x50 => Key::ArrowDown,
+                0x4b => Key::ArrowLeft,
+                0x4d => Key::ArrowRight,
+                _ => return None,
+            };
+            return Some(KeyEvent {
+                key,
+                pressed,
+                shift: self.shift(),
+            });
+        }
+        match code {
+            SCANCODE_LEFT_SHIFT => {
+                self.left_shift = pressed;
+                None
+            }
+            SCANCODE_RIGHT_SHIFT => {
+                self.right_shift = pressed;
+                None
+            }
+            SCANCODE_CAPS_LOCK => {
+                if pressed {
+                    self.caps_lock = !self.caps_lock;
+                }
+                None
+            }
+            _ => {
+                let c = *KEYMAP_NORMAL.get(code as usize)?;
+                if c == 0 {
+                    return None;
+                }
+                // CapsLockはアルファベットにのみ影響する
+                let shifted = if c.is_ascii_alphabetic() {
+                    self.shift() != self.caps_lock
+                } else {
+                    self.shift()
+                };
+                let c = if shifted {
+                    KEYMAP_SHIFTED[code as usize]
+                } else {
+                    c
+                };
+                Some(KeyEvent {
+                    key: Key::Char(c as char),
+                    pressed,
+                    shift: self.shift(),
+                })
+            }
+        }
+    }
+}
+
+static DECODER: Mutex<ScancodeDecoder> = Mutex::new(ScancodeDecoder::new());
+
+// コントローラの出力バッファにデータがあれば読み出して変換する（ポーリング）
+pub fn poll_key() -> Option<KeyEvent> {
+    if read_io_port_u8(PS2_STATUS_PORT) & PS2_STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+    let scancode = read_io_port_u8(PS2_DATA_PORT);
+    DECODER.lock().feed(scancode)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    fn decode(scancodes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = ScancodeDecoder::new();
+        scancodes.iter().filter_map(|s| decoder.feed(*s)).collect()
+    }
+
+    #[test_case]
+    fn translate_scancodes() {
+        // "h", "i", Shift + "1", Shiftを離して "1"
+        let chars: Vec<char> = decode(&[0x23, 0xa3, 0x17, 0x97, 0x2a, 0x02, 0x82, 0xaa, 0x02])
+            .iter()
+            .filter(|e| e.pressed)
+            .filter_map(|e| e.ch())
+            .collect();
+        assert_eq!(chars, ['h', 'i', '!', '1']);
+    }
+
+    #[test_case]
+    fn caps_lock_affects_only_letters() {
+        let chars: Vec<char> = decode(&[0x3a, 0xba, 0x10, 0x02, 0x2a, 0x10])
+            .iter()
+            .filter_map(|e| e.ch())
+            .collect();
+        assert_eq!(chars, ['Q', '1', 'q']);
+    }
+
+    #[test_case]
+    fn extended_arrow_keys() {
+        let events = decode(&[0xe0, 0x48, 0xe0, 0xc8, 0xe0, 0x4b]);
+        assert_eq!(
+            events,
+            [
+                KeyEvent {
+                    key: Key::ArrowUp,
+                    pressed: true,
+                    shift: false
+                },
+                KeyEvent {
+                    key: Key::ArrowUp,
+                    pressed: false,
+                    shift: false
+                },
+                KeyEvent {
+                    key: Key::ArrowLeft,
+                    pressed: true,
+                    shift: false
+                },
+            ]
+        );
+    }
+}
@@ -8,9 +8,36 @@ pub enum QemuExitCode {
     Fail = 0x2,
 }
 
-pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
-    write_io_port_u8(0xf4, exit_code as u8);
+// isa-debug-exitのI/Oポート(scripts/launch_qemu.shのiobase)
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+// exit_qemu_with(code)でQEMUが終了するときの終了ステータス
+// iosize=0x01なので下位8bitだけが書き込まれ、QEMUは(value << 1) | 1で終了する
+pub const fn qemu_exit_status(code: u32) -> u32 {
+    ((code & 0xff) << 1) | 1
+}
+
+pub fn exit_qemu_with(code: u32) -> ! {
+    write_io_port_u8(ISA_DEBUG_EXIT_PORT, code as u8);
     loop {
         hlt()
     }
 }
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    exit_qemu_with(exit_code as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn exit_code_maps_to_qemu_exit_status() {
+        // scripts/launch_qemu.shは3をPASSとして扱う
+        assert_eq!(qemu_exit_status(QemuExitCode::Success as u32), 3);
+        assert_eq!(qemu_exit_status(QemuExitCode::Fail as u32), 5);
+        assert_eq!(qemu_exit_status(7), 15);
+        assert_eq!(qemu_exit_status(0x100 | 7), 15);
+    }
+}
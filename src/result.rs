@@ -1 +1,54 @@
-pub type Result<T> = core::result::Result<T, &'static str>;
+use crate::graphics::GraphicsError;
+use crate::uefi::EfiStatus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Failed(&'static str),
+    OutOfMemory,
+    InvalidArgument,
+    // with_timeoutなどで、期限までに処理が終わらなかった
+    Timeout,
+    Acpi(&'static str),
+    Uefi(EfiStatus),
+    Graphics(GraphicsError),
+}
+// 文字列のエラーからの移行用
+impl From<&'static str> for Error {
+    fn from(message: &'static str) -> Self {
+        Error::Failed(message)
+    }
+}
+impl From<GraphicsError> for Error {
+    fn from(e: GraphicsError) -> Self {
+        Error::Graphics(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn uefi_error_carries_status() {
+        assert_eq!(EfiStatus::Success.into_result(), Ok(()));
+        assert_eq!(
+            EfiStatus::NotFound.into_result(),
+            Err(Error::Uefi(EfiStatus::NotFound))
+        );
+    }
+
+    #[test_case]
+    fn string_error_converts() {
+        fn fails() -> core::result::Result<(), &'static str> {
+            Err("old style error")
+        }
+        fn migrated() -> Result<()> {
+            fails()?;
+            Ok(())
+        }
+        assert_eq!(migrated(), Err(Error::Failed("old style error")));
+        assert_eq!(Error::from("x"), Error::Failed("x"));
+    }
+}
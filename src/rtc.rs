@@ -0,0 +1,199 @@
+use crate::x86::busy_loop_hint;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use core::fmt;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+// FADTのcenturyフィールドで別の場所が示されることもあるが、多くの場合は0x32
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// CMOSから読み出したままの値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+    pub century: u8,
+    pub status_b: u8,
+}
+impl RtcRegisters {
+    fn read() -> Self {
+        Self {
+            second: read_cmos(REG_SECOND),
+            minute: read_cmos(REG_MINUTE),
+            hour: read_cmos(REG_HOUR),
+            day: read_cmos(REG_DAY),
+            month: read_cmos(REG_MONTH),
+            year: read_cmos(REG_YEAR),
+            century: read_cmos(REG_CENTURY),
+            status_b: read_cmos(REG_STATUS_B),
+        }
+    }
+    // Status Register BにしたがってBCD/12時間表記を解釈する
+    pub fn decode(&self) -> DateTime {
+        let is_binary = self.status_b & STATUS_B_BINARY != 0;
+        let to_binary = |v: u8| {
+            if is_binary {
+                v
+            } else {
+                (v >> 4) * 10 + (v & 0x0f)
+            }
+        };
+        let mut hour = to_binary(self.hour & !HOUR_PM);
+        if self.status_b & STATUS_B_24_HOUR == 0 {
+            // 12時間表記: 12AM = 0時, 12PM = 12時
+            hour %= 12;
+            if self.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = to_binary(self.century);
+        let century = if (19..=99).contains(&century) {
+            century as u16
+        } else {
+            // centuryレジスタがない場合は2000年以降とみなす
+            20
+        };
+        DateTime {
+            year: century * 100 + to_binary(self.year) as u16,
+            month: to_binary(self.month),
+            day: to_binary(self.day),
+            hour,
+            minute: to_binary(self.minute),
+            second: to_binary(self.second),
+        }
+    }
+}
+
+fn read_cmos(reg: u8) -> u8 {
+    write_io_port_u8(CMOS_ADDRESS_PORT, reg);
+    read_io_port_u8(CMOS_DATA_PORT)
+}
+
+fn wait_for_update_completion() {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        busy_loop_hint();
+    }
+}
+
+// 更新中に読むと値がずれるので、同じ値が2回続けて読めるまで繰り返す
+pub fn read_rtc() -> DateTime {
+    loop {
+        wait_for_update_completion();
+        let first = RtcRegisters::read();
+        wait_for_update_completion();
+        let second = RtcRegisters::read();
+        if first == second {
+            break first.decode();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn decode_bcd_registers() {
+        let regs = RtcRegisters {
+            second: 0x59,
+            minute: 0x34,
+            hour: 0x23,
+            day: 0x31,
+            month: 0x12,
+            year: 0x24,
+            century: 0x20,
+            status_b: STATUS_B_24_HOUR,
+        };
+        assert_eq!(
+            regs.decode(),
+            DateTime {
+                year: 2024,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 34,
+                second: 59
+            }
+        );
+    }
+
+    #[test_case]
+    fn decode_without_century_register() {
+        let regs = RtcRegisters {
+            second: 0x00,
+            minute: 0x00,
+            hour: 0x12 | HOUR_PM,
+            day: 0x01,
+            month: 0x01,
+            year: 0x99,
+            century: 0x00,
+            status_b: 0,
+        };
+        let dt = regs.decode();
+        assert_eq!(dt.year, 2099);
+        assert_eq!(dt.hour, 12);
+    }
+
+    #[test_case]
+    fn decode_binary_12_hour_registers() {
+        let regs = RtcRegisters {
+            second: 5,
+            minute: 6,
+            hour: 7 | HOUR_PM,
+            day: 8,
+            month: 9,
+            year: 1,
+            century: 19,
+            status_b: STATUS_B_BINARY,
+        };
+        assert_eq!(
+            regs.decode(),
+            DateTime {
+                year: 1901,
+                month: 9,
+                day: 8,
+                hour: 19,
+                minute: 6,
+                second: 5
+            }
+        );
+    }
+}
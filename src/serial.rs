@@ -1,9 +1,33 @@
+extern crate alloc;
+
+use crate::executor::wait_for_token;
+use crate::executor::wake_from_interrupt;
+use crate::executor::LazyWakerToken;
+use crate::result::Error;
 use crate::result::Result;
 use crate::x86::busy_loop_hint;
 use crate::x86::read_io_port_u8;
 use crate::x86::write_io_port_u8;
+use alloc::string::String;
 use core::fmt;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+pub const COM1_BASE: u16 = 0x3f8;
+pub const COM2_BASE: u16 = 0x2f8;
+
+const UART_BASE_CLOCK: u32 = 115200;
+const LCR_DLAB: u8 = 0x80;
+const LCR_8N1: u8 = 0x03;
+// Interrupt Enable RegisterのReceived Data Availableビット
+const IER_RECEIVED_DATA_AVAILABLE: u8 = 0x01;
+
+// COM1の割り込み(ISA IRQ4)のベクタ番号
+pub const COM1_IRQ: u32 = 4;
+pub const SERIAL_INTERRUPT_VECTOR: u8 = 36;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SerialPort {
     base: u16,
 }
@@ -11,31 +35,63 @@ impl SerialPort {
     pub fn new(base: u16) -> Self {
         Self { base }
     }
+    pub fn base(&self) -> u16 {
+        self.base
+    }
     pub fn new_for_com1() -> Self {
-        Self::new(0x3f8)
+        Self::new(COM1_BASE)
+    }
+    pub fn new_for_com2() -> Self {
+        Self::new(COM2_BASE)
     }
-    pub fn init(&mut self) {
+    // 115200を基準クロックとしてボーレートを設定し、8N1でFIFOを有効化する
+    pub fn init(&self, baud: u32) -> Result<()> {
+        if baud == 0 || baud > UART_BASE_CLOCK {
+            return Err(Error::InvalidArgument);
+        }
+        let divisor = (UART_BASE_CLOCK / baud).min(u16::MAX as u32) as u16;
+        // Disable all interrupts
         write_io_port_u8(self.base + 1, 0x00);
-        write_io_port_u8(self.base + 3, 0x80);
-
-        const BAUD_DIVISOR: u16 = 0x0001;
-
-        write_io_port_u8(self.base, (BAUD_DIVISOR & 0xff) as u8);
-        write_io_port_u8(self.base + 1, (BAUD_DIVISOR >> 8) as u8);
-
-        write_io_port_u8(self.base + 3, 0x03);
+        // Set DLAB to access the divisor latch
+        write_io_port_u8(self.base + 3, LCR_DLAB);
+        write_io_port_u8(self.base, (divisor & 0xff) as u8);
+        write_io_port_u8(self.base + 1, (divisor >> 8) as u8);
+        // 8 bits, no parity, one stop bit (clears DLAB)
+        write_io_port_u8(self.base + 3, LCR_8N1);
+        // Enable FIFO, clear them, with 14-byte threshold
         write_io_port_u8(self.base + 2, 0xC7);
+        // DTR, RTS and OUT2
         write_io_port_u8(self.base + 4, 0x0B);
+        Ok(())
+    }
+    // データを受信したときに割り込みを発生させる
+    // MCRのOUT2はinit()で立てているので、IERを設定するだけでよい
+    pub fn enable_receive_interrupt(&self) {
+        write_io_port_u8(self.base + 1, IER_RECEIVED_DATA_AVAILABLE);
+    }
+    pub fn divisor_latch(&self) -> u16 {
+        let lcr = read_io_port_u8(self.base + 3);
+        write_io_port_u8(self.base + 3, lcr | LCR_DLAB);
+        let low = read_io_port_u8(self.base) as u16;
+        let high = read_io_port_u8(self.base + 1) as u16;
+        write_io_port_u8(self.base + 3, lcr);
+        (high << 8) | low
+    }
+    fn enable_loopback(&self) {
+        write_io_port_u8(self.base + 4, 0x1e);
+    }
+    fn disable_loopback(&self) {
+        write_io_port_u8(self.base + 4, 0x0f);
     }
     pub fn loopback_test(&self) -> Result<()> {
         // Set in loopback mode
-        write_io_port_u8(self.base + 4, 0x1e);
+        self.enable_loopback();
         self.send_char('T');
         if self.try_read().ok_or("loopback_test failed: No response")? != b'T' {
-            return Err("loopback_test failed: wrong data received");
+            return Err(Error::Failed("loopback_test failed: wrong data received"));
         }
         // Return to the normal mode
-        write_io_port_u8(self.base + 4, 0x0f);
+        self.disable_loopback();
         Ok(())
     }
     pub fn send_char(&self, c: char) {
@@ -52,20 +108,115 @@ impl SerialPort {
         }
     }
     pub fn try_read(&self) -> Option<u8> {
+        let c = self.try_read_byte()?;
+        // Enable FIFO, clear them, with 14-byte threshold
+        write_io_port_u8(self.base + 2, 0xC7);
+        Some(c)
+    }
+    // Line Status RegisterのData Readyビットが立っていれば1バイト読む
+    pub fn try_read_byte(&self) -> Option<u8> {
         if read_io_port_u8(self.base + 5) & 0x01 == 0 {
             None
         } else {
-            let c = read_io_port_u8(self.base);
-            // Enable FIFO, clear them, with 14-byte threshold
-            write_io_port_u8(self.base + 2, 0xC7);
-            Some(c)
+            Some(read_io_port_u8(self.base))
+        }
+    }
+    pub fn read_byte_blocking(&self) -> u8 {
+        loop {
+            if let Some(c) = self.try_read_byte() {
+                return c;
+            }
+            busy_loop_hint();
+        }
+    }
+    // 改行が来るまで読み込む（改行文字は含まない）
+    pub fn read_line(&self) -> String {
+        let mut line = String::new();
+        loop {
+            match self.read_byte_blocking() {
+                b'\n' | b'\r' => break line,
+                c => line.push(c as char),
+            }
         }
     }
 }
+// 割り込みハンドラ(書き込み側)とExecutor(読み出し側)の間で受信したバイトを受け渡すリングバッファ
+// 書き込み側も読み出し側も1つずつなので、headとtailをそれぞれの側だけが進める
+pub struct ByteRing<const N: usize> {
+    bytes: [AtomicU8; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+impl<const N: usize> ByteRing<N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU8 = AtomicU8::new(0);
+    pub const fn new() -> Self {
+        Self {
+            bytes: [Self::ZERO; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+    // 一杯の場合はfalseを返す（バイトは捨てられる）
+    pub fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= N {
+            return false;
+        }
+        self.bytes[tail % N].store(byte, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.bytes[head % N].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
+impl<const N: usize> Default for ByteRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static SERIAL_RX_RING: ByteRing<256> = ByteRing::new();
+static SERIAL_RX_TOKEN: LazyWakerToken = LazyWakerToken::new();
+
+// 割り込みハンドラから呼ばれる。受信したバイトを積んで、待っているタスクを起こす
+fn receive_byte_from_interrupt(byte: u8) {
+    SERIAL_RX_RING.push(byte);
+    if let Some(token) = SERIAL_RX_TOKEN.get() {
+        wake_from_interrupt(token);
+    }
+}
+
+// COM1の受信割り込みのハンドラ。FIFOに溜まっている分を全て読み出す
+pub fn on_serial_interrupt() {
+    let sp = SerialPort::default();
+    while let Some(byte) = sp.try_read_byte() {
+        receive_byte_from_interrupt(byte);
+    }
+}
+
+// COM1から1バイト受信するまで待つ
+// 受信割り込みが有効になっている必要がある
+pub async fn read_serial_byte() -> Result<u8> {
+    let token = SERIAL_RX_TOKEN.get_or_allocate()?;
+    loop {
+        if let Some(byte) = SERIAL_RX_RING.pop() {
+            return Ok(byte);
+        }
+        wait_for_token(token).await;
+    }
+}
+
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let serial = Self::default();
-        serial.send_str(s);
+        self.send_str(s);
         Ok(())
     }
 }
@@ -74,3 +225,79 @@ impl Default for SerialPort {
         Self::new_for_com1()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn byte_ring_is_fifo_and_bounded() {
+        let ring = ByteRing::<2>::new();
+        assert_eq!(ring.pop(), None);
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(!ring.push(3));
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(4));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), Some(4));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test_case]
+    fn read_serial_byte_wakes_on_interrupt() {
+        extern crate alloc;
+        use crate::executor::Executor;
+        use crate::executor::Task;
+        use alloc::rc::Rc;
+        use core::cell::Cell;
+
+        while SERIAL_RX_RING.pop().is_some() {}
+        let mut executor = Executor::new();
+        let received = Rc::new(Cell::new(None));
+        {
+            let received = received.clone();
+            executor.enqueue(Task::new(async move {
+                received.set(Some(read_serial_byte().await?));
+                Ok(())
+            }));
+        }
+        // 受信するまではタスクは待ち状態のまま
+        assert!(executor.run_once());
+        assert!(!executor.run_once());
+        assert_eq!(received.get(), None);
+        // 受信割り込みが来たことを模擬する
+        receive_byte_from_interrupt(b'w');
+        assert!(executor.run_once());
+        assert_eq!(received.get(), Some(b'w'));
+    }
+
+    #[test_case]
+    fn init_programs_divisor_latch() {
+        let sp = SerialPort::default();
+        sp.init(9600).expect("init(9600) failed");
+        assert_eq!(sp.divisor_latch(), 0x000C);
+        sp.init(115200).expect("init(115200) failed");
+        assert_eq!(sp.divisor_latch(), 0x0001);
+        assert!(sp.init(0).is_err());
+    }
+
+    #[test_case]
+    fn read_back_byte_in_loopback_mode() {
+        let sp = SerialPort::default();
+        sp.enable_loopback();
+        sp.send_char('W');
+        assert_eq!(sp.read_byte_blocking(), b'W');
+        assert_eq!(sp.try_read_byte(), None);
+        sp.disable_loopback();
+    }
+
+    #[test_case]
+    fn read_line_in_loopback_mode() {
+        let sp = SerialPort::default();
+        sp.enable_loopback();
+        sp.send_str("ok\n");
+        assert_eq!(sp.read_line(), "ok");
+        sp.disable_loopback();
+    }
+}
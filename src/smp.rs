@@ -0,0 +1,331 @@
+extern crate alloc;
+
+use crate::acpi::AcpiMadt;
+use crate::acpi::MadtEntry;
+use crate::allocator::ALLOCATOR;
+use crate::apic::Ipi;
+use crate::apic::LocalApic;
+use crate::info;
+use crate::mtrr::MtrrSettings;
+use crate::mutex::Mutex;
+use crate::percpu::install_percpu;
+use crate::percpu::PerCpu;
+use crate::result::Error;
+use crate::result::Result;
+use crate::tsc::monotonic_now;
+use crate::uefi::MemoryMapHolder;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+use crate::x86::read_cr0;
+use crate::x86::read_cr3;
+use crate::x86::read_cr4;
+use crate::x86::read_idtr;
+use crate::x86::read_msr;
+use crate::x86::write_msr;
+use crate::x86::GdtWrapper;
+use crate::x86::KERNEL_CS;
+use crate::x86::KERNEL_DS;
+use crate::x86::MSR_IA32_EFER;
+use crate::x86::MSR_IA32_PAT;
+use crate::x86::PAGE_SIZE;
+use crate::x86::TSS64_SEL;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+// APが最初に実行するトランポリンを置く物理アドレス
+// SIPIのベクタはこのアドレスのページ番号なので、1MiB未満で4KiB境界に揃っている必要がある
+pub const AP_TRAMPOLINE_ADDR: usize = 0x8000;
+// トランポリンのページ内で、BSPからAPに渡すパラメータを置くオフセット
+const AP_PARAMS_OFFSET: usize = 0xf00;
+const AP_PARAMS_ADDR: usize = AP_TRAMPOLINE_ADDR + AP_PARAMS_OFFSET;
+pub const AP_STACK_SIZE: usize = 64 * 1024;
+// MADTのLocal APICのフラグ: このCPUが使える
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+// lgdt/lidtのオペランド
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+const _: () = assert!(size_of::<DescriptorTablePointer>() == 10);
+
+// トランポリンが読むパラメータ。オフセットはアセンブリ側でoffset_of!から求める
+#[repr(C)]
+struct ApParams {
+    // 32bitモードで読むので、4GiB未満である必要がある
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    // INIT直後のCR0はCD/NWが立っているので、BSPの値で上書きする
+    // CR4のOSFXSRやEFERのNXEなども、BSPと揃えないとRustのコードが動かない
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    // APごとのGDT(とTSS)と、BSPと共有するIDT
+    gdtr: DescriptorTablePointer,
+    idtr: DescriptorTablePointer,
+}
+const _: () = assert!(AP_PARAMS_OFFSET + size_of::<ApParams>() <= PAGE_SIZE);
+
+// リアルモードで起動したAPを、プロテクトモードを経由してロングモードに移行させる
+// 0x8000にコピーして実行されるので、絶対アドレスは全てAP_TRAMPOLINE_ADDRからのオフセットで書く
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    // lgdt [ap_trampoline_gdtr] (16bitの絶対アドレス)
+    ".byte 0x0f, 0x01, 0x16",
+    ".word {addr} + ap_trampoline_gdtr - ap_trampoline_start",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // ljmp 0x18:ap_trampoline_32 (32bitのオフセット)
+    ".byte 0x66, 0xea",
+    ".long {addr} + ap_trampoline_32 - ap_trampoline_start",
+    ".word 0x18",
+    ".code32",
+    "ap_trampoline_32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    // CR4.PAE
+    // 他のビット(PCIDEなど)はロングモードでないと立てられないので、64bitに移ってから設定する
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [{cr3}]",
+    "mov cr3, eax",
+    // BSPのEFERからLMAを落としたもの (LMEとNXEを含む)
+    "mov ecx, 0xc0000080",
+    "mov eax, [{efer}]",
+    "and eax, ~(1 << 10)",
+    "mov edx, [{efer} + 4]",
+    "wrmsr",
+    // BSPのCR0 (PG | PEが立っていて、CD | NWは落ちている)
+    "mov eax, [{cr0}]",
+    "mov cr0, eax",
+    // ljmp 0x08:ap_trampoline_64
+    ".byte 0xea",
+    ".long {addr} + ap_trampoline_64 - ap_trampoline_start",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_64:",
+    "mov rax, [{cr4}]",
+    "mov cr4, rax",
+    // BSPが用意したAP用のGDTに切り替えて、CSを読み直す
+    "lgdt [{gdtr}]",
+    "push {kernel_cs}",
+    "lea rax, [rip + 3f]",
+    "push rax",
+    "retfq",
+    "3:",
+    "mov ax, {kernel_ds}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "mov ax, {tss64_sel}",
+    "ltr ax",
+    // Rustのコードで例外が起きても処理できるよう、callする前にIDTをロードする
+    "lidt [{idtr}]",
+    "mov rsp, [{stack_top}]",
+    "mov rax, [{entry}]",
+    "call rax",
+    "2:",
+    "hlt",
+    "jmp 2b",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    // x86.rsのGDTとセレクタを揃える
+    // 0x08: 64bitコード, 0x10: データ, 0x18: 32bitコード
+    ".quad 0x00af9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    ".quad 0x00cf9a000000ffff",
+    "ap_trampoline_gdtr:",
+    ".word 4 * 8 - 1",
+    ".long {addr} + ap_trampoline_gdt - ap_trampoline_start",
+    "ap_trampoline_end:",
+    addr = const AP_TRAMPOLINE_ADDR,
+    cr3 = const AP_PARAMS_ADDR + offset_of!(ApParams, cr3),
+    stack_top = const AP_PARAMS_ADDR + offset_of!(ApParams, stack_top),
+    entry = const AP_PARAMS_ADDR + offset_of!(ApParams, entry),
+    cr0 = const AP_PARAMS_ADDR + offset_of!(ApParams, cr0),
+    cr4 = const AP_PARAMS_ADDR + offset_of!(ApParams, cr4),
+    efer = const AP_PARAMS_ADDR + offset_of!(ApParams, efer),
+    gdtr = const AP_PARAMS_ADDR + offset_of!(ApParams, gdtr),
+    idtr = const AP_PARAMS_ADDR + offset_of!(ApParams, idtr),
+    kernel_cs = const KERNEL_CS,
+    kernel_ds = const KERNEL_DS,
+    tss64_sel = const TSS64_SEL,
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+// 起動が完了したAPの数
+static AP_READY: AtomicUsize = AtomicUsize::new(0);
+// start_apsに渡されたentry (fn() -> !)
+static AP_ENTRY: AtomicUsize = AtomicUsize::new(0);
+// 次に起動するAPのPerCpu
+// ヒープを使わなくて済むよう、BSPが確保してから渡す
+static AP_PERCPU: AtomicPtr<PerCpu> = AtomicPtr::new(core::ptr::null_mut());
+// APに写すBSPのPATとMTRRの設定
+// メモリの種類の設定は全てのCPUで揃っている必要がある(Intel SDM 11.11.8, 11.12.4)
+static BSP_MEMORY_TYPES: Mutex<Option<(u64, MtrrSettings)>> = Mutex::new(None);
+
+// トランポリンから呼ばれるAPのRustのエントリポイント
+// シャドウスペースを用意せずにcallするので、System V ABIにしておく
+extern "sysv64" fn ap_main() -> ! {
+    install_percpu(unsafe { &*AP_PERCPU.load(Ordering::SeqCst) });
+    if let Some((pat, mtrrs)) = *BSP_MEMORY_TYPES.lock() {
+        unsafe { write_msr(MSR_IA32_PAT, pat) };
+        mtrrs.apply();
+    }
+    AP_READY.fetch_add(1, Ordering::SeqCst);
+    let entry: fn() -> ! = unsafe { core::mem::transmute(AP_ENTRY.load(Ordering::SeqCst)) };
+    entry()
+}
+
+pub fn num_of_ready_aps() -> usize {
+    AP_READY.load(Ordering::SeqCst)
+}
+
+fn spin_wait(duration: Duration) {
+    let deadline = monotonic_now() + duration;
+    while monotonic_now() < deadline {
+        busy_loop_hint();
+    }
+}
+
+// readyがbeforeより増えるまで、最大timeoutだけ待つ
+fn wait_for_ready(before: usize, timeout: Duration) -> bool {
+    let deadline = monotonic_now() + timeout;
+    while num_of_ready_aps() == before {
+        if monotonic_now() >= deadline {
+            return false;
+        }
+        busy_loop_hint();
+    }
+    true
+}
+
+// BSP以外の有効なCPUを、INIT-SIPI-SIPIで1つずつ起動する
+// APはBSPと同じページテーブルを使い、専用のスタックでentryを実行する
+// CR0/CR4/EFERとIDTはBSPと同じものを使い、GDTとTSSはAPごとにBSPが用意する
+// ALLOCATORはスピンロックで守られているので、entryからもヒープを使ってよい
+// APのcpu_idは、起動した順に1から振る
+// 起動できたAPの数を返す
+pub fn start_aps(madt: &AcpiMadt, memory_map: &MemoryMapHolder, entry: fn() -> !) -> Result<usize> {
+    let trampoline = unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let end = &ap_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    if trampoline.len() > AP_PARAMS_OFFSET {
+        return Err(Error::Failed("smp: trampoline is too large"));
+    }
+    let cr3 = read_cr3() as u64;
+    if cr3 >= 0x1_0000_0000 {
+        return Err(Error::Failed("smp: CR3 is not below 4GiB"));
+    }
+    let (cr0, cr4, efer) = (read_cr0(), read_cr4(), read_msr(MSR_IA32_EFER));
+    let (idtr_limit, idtr_base) = read_idtr();
+    // ファームウェアが使っている領域(ACPI NVSなど)を上書きしないよう、空き領域であることを確かめる
+    let trampoline_page = AP_TRAMPOLINE_ADDR as u64..(AP_TRAMPOLINE_ADDR + PAGE_SIZE) as u64;
+    if !memory_map.is_conventional_memory(trampoline_page) {
+        return Err(Error::Failed(
+            "smp: trampoline page is not conventional memory",
+        ));
+    }
+    // ヒープに入っている場合は、トランポリンのページが割り当てられないようにする
+    ALLOCATOR.reserve(AP_TRAMPOLINE_ADDR, PAGE_SIZE)?;
+    unsafe {
+        copy_nonoverlapping(
+            trampoline.as_ptr(),
+            AP_TRAMPOLINE_ADDR as *mut u8,
+            trampoline.len(),
+        )
+    };
+    AP_ENTRY.store(entry as usize, Ordering::SeqCst);
+    // PATのWCやフレームバッファのMTRRは、この時点までにBSPで設定されている
+    *BSP_MEMORY_TYPES.lock() = Some((read_msr(MSR_IA32_PAT), MtrrSettings::read()));
+
+    let apic = LocalApic::current();
+    let bsp_id = apic.id();
+    let mut started = 0;
+    for e in madt.iter() {
+        let MadtEntry::LocalApic { apic_id, flags, .. } = e else {
+            continue;
+        };
+        if apic_id == bsp_id || flags & MADT_LOCAL_APIC_ENABLED == 0 {
+            continue;
+        }
+        // スタックはAPが終了することはないので解放しない
+        let stack = vec![0u8; AP_STACK_SIZE].leak();
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+        // ltrでTSSがbusyになるので、BSPのGDTは共有できない
+        // 同じ理由で、GDTとTSSも解放しない
+        let gdt = Box::leak(Box::<GdtWrapper>::default());
+        let (gdtr_limit, gdtr_base) = gdt.gdtr();
+        unsafe {
+            write_volatile(
+                AP_PARAMS_ADDR as *mut ApParams,
+                ApParams {
+                    cr3,
+                    stack_top,
+                    entry: ap_main as usize as u64,
+                    cr0,
+                    cr4,
+                    efer,
+                    gdtr: DescriptorTablePointer {
+                        limit: gdtr_limit,
+                        base: gdtr_base,
+                    },
+                    idtr: DescriptorTablePointer {
+                        limit: idtr_limit,
+                        base: idtr_base,
+                    },
+                },
+            )
+        };
+        let percpu = PerCpu::alloc(started as u32 + 1, apic_id);
+        AP_PERCPU.store(percpu as *const PerCpu as *mut PerCpu, Ordering::SeqCst);
+        let before = num_of_ready_aps();
+        apic.send_ipi(apic_id, Ipi::Init);
+        spin_wait(Duration::from_millis(10));
+        let vector = (AP_TRAMPOLINE_ADDR / PAGE_SIZE) as u8;
+        apic.send_ipi(apic_id, Ipi::Startup(vector));
+        spin_wait(Duration::from_micros(200));
+        apic.send_ipi(apic_id, Ipi::Startup(vector));
+        // パラメータを書き換える前に、APがスタックを読み終わるのを待つ
+        if wait_for_ready(before, Duration::from_secs(1)) {
+            info!("smp: AP (APIC ID {apic_id}) is started");
+            started += 1;
+        } else {
+            // 遅れて起動したAPが次のAPのスタックを使わないよう、ここで打ち切る
+            warn!("smp: AP (APIC ID {apic_id}) did not respond");
+            break;
+        }
+    }
+    Ok(started)
+}
@@ -1,37 +1,150 @@
-use crate::qemu::exit_qemu;
+use crate::print::write_panic_info;
+use crate::qemu::exit_qemu_with;
 use crate::qemu::QemuExitCode;
 use crate::serial::SerialPort;
 use core::any::type_name;
+use core::cmp::min;
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 pub trait Testable {
+    fn name(&self) -> &'static str;
     fn run(&self, writer: &mut SerialPort);
 }
 impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        type_name::<T>()
+    }
     fn run(&self, writer: &mut SerialPort) {
-        writeln!(writer, "[RUNNING] >>> {}", type_name::<T>()).unwrap();
+        writeln!(writer, "[RUNNING] >>> {}", self.name()).unwrap();
         self();
-        writeln!(writer, "[PASS   ] <<< {}", type_name::<T>()).unwrap();
+        writeln!(writer, "[PASS   ] <<< {}", self.name()).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestSummary {
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+}
+impl TestSummary {
+    // 失敗したテストより後のテストは実行されない
+    pub fn not_run(&self) -> usize {
+        self.total - self.passed - self.failed
+    }
+    // 成功なら1(QemuExitCode::Success)、失敗があれば1+失敗数を返す
+    // 失敗数1のときはQemuExitCode::Failと同じ値になる
+    pub fn exit_code(&self) -> u32 {
+        QemuExitCode::Success as u32 + min(self.failed, 0xfe) as u32
+    }
+}
+
+fn write_summary<W: Write>(w: &mut W, summary: &TestSummary) -> core::fmt::Result {
+    writeln!(
+        w,
+        "Test result: {} passed, {} failed, {} not run (total {})",
+        summary.passed,
+        summary.failed,
+        summary.not_run(),
+        summary.total
+    )
+}
+
+// 実行中のテストの数と成功した数。panic_handlerから集計を出力するために使う
+static TESTS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+static TESTS_PASSED: AtomicUsize = AtomicUsize::new(0);
+
+// テストを順に実行し、成功するたびにpassedを増やす
+// panicからは復帰できないので、テストが失敗した場合はこの関数から戻らない
+pub fn run_tests(
+    tests: &[&dyn Testable],
+    writer: &mut SerialPort,
+    passed: &AtomicUsize,
+) -> TestSummary {
+    for test in tests {
+        test.run(writer);
+        passed.fetch_add(1, Ordering::SeqCst);
+    }
+    TestSummary {
+        total: tests.len(),
+        passed: passed.load(Ordering::SeqCst),
+        failed: 0,
     }
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) -> ! {
     let mut sw = SerialPort::new_for_com1();
     writeln!(sw, "Running {} tests...", tests.len()).unwrap();
-    for test in tests {
-        test.run(&mut sw);
-    }
+    TESTS_TOTAL.store(tests.len(), Ordering::SeqCst);
+    let summary = run_tests(tests, &mut sw, &TESTS_PASSED);
     writeln!(sw, "Completed {} tests!", tests.len()).unwrap();
-    exit_qemu(QemuExitCode::Success)
+    let _ = write_summary(&mut sw, &summary);
+    exit_qemu_with(summary.exit_code())
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     let mut sw = SerialPort::new_for_com1();
-    writeln!(sw, "PANIC during test: {info:?}").unwrap();
-    exit_qemu(QemuExitCode::Fail)
+    let _ = write!(sw, "During test: ");
+    let _ = write_panic_info(&mut sw, info.message(), info.location());
+    let summary = TestSummary {
+        total: TESTS_TOTAL.load(Ordering::SeqCst),
+        passed: TESTS_PASSED.load(Ordering::SeqCst),
+        failed: 1,
+    };
+    let _ = write_summary(&mut sw, &summary);
+    exit_qemu_with(summary.exit_code())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use crate::qemu::qemu_exit_status;
+
+    fn trivial_pass() {
+        assert_eq!(2u32.pow(3), 8);
+    }
+
+    fn another_trivial_pass() {
+        assert!("wasabi".starts_with('w'));
+    }
+
+    #[test_case]
+    fn runner_counts_passed_tests() {
+        let tests: [&dyn Testable; 2] = [&trivial_pass, &another_trivial_pass];
+        assert!(tests[0].name().ends_with("trivial_pass"));
+        let passed = AtomicUsize::new(0);
+        let summary = run_tests(&tests, &mut SerialPort::new_for_com1(), &passed);
+        assert_eq!(
+            summary,
+            TestSummary {
+                total: 2,
+                passed: 2,
+                failed: 0
+            }
+        );
+        assert_eq!(summary.not_run(), 0);
+        assert_eq!(qemu_exit_status(summary.exit_code()), 3);
+    }
+
+    #[test_case]
+    fn failure_count_is_encoded_in_exit_code() {
+        let summary = TestSummary {
+            total: 5,
+            passed: 2,
+            failed: 1,
+        };
+        assert_eq!(summary.not_run(), 2);
+        assert_eq!(summary.exit_code(), QemuExitCode::Fail as u32);
+        let mut s = alloc::string::String::new();
+        write_summary(&mut s, &summary).unwrap();
+        assert_eq!(s, "Test result: 2 passed, 1 failed, 2 not run (total 5)\n");
+    }
 }
@@ -0,0 +1,122 @@
+use crate::hpet::global_timestamp;
+use crate::hpet::is_global_hpet_initialized;
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::has_invariant_tsc;
+use crate::x86::rdtsc;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+const CALIBRATION_INTERVAL: Duration = Duration::from_millis(10);
+
+// 1秒あたりのTSCのカウント数。0の場合は未較正
+static TSC_FREQ: AtomicU64 = AtomicU64::new(0);
+// 較正したときのTSCの値と、その時点のHPETの時刻(ns)
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+// u64のナノ秒では約584年で溢れるので、秒と1秒未満に分けて変換する
+fn ticks_to_duration(delta: u64, freq: u64) -> Duration {
+    if freq == 0 {
+        return Duration::ZERO;
+    }
+    let nanos = (delta % freq) as u128 * 1_000_000_000 / freq as u128;
+    Duration::new(delta / freq, nanos as u32)
+}
+
+// HPETで一定時間待つ間にTSCがどれだけ進むかを測り、TSCの周波数を求める
+// 成功した場合は周波数(Hz)を返す
+pub fn calibrate_tsc() -> Result<u64> {
+    if !has_invariant_tsc() {
+        return Err(Error::Failed("TSC: invariant TSC is not supported"));
+    }
+    if !is_global_hpet_initialized() {
+        return Err(Error::Failed("TSC: HPET is not initialized"));
+    }
+    let hpet_start = global_timestamp();
+    let tsc_start = rdtsc();
+    let mut hpet_end = global_timestamp();
+    while hpet_end - hpet_start < CALIBRATION_INTERVAL {
+        busy_loop_hint();
+        hpet_end = global_timestamp();
+    }
+    let tsc_end = rdtsc();
+    let elapsed_ns = (hpet_end - hpet_start).as_nanos();
+    let freq = ((tsc_end - tsc_start) as u128 * 1_000_000_000 / elapsed_ns) as u64;
+    if freq == 0 {
+        return Err(Error::Failed("TSC: TSC did not advance"));
+    }
+    TSC_BASE.store(tsc_end, Ordering::Relaxed);
+    TSC_BASE_NS.store(hpet_end.as_nanos() as u64, Ordering::Relaxed);
+    // 周波数を最後に書き込むことで、monotonic_now()は較正済みの値だけを使う
+    TSC_FREQ.store(freq, Ordering::Release);
+    Ok(freq)
+}
+
+pub fn tsc_freq() -> Option<u64> {
+    match TSC_FREQ.load(Ordering::Acquire) {
+        0 => None,
+        freq => Some(freq),
+    }
+}
+
+// TSCの差分を時間に変換する。未較正の場合はZERO
+pub fn tsc_to_duration(delta: u64) -> Duration {
+    tsc_freq().map_or(Duration::ZERO, |freq| ticks_to_duration(delta, freq))
+}
+
+// 起動からの時刻。TSCが較正済みならTSCを、そうでなければHPETを使う
+pub fn monotonic_now() -> Duration {
+    let Some(freq) = tsc_freq() else {
+        return global_timestamp();
+    };
+    let base = TSC_BASE.load(Ordering::Relaxed);
+    let base_ns = TSC_BASE_NS.load(Ordering::Relaxed);
+    Duration::from_nanos(base_ns)
+        .saturating_add(ticks_to_duration(rdtsc().wrapping_sub(base), freq))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn convert_ticks_to_duration() {
+        assert_eq!(ticks_to_duration(0, 1_000_000_000), Duration::ZERO);
+        assert_eq!(
+            ticks_to_duration(3_000_000, 3_000_000_000),
+            Duration::from_millis(1)
+        );
+        assert_eq!(
+            ticks_to_duration(u64::MAX, 1_000_000_000),
+            Duration::from_nanos(u64::MAX)
+        );
+        // ナノ秒がu64に収まらなくても切り捨てない
+        assert_eq!(
+            ticks_to_duration(u64::MAX, 1),
+            Duration::from_secs(u64::MAX)
+        );
+        assert_eq!(ticks_to_duration(5, 2), Duration::from_millis(2500));
+        assert_eq!(ticks_to_duration(1, 0), Duration::ZERO);
+    }
+
+    #[test_case]
+    fn monotonic_now_advances_with_hpet() {
+        if !is_global_hpet_initialized() {
+            return;
+        }
+        // Invariant TSCがない環境ではHPETにフォールバックする
+        let _ = calibrate_tsc();
+        let hpet_start = global_timestamp();
+        let start = monotonic_now();
+        while global_timestamp() - hpet_start < Duration::from_millis(20) {
+            busy_loop_hint();
+        }
+        let elapsed = monotonic_now() - start;
+        // エミュレータ上では誤差が大きいので、かなり緩い範囲で確認する
+        assert!(elapsed >= Duration::from_millis(10), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(200), "{elapsed:?}");
+    }
+}
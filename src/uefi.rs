@@ -1,9 +1,18 @@
+extern crate alloc;
+
 use crate::acpi::AcpiRsdpStruct;
 use crate::graphics::Bitmap;
+use crate::graphics::GraphicsError;
+use crate::graphics::PixelFormat;
+use crate::println;
+use crate::result::Error;
 use crate::result::Result;
 
+use alloc::vec::Vec;
+use core::fmt;
 use core::mem::offset_of;
 use core::mem::size_of;
+use core::ops::Range;
 use core::ptr::null_mut;
 
 type EfiVoid = u8;
@@ -12,7 +21,7 @@ pub type EfiHandle = u64;
 // EFI プロトコルのGUID
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-struct EfiGuid {
+pub struct EfiGuid {
     pub data0: u32,
     pub data1: u16,
     pub data2: u16,
@@ -42,16 +51,51 @@ const EFI_ACPI_TABLE_GUID: EfiGuid = EfiGuid {
     data3: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
 };
 
+// エラーを示すステータスは最上位ビットが立っている
+const EFI_ERROR_BIT: u64 = 1 << 63;
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[must_use]
 #[repr(u64)]
 pub enum EfiStatus {
     Success = 0,
+    LoadError = EFI_ERROR_BIT | 1,
+    InvalidParameter = EFI_ERROR_BIT | 2,
+    Unsupported = EFI_ERROR_BIT | 3,
+    BadBufferSize = EFI_ERROR_BIT | 4,
+    BufferTooSmall = EFI_ERROR_BIT | 5,
+    NotReady = EFI_ERROR_BIT | 6,
+    DeviceError = EFI_ERROR_BIT | 7,
+    WriteProtected = EFI_ERROR_BIT | 8,
+    OutOfResources = EFI_ERROR_BIT | 9,
+    VolumeCorrupted = EFI_ERROR_BIT | 10,
+    VolumeFull = EFI_ERROR_BIT | 11,
+    NoMedia = EFI_ERROR_BIT | 12,
+    MediaChanged = EFI_ERROR_BIT | 13,
+    NotFound = EFI_ERROR_BIT | 14,
+    AccessDenied = EFI_ERROR_BIT | 15,
+    NoResponse = EFI_ERROR_BIT | 16,
+    NoMapping = EFI_ERROR_BIT | 17,
+    Timeout = EFI_ERROR_BIT | 18,
+    NotStarted = EFI_ERROR_BIT | 19,
+    AlreadyStarted = EFI_ERROR_BIT | 20,
+    Aborted = EFI_ERROR_BIT | 21,
+}
+impl EfiStatus {
+    pub fn into_result(self) -> Result<()> {
+        if self == EfiStatus::Success {
+            Ok(())
+        } else {
+            Err(Error::Uefi(self))
+        }
+    }
 }
 
 // UEFIから返されるメモリマップにおける、様々なディスクリプタのタイプ
+// UEFIの仕様ではUINT32なので、ディスクリプタの先頭4バイトを占める
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
+#[repr(u32)]
 pub enum EfiMemoryType {
     RESERVED = 0,
     LOADER_CODE,
@@ -69,6 +113,52 @@ pub enum EfiMemoryType {
     PAL_CODE,
     PERSISTENT_MEMORY,
 }
+impl EfiMemoryType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EfiMemoryType::RESERVED => "Reserved",
+            EfiMemoryType::LOADER_CODE => "LoaderCode",
+            EfiMemoryType::LOADER_DATA => "LoaderData",
+            EfiMemoryType::BOOT_SERVICES_CODE => "BootServicesCode",
+            EfiMemoryType::BOOT_SERVICES_DATA => "BootServicesData",
+            EfiMemoryType::RUNTIME_SERVICES_CODE => "RuntimeServicesCode",
+            EfiMemoryType::RUNTIME_SERVICES_DATA => "RuntimeServicesData",
+            EfiMemoryType::CONVENTIONAL_MEMORY => "Conventional",
+            EfiMemoryType::UNUSABLE_MEMORY => "Unusable",
+            EfiMemoryType::ACPI_RECLAIM_MEMORY => "ACPIReclaim",
+            EfiMemoryType::ACIP_MEMORY_NVS => "ACPIMemoryNVS",
+            EfiMemoryType::MEMORY_MAPPED_IO => "MMIO",
+            EfiMemoryType::MEMORY_MAPPED_IO_PORT_SPACE => "MMIOPortSpace",
+            EfiMemoryType::PAL_CODE => "PalCode",
+            EfiMemoryType::PERSISTENT_MEMORY => "Persistent",
+        }
+    }
+}
+
+// メモリ領域の属性のビットとその名前
+const EFI_MEMORY_ATTRIBUTES: [(u64, &str); 12] = [
+    (0x1, "UC"),
+    (0x2, "WC"),
+    (0x4, "WT"),
+    (0x8, "WB"),
+    (0x10, "UCE"),
+    (0x1000, "WP"),
+    (0x2000, "RP"),
+    (0x4000, "XP"),
+    (0x8000, "NV"),
+    (0x10000, "MORE_RELIABLE"),
+    (0x20000, "RO"),
+    (1 << 63, "RUNTIME"),
+];
+
+// 1MiB以上ならMiB単位、それ未満ならKiB単位で表示する
+fn fmt_size(f: &mut fmt::Formatter, bytes: u64) -> fmt::Result {
+    if bytes >= 1024 * 1024 {
+        write!(f, "{} MiB", bytes / 1024 / 1024)
+    } else {
+        write!(f, "{} KiB", bytes / 1024)
+    }
+}
 
 // メモリのディスクリプタ
 // メモリマップはこれが連なっている
@@ -91,9 +181,42 @@ impl EfiMemoryDescriptor {
     pub fn physical_start(&self) -> u64 {
         self.physical_start
     }
+    pub fn attribute(&self) -> u64 {
+        self.attribute
+    }
+    pub fn physical_range(&self) -> Range<u64> {
+        self.physical_start..self.physical_start + self.number_of_pages * 4096
+    }
+    fn overlaps(&self, other: &EfiMemoryDescriptor) -> bool {
+        let (a, b) = (self.physical_range(), other.physical_range());
+        a.start < b.end && b.start < a.end
+    }
+}
+impl fmt::Display for EfiMemoryDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<20} {:#018X} {:>8} pages (",
+            self.memory_type.name(),
+            self.physical_start,
+            self.number_of_pages
+        )?;
+        fmt_size(f, self.number_of_pages * 4096)?;
+        write!(f, ") [")?;
+        let mut is_first = true;
+        for (bit, name) in EFI_MEMORY_ATTRIBUTES {
+            if self.attribute & bit != 0 {
+                write!(f, "{}{name}", if is_first { "" } else { "|" })?;
+                is_first = false;
+            }
+        }
+        write!(f, "]")
+    }
 }
 
 const MEMORY_MAP_BUFFER_SIZE: usize = 0x8000;
+// to_bytesで先頭に置くディスクリプタの数とdescriptor_size (それぞれu64)
+const MEMORY_MAP_BYTES_HEADER_SIZE: usize = 16;
 
 pub struct MemoryMapHolder {
     memory_map_buffer: [u8; MEMORY_MAP_BUFFER_SIZE],
@@ -115,6 +238,135 @@ impl MemoryMapHolder {
     pub fn iter(&self) -> MemoryMapIterator {
         MemoryMapIterator { map: self, ofs: 0 }
     }
+    // CONVENTIONAL_MEMORYの合計(バイト)
+    pub fn total_conventional_memory(&self) -> u64 {
+        self.iter()
+            .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+            .map(|e| e.number_of_pages() * 4096)
+            .sum()
+    }
+    pub fn dump(&self) {
+        println!("{self}");
+    }
+    // rangeが1つのCONVENTIONAL_MEMORYのディスクリプタに収まっているかどうか
+    pub fn is_conventional_memory(&self, range: Range<u64>) -> bool {
+        self.iter().any(|e| {
+            let r = e.physical_range();
+            e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY
+                && r.start <= range.start
+                && range.end <= r.end
+        })
+    }
+    pub fn num_of_descriptors(&self) -> usize {
+        self.memory_map_size
+            .checked_div(self.descriptor_size)
+            .unwrap_or(0)
+    }
+    // メモリマップを保存するためのバイト列に変換する
+    // [ディスクリプタの数: u64][descriptor_size: u64][ディスクリプタのバイト列]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let raw = &self.memory_map_buffer[..self.num_of_descriptors() * self.descriptor_size];
+        let mut bytes = Vec::with_capacity(MEMORY_MAP_BYTES_HEADER_SIZE + raw.len());
+        bytes.extend_from_slice(&(self.num_of_descriptors() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.descriptor_size as u64).to_le_bytes());
+        bytes.extend_from_slice(raw);
+        bytes
+    }
+    // to_bytesで保存したバイト列からメモリマップを復元する
+    pub fn from_bytes(bytes: &[u8]) -> Result<MemoryMapHolder> {
+        if bytes.len() < MEMORY_MAP_BYTES_HEADER_SIZE {
+            return Err(Error::Failed("Memory map is truncated"));
+        }
+        let (header, raw) = bytes.split_at(MEMORY_MAP_BYTES_HEADER_SIZE);
+        let count = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let descriptor_size = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+        if descriptor_size < size_of::<EfiMemoryDescriptor>() {
+            return Err(Error::Failed("Memory map has an invalid descriptor size"));
+        }
+        let size = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(descriptor_size))
+            .filter(|size| *size <= MEMORY_MAP_BUFFER_SIZE)
+            .ok_or(Error::Failed("Memory map is too large"))?;
+        if raw.len() != size {
+            return Err(Error::Failed("Memory map is truncated"));
+        }
+        // EfiMemoryTypeに無い値を持つディスクリプタは参照として読めないので、ここで弾く
+        if raw.chunks_exact(descriptor_size).any(|d| {
+            u32::from_le_bytes(d[0..4].try_into().unwrap())
+                > EfiMemoryType::PERSISTENT_MEMORY as u32
+        }) {
+            return Err(Error::Failed("Memory map has an unknown memory type"));
+        }
+        let mut map = MemoryMapHolder::new();
+        map.memory_map_buffer[..size].copy_from_slice(raw);
+        map.memory_map_size = size;
+        map.descriptor_size = descriptor_size;
+        Ok(map)
+    }
+    // selfを前、otherを後のメモリマップとして、その差分を求める
+    // 開始アドレスとページ数が同じディスクリプタを、同じ領域とみなす
+    pub fn diff(&self, other: &MemoryMapHolder) -> MemoryMapDiff {
+        let same_range = |a: &EfiMemoryDescriptor, b: &EfiMemoryDescriptor| {
+            a.physical_range() == b.physical_range()
+        };
+        let is_conventional =
+            |e: &EfiMemoryDescriptor| e.memory_type == EfiMemoryType::CONVENTIONAL_MEMORY;
+        let mut diff = MemoryMapDiff::default();
+        for before in self.iter() {
+            match other.iter().find(|after| same_range(before, after)) {
+                Some(after) if after.memory_type != before.memory_type => {
+                    diff.changed.push((*before, *after))
+                }
+                Some(_) => (),
+                None => diff.removed.push(*before),
+            }
+        }
+        for after in other.iter() {
+            if !self.iter().any(|before| same_range(before, after)) {
+                diff.added.push(*after);
+            }
+            // 前のCONVENTIONAL_MEMORYと少しでも重なるものは、既にヒープにある可能性があるので含めない
+            if is_conventional(after)
+                && !self
+                    .iter()
+                    .any(|before| is_conventional(before) && before.overlaps(after))
+            {
+                diff.newly_conventional.push(*after);
+            }
+        }
+        diff
+    }
+}
+
+// MemoryMapHolder::diffの結果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryMapDiff {
+    // 両方にあるが種類が変わった領域 (前, 後)
+    pub changed: Vec<(EfiMemoryDescriptor, EfiMemoryDescriptor)>,
+    // 後のメモリマップにしかない領域
+    pub added: Vec<EfiMemoryDescriptor>,
+    // 前のメモリマップにしかない領域
+    pub removed: Vec<EfiMemoryDescriptor>,
+    // 後でCONVENTIONAL_MEMORYになった領域
+    // ALLOCATOR.add_free_from_descriptorでヒープに戻せる
+    pub newly_conventional: Vec<EfiMemoryDescriptor>,
+}
+impl MemoryMapDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+}
+impl fmt::Display for MemoryMapHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in self.iter() {
+            writeln!(f, "{e}")?;
+        }
+        let total = self.total_conventional_memory();
+        write!(f, "Total conventional memory: {} pages (", total / 4096)?;
+        fmt_size(f, total)?;
+        write!(f, ")")
+    }
 }
 impl Default for MemoryMapHolder {
     fn default() -> Self {
@@ -200,33 +452,52 @@ pub struct EfiSystemTable {
     configuration_table: *const EfiConfigurationTable, // EfiConfigurationTableが並べられた配列へのポインタ
 }
 const _: () = assert!(offset_of!(EfiSystemTable, boot_services) == 96);
+const _: () = assert!(offset_of!(EfiSystemTable, number_of_table_entries) == 104);
+const _: () = assert!(offset_of!(EfiSystemTable, configuration_table) == 112);
 impl EfiSystemTable {
     pub fn boot_services(&self) -> &EfiBootServicesTable {
         self.boot_services
     }
-    // テーブルを検索
-    fn lookup_config_table(&self, guid: &EfiGuid) -> Option<EfiConfigurationTable> {
-        for i in 0..self.number_of_table_entries {
-            let ct = unsafe { &*self.configuration_table.add(i) };
-            if ct.vendor_guid == *guid {
-                return Some(*ct);
-            }
+    // ACPI, SMBIOSなど、ファームウェアから渡されたテーブルの一覧
+    pub fn config_tables(&self) -> impl Iterator<Item = &EfiConfigurationTable> {
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
         }
-        None
+        .iter()
     }
-    pub fn acpi_table(&self) -> Option<&'static AcpiRsdpStruct> {
-        self.lookup_config_table(&EFI_ACPI_TABLE_GUID)
-            .map(|t| unsafe { &*(t.vendor_table as *const AcpiRsdpStruct) })
+    // テーブルを検索
+    pub fn find_config_table(&self, guid: &EfiGuid) -> Option<&EfiConfigurationTable> {
+        find_config_table(self.config_tables(), guid)
+    }
+    // ACPI 2.0のRSDPを返す。シグネチャやチェックサムが正しくなければエラーにする
+    pub fn acpi_table(&self) -> Result<&'static AcpiRsdpStruct> {
+        let table = self
+            .find_config_table(&EFI_ACPI_TABLE_GUID)
+            .ok_or(Error::Acpi("RSDP is not found in the configuration tables"))?;
+        let rsdp = unsafe { &*(table.vendor_table as *const AcpiRsdpStruct) };
+        rsdp.validate()?;
+        Ok(rsdp)
     }
 }
 
+fn find_config_table<'a>(
+    mut tables: impl Iterator<Item = &'a EfiConfigurationTable>,
+    guid: &EfiGuid,
+) -> Option<&'a EfiConfigurationTable> {
+    tables.find(|t| t.vendor_guid == *guid)
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct EfiGraphicsOutputProtocolPixelInfo {
     version: u32,
     pub horizontal_resolution: u32,
     pub vertival_resolution: u32,
-    _padding0: [u32; 5],
+    pub pixel_format: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    _reserved_mask: u32,
     pub pixels_per_scan_line: u32,
 }
 const _: () = assert!(size_of::<EfiGraphicsOutputProtocolPixelInfo>() == 36);
@@ -260,18 +531,23 @@ fn locate_graphic_protocol<'a>(
         null_mut::<EfiVoid>(), // null
         &mut graphic_output_protocol as *mut *mut EfiGraphicsOutputProtocol as *mut *mut EfiVoid,
     );
-    if status != EfiStatus::Success {
-        return Err("Failed to locate graphics output protocol");
-    }
+    status.into_result()?;
     Ok(unsafe { &*graphic_output_protocol })
 }
 
-#[derive(Clone, Copy)]
 pub struct VramBufferInfo {
     buf: *mut u8,
     width: i64,
     height: i64,
     pixels_per_line: i64,
+    size: usize,
+    pixel_format: PixelFormat,
+}
+impl VramBufferInfo {
+    // フレームバッファの物理アドレスの範囲
+    pub fn frame_buffer_range(&self) -> Range<usize> {
+        self.buf as usize..self.buf as usize + self.size
+    }
 }
 impl Bitmap for VramBufferInfo {
     fn bytes_per_pixel(&self) -> i64 {
@@ -289,15 +565,40 @@ impl Bitmap for VramBufferInfo {
     fn buf_mut(&mut self) -> *mut u8 {
         self.buf
     }
+    fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+}
+
+// EFI_GRAPHICS_PIXEL_FORMAT
+const PIXEL_RED_GREEN_BLUE_RESERVED_8BIT_PER_COLOR: u32 = 0;
+const PIXEL_BLUE_GREEN_RED_RESERVED_8BIT_PER_COLOR: u32 = 1;
+const PIXEL_BIT_MASK: u32 = 2;
+
+fn pixel_format_from_info(info: &EfiGraphicsOutputProtocolPixelInfo) -> Result<PixelFormat> {
+    match info.pixel_format {
+        PIXEL_RED_GREEN_BLUE_RESERVED_8BIT_PER_COLOR => Ok(PixelFormat::Rgb),
+        PIXEL_BLUE_GREEN_RED_RESERVED_8BIT_PER_COLOR => Ok(PixelFormat::Bgr),
+        PIXEL_BIT_MASK => Ok(PixelFormat::Bitmask {
+            red: info.red_mask,
+            green: info.green_mask,
+            blue: info.blue_mask,
+        }),
+        // PixelBltOnlyなど、フレームバッファに直接書き込めない形式
+        _ => Err(GraphicsError::UnsupportedPixelFormat.into()),
+    }
 }
 
 pub fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
     let gp = locate_graphic_protocol(efi_system_table)?;
+    let pixel_format = pixel_format_from_info(gp.mode.info)?;
     Ok(VramBufferInfo {
         buf: gp.mode.frame_buffer_base as *mut u8,
         width: gp.mode.info.horizontal_resolution as i64,
         height: gp.mode.info.vertival_resolution as i64,
         pixels_per_line: gp.mode.info.pixels_per_scan_line as i64,
+        size: gp.mode.frame_buffer_size,
+        pixel_format,
     })
 }
 
@@ -317,9 +618,7 @@ pub fn locate_loaded_image_protocol(
         &EFI_LOADED_IMAGE_PROTOCOL_GUID,
         &mut graphic_output_protocol as *mut *mut EfiLoadedImageProtocol as *mut *mut EfiVoid,
     );
-    if status != EfiStatus::Success {
-        return Err("Failed to locate graphics output protocol");
-    }
+    status.into_result()?;
     Ok(unsafe { &*graphic_output_protocol })
 }
 
@@ -343,8 +642,217 @@ pub fn exit_from_boot_services(
 
 // GUIDとテーブルのおいてあるアドレスの紐付け
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct EfiConfigurationTable {
     vendor_guid: EfiGuid,
-    pub vendor_table: *const u8,
+    pub vendor_table: *const EfiVoid,
+}
+const _: () = assert!(size_of::<EfiConfigurationTable>() == 24);
+const _: () = assert!(offset_of!(EfiConfigurationTable, vendor_table) == 16);
+impl EfiConfigurationTable {
+    pub fn vendor_guid(&self) -> &EfiGuid {
+        &self.vendor_guid
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use alloc::format;
+
+    pub fn map_from_descriptors(descriptors: &[EfiMemoryDescriptor]) -> MemoryMapHolder {
+        let mut map = MemoryMapHolder::new();
+        let descriptor_size = size_of::<EfiMemoryDescriptor>();
+        for (i, d) in descriptors.iter().enumerate() {
+            unsafe {
+                (map.memory_map_buffer.as_mut_ptr().add(i * descriptor_size)
+                    as *mut EfiMemoryDescriptor)
+                    .write_unaligned(*d)
+            }
+        }
+        map.descriptor_size = descriptor_size;
+        map.memory_map_size = core::mem::size_of_val(descriptors);
+        map
+    }
+
+    #[test_case]
+    fn pixel_format_is_read_from_gop_info() {
+        let mut info = EfiGraphicsOutputProtocolPixelInfo {
+            version: 0,
+            horizontal_resolution: 800,
+            vertival_resolution: 600,
+            pixel_format: PIXEL_BLUE_GREEN_RED_RESERVED_8BIT_PER_COLOR,
+            red_mask: 0,
+            green_mask: 0,
+            blue_mask: 0,
+            _reserved_mask: 0,
+            pixels_per_scan_line: 800,
+        };
+        assert_eq!(pixel_format_from_info(&info), Ok(PixelFormat::Bgr));
+        info.pixel_format = PIXEL_RED_GREEN_BLUE_RESERVED_8BIT_PER_COLOR;
+        assert_eq!(pixel_format_from_info(&info), Ok(PixelFormat::Rgb));
+        info.pixel_format = PIXEL_BIT_MASK;
+        info.red_mask = 0xff;
+        info.green_mask = 0xff00;
+        info.blue_mask = 0xff0000;
+        assert_eq!(
+            pixel_format_from_info(&info),
+            Ok(PixelFormat::Bitmask {
+                red: 0xff,
+                green: 0xff00,
+                blue: 0xff0000
+            })
+        );
+        // PixelBltOnly
+        info.pixel_format = 3;
+        assert_eq!(
+            pixel_format_from_info(&info),
+            Err(Error::Graphics(GraphicsError::UnsupportedPixelFormat))
+        );
+    }
+
+    fn desc(memory_type: EfiMemoryType, start: u64, pages: u64, attr: u64) -> EfiMemoryDescriptor {
+        EfiMemoryDescriptor {
+            memory_type,
+            physical_start: start,
+            virtual_start: 0,
+            number_of_pages: pages,
+            attribute: attr,
+        }
+    }
+
+    #[test_case]
+    fn find_config_table_by_guid() {
+        let other_guid = EfiGuid {
+            data0: 0xf2fd1544,
+            data1: 0x9794,
+            data2: 0x4a2c,
+            data3: [0x99, 0x2e, 0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94],
+        };
+        let tables = [
+            EfiConfigurationTable {
+                vendor_guid: other_guid,
+                vendor_table: 0x1000 as *const EfiVoid,
+            },
+            EfiConfigurationTable {
+                vendor_guid: EFI_ACPI_TABLE_GUID,
+                vendor_table: 0x2000 as *const EfiVoid,
+            },
+        ];
+        let found = find_config_table(tables.iter(), &EFI_ACPI_TABLE_GUID).unwrap();
+        assert_eq!(found.vendor_table as usize, 0x2000);
+        assert_eq!(*found.vendor_guid(), EFI_ACPI_TABLE_GUID);
+        let found = find_config_table(tables.iter(), &other_guid).unwrap();
+        assert_eq!(found.vendor_table as usize, 0x1000);
+        assert!(find_config_table(tables[..1].iter(), &EFI_ACPI_TABLE_GUID).is_none());
+    }
+
+    #[test_case]
+    fn format_memory_map() {
+        let map = map_from_descriptors(&[
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x1000, 159, 0xf),
+            desc(EfiMemoryType::BOOT_SERVICES_DATA, 0x10_0000, 0x300, 0xf),
+            desc(
+                EfiMemoryType::RUNTIME_SERVICES_CODE,
+                0x7f00_0000,
+                16,
+                (1 << 63) | 0x1,
+            ),
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x100_0000, 0x800, 0),
+        ]);
+        assert_eq!(
+            format!("{map}"),
+            "Conventional         0x0000000000001000      159 pages (636 KiB) [UC|WC|WT|WB]\n\
+             BootServicesData     0x0000000000100000      768 pages (3 MiB) [UC|WC|WT|WB]\n\
+             RuntimeServicesCode  0x000000007F000000       16 pages (64 KiB) [UC|RUNTIME]\n\
+             Conventional         0x0000000001000000     2048 pages (8 MiB) []\n\
+             Total conventional memory: 2207 pages (8 MiB)"
+        );
+        assert_eq!(map.total_conventional_memory(), 2207 * 4096);
+    }
+
+    #[test_case]
+    fn memory_map_round_trips_through_bytes() {
+        let map = map_from_descriptors(&[
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x1000, 159, 0xf),
+            desc(EfiMemoryType::ACPI_RECLAIM_MEMORY, 0x7fb7_e000, 4, 0xf),
+            desc(EfiMemoryType::MEMORY_MAPPED_IO, 0xffc0_0000, 0x400, 1 << 63),
+        ]);
+        let bytes = map.to_bytes();
+        assert_eq!(bytes.len(), 16 + 3 * size_of::<EfiMemoryDescriptor>());
+        let restored = MemoryMapHolder::from_bytes(&bytes).expect("from_bytes failed");
+        assert_eq!(restored.num_of_descriptors(), 3);
+        assert!(map.iter().eq(restored.iter()));
+        assert_eq!(format!("{restored}"), format!("{map}"));
+        assert_eq!(restored.to_bytes(), bytes);
+
+        // 長さが合わないものや、不明な種類のディスクリプタは読み込まない
+        assert!(MemoryMapHolder::from_bytes(&bytes[..8]).is_err());
+        assert!(MemoryMapHolder::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut bad_size = bytes.clone();
+        bad_size[8..16].copy_from_slice(&8u64.to_le_bytes());
+        assert!(MemoryMapHolder::from_bytes(&bad_size).is_err());
+        let mut too_many = bytes.clone();
+        too_many[0..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(MemoryMapHolder::from_bytes(&too_many).is_err());
+        let mut bad_type = bytes.clone();
+        bad_type[16] = 0x70;
+        assert!(MemoryMapHolder::from_bytes(&bad_type).is_err());
+        // 下位バイトは正しくても、u32として範囲外なら弾く
+        let mut bad_type = bytes.clone();
+        bad_type[17] = 0x01;
+        assert!(MemoryMapHolder::from_bytes(&bad_type).is_err());
+        let empty = MemoryMapHolder::from_bytes(&map_from_descriptors(&[]).to_bytes())
+            .expect("from_bytes failed");
+        assert_eq!(empty.iter().count(), 0);
+    }
+
+    #[test_case]
+    fn diff_finds_reclaimed_boot_services_data() {
+        let conventional = desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x1000, 159, 0xf);
+        let boot_services_code = desc(EfiMemoryType::BOOT_SERVICES_CODE, 0x10_0000, 0x20, 0xf);
+        let boot_services_data = desc(EfiMemoryType::BOOT_SERVICES_DATA, 0x20_0000, 0x100, 0xf);
+        let acpi = desc(EfiMemoryType::ACPI_RECLAIM_MEMORY, 0x7fb7_e000, 4, 0xf);
+        let before =
+            map_from_descriptors(&[conventional, boot_services_code, boot_services_data, acpi]);
+        let reclaimed = desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x20_0000, 0x100, 0xf);
+        let after = map_from_descriptors(&[conventional, boot_services_code, reclaimed, acpi]);
+        let diff = before.diff(&after);
+        assert_eq!(diff.changed, [(boot_services_data, reclaimed)]);
+        assert_eq!(diff.newly_conventional, [reclaimed]);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert!(!diff.is_empty());
+        assert!(before.diff(&before).is_empty());
+
+        // 分割されただけのCONVENTIONAL_MEMORYは、新たに空いた領域として扱わない
+        let split = map_from_descriptors(&[
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x1000, 100, 0xf),
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x65000, 59, 0xf),
+            acpi,
+        ]);
+        let diff = before.diff(&split);
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.added.len(), 2);
+        assert_eq!(
+            diff.removed,
+            [conventional, boot_services_code, boot_services_data]
+        );
+        assert!(diff.newly_conventional.is_empty());
+    }
+
+    #[test_case]
+    fn conventional_memory_lookup() {
+        let map = map_from_descriptors(&[
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x1000, 7, 0xf),
+            desc(EfiMemoryType::ACIP_MEMORY_NVS, 0x8000, 1, 0xf),
+            desc(EfiMemoryType::CONVENTIONAL_MEMORY, 0x9000, 0x96, 0xf),
+        ]);
+        assert!(map.is_conventional_memory(0x1000..0x8000));
+        assert!(map.is_conventional_memory(0x9000..0xa000));
+        assert!(!map.is_conventional_memory(0x8000..0x9000));
+        assert!(!map.is_conventional_memory(0x7000..0x9000));
+        assert!(!map.is_conventional_memory(0x9f000..0xa0000));
+    }
 }
@@ -1,8 +1,18 @@
 extern crate alloc;
 
+use crate::apic::send_eoi;
 use crate::error;
+use crate::executor::on_timer_interrupt;
+use crate::executor::on_timer_queue_interrupt;
+use crate::frame_allocator::alloc_frame;
+use crate::frame_allocator::free_frame;
+use crate::hpet::TIMER_INTERRUPT_VECTOR;
+use crate::hpet::TIMER_QUEUE_INTERRUPT_VECTOR;
 use crate::info;
+use crate::result::Error;
 use crate::result::Result;
+use crate::serial::on_serial_interrupt;
+use crate::serial::SERIAL_INTERRUPT_VECTOR;
 
 use alloc::boxed::Box;
 
@@ -13,8 +23,11 @@ use core::marker::PhantomData;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::mem::size_of_val;
+use core::mem::ManuallyDrop;
 use core::mem::MaybeUninit;
 use core::pin::Pin;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
@@ -38,10 +51,139 @@ pub fn write_io_port_u8(port: u16, data: u8) {
     }
 }
 
+pub fn read_io_port_u16(port: u16) -> u16 {
+    let mut data: u16;
+    unsafe {
+        asm!("in ax, dx",
+                out("ax") data,
+                in("dx") port)
+    }
+    data
+}
+
+pub fn write_io_port_u16(port: u16, data: u16) {
+    unsafe {
+        asm!("out dx, ax",
+                in("ax") data,
+                in("dx") port)
+    }
+}
+
+pub fn enable_interrupts() {
+    unsafe { asm!("sti") }
+}
+
+pub fn disable_interrupts() {
+    unsafe { asm!("cli") }
+}
+
+// RFLAGSのIFビットを見て、割り込みが有効かどうかを返す
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq",
+             "pop {}",
+             out(reg) rflags)
+    }
+    rflags & (1 << 9) != 0
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// CPUID.80000007H:EDX[8] Invariant TSC: 電源状態やクロックによらず一定の周期で進む
+pub fn has_invariant_tsc() -> bool {
+    const CPUID_EXT_MAX_LEAF: u32 = 0x8000_0000;
+    const CPUID_EXT_POWER_MANAGEMENT: u32 = 0x8000_0007;
+    const EDX_INVARIANT_TSC: u32 = 1 << 8;
+    let max_leaf = unsafe { core::arch::x86_64::__cpuid(CPUID_EXT_MAX_LEAF) }.eax;
+    if max_leaf < CPUID_EXT_POWER_MANAGEMENT {
+        return false;
+    }
+    let edx = unsafe { core::arch::x86_64::__cpuid(CPUID_EXT_POWER_MANAGEMENT) }.edx;
+    edx & EDX_INVARIANT_TSC != 0
+}
+
+// CPUID.80000008H:EAX[7:0] 物理アドレスのビット数
+pub fn physical_address_bits() -> u32 {
+    const CPUID_EXT_MAX_LEAF: u32 = 0x8000_0000;
+    const CPUID_EXT_ADDRESS_SIZE: u32 = 0x8000_0008;
+    let max_leaf = unsafe { core::arch::x86_64::__cpuid(CPUID_EXT_MAX_LEAF) }.eax;
+    if max_leaf < CPUID_EXT_ADDRESS_SIZE {
+        // CPUIDで取得できない場合は36bitとみなす
+        return 36;
+    }
+    unsafe { core::arch::x86_64::__cpuid(CPUID_EXT_ADDRESS_SIZE) }.eax & 0xff
+}
+
+pub fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe {
+        asm!("mov {}, cr0",
+                out(reg) cr0)
+    }
+    cr0
+}
+
+/// # Safety
+/// Writing CR0 can change the CPU behavior arbitrarily.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, {}",
+            in(reg) cr0)
+}
+
+pub fn read_cr4() -> u64 {
+    let cr4: u64;
+    unsafe {
+        asm!("mov {}, cr4",
+                out(reg) cr4)
+    }
+    cr4
+}
+
+// キャッシュの内容をメモリに書き戻してから無効化する
+pub fn wbinvd() {
+    unsafe { asm!("wbinvd") }
+}
+
 pub fn busy_loop_hint() {
     unsafe { asm!("pause") }
 }
 
+pub const MSR_IA32_APIC_BASE: u32 = 0x1b;
+pub const MSR_IA32_PAT: u32 = 0x277;
+pub const MSR_IA32_EFER: u32 = 0xc000_0080;
+const EFER_NO_EXECUTE_ENABLE: u64 = 1 << 11;
+
+// EFER.NXEが無効な場合、ページテーブルのNXビットは予約ビット扱いになり#PFが起きる
+pub fn is_no_execute_enabled() -> bool {
+    read_msr(MSR_IA32_EFER) & EFER_NO_EXECUTE_ENABLE != 0
+}
+
+// Model Specific Registerの読み書き
+// 上位32bitがedx, 下位32bitがeaxに入る
+pub fn read_msr(msr: u32) -> u64 {
+    let mut high: u32;
+    let mut low: u32;
+    unsafe {
+        asm!("rdmsr",
+                in("ecx") msr,
+                out("edx") high,
+                out("eax") low)
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// # Safety
+/// Writing an MSR can change the CPU behavior arbitrarily.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr",
+            in("ecx") msr,
+            in("edx") (value >> 32) as u32,
+            in("eax") value as u32)
+}
+
 pub fn read_cr3() -> *mut PML4 {
     let mut cr3: *mut PML4;
     unsafe {
@@ -52,18 +194,65 @@ pub fn read_cr3() -> *mut PML4 {
 }
 
 pub const PAGE_SIZE: usize = 4096;
+pub const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
 const ATTR_MASK: u64 = 0xFFF;
 const ATTR_PRESENT: u64 = 1 << 0;
 const ATTR_WRITABLE: u64 = 1 << 1;
+const ATTR_USER: u64 = 1 << 2;
 const ATTR_WRITE_THROUGH: u64 = 1 << 3;
 const ATTR_CACHE_DISABLE: u64 = 1 << 4;
+const ATTR_ACCESSED: u64 = 1 << 5;
+const ATTR_DIRTY: u64 = 1 << 6;
+const ATTR_PAGE_SIZE: u64 = 1 << 7;
+// 4KiBページのPTEでは、bit 7はPAGE_SIZEではなくPATのインデックスの最上位ビットになる
+const ATTR_PAT_4K: u64 = 1 << 7;
+// 2MiBページのPDEでは、PATのビットはbit 12にある
+const ATTR_PAT_2M: u64 = 1 << 12;
+const ATTR_NO_EXECUTE: u64 = 1 << 63;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// ページテーブルのエントリの属性ビットとその名前
+const ENTRY_ATTRIBUTES: [(u64, &str); 9] = [
+    (ATTR_PRESENT, "PRESENT"),
+    (ATTR_WRITABLE, "WRITABLE"),
+    (ATTR_USER, "USER"),
+    (ATTR_WRITE_THROUGH, "WRITE_THROUGH"),
+    (ATTR_CACHE_DISABLE, "CACHE_DISABLE"),
+    (ATTR_ACCESSED, "ACCESSED"),
+    (ATTR_DIRTY, "DIRTY"),
+    (ATTR_PAGE_SIZE, "LARGE_PAGE"),
+    (ATTR_NO_EXECUTE, "NX"),
+];
+
+// PATのインデックスは PAT:PCD:PWT の3bitで決まる
+// 0, 3 はリセット時の既定値(WB, UC)のまま使い、4 は enable_write_combining() でWCにする
+const PAT_INDEX_WRITE_COMBINING: u64 = 4;
+const PAT_TYPE_WRITE_COMBINING: u64 = 0x01;
 
 #[derive(Debug, Copy, Clone)]
 #[repr(u64)]
 pub enum PageAttr {
     NotPresent = 0,
-    ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
-    ReadWriteIo = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
+    // 通常のメモリ向け (PAT[0]: ライトバック)
+    WriteBack = ATTR_PRESENT | ATTR_WRITABLE,
+    // フレームバッファ向け (PAT[4]: ライトコンバイン)
+    WriteCombining = ATTR_PRESENT | ATTR_WRITABLE | ATTR_PAT_4K,
+    // デバイスのMMIO向け (PAT[3]: キャッシュ無効)
+    Uncacheable = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
+    // 読み出し専用のメモリ向け
+    ReadOnly = ATTR_PRESENT,
+    // 実行しないメモリ向け (is_no_execute_enabled()の場合のみ使える)
+    ReadOnlyNoExecute = ATTR_PRESENT | ATTR_NO_EXECUTE,
+    WriteBackNoExecute = ATTR_PRESENT | ATTR_WRITABLE | ATTR_NO_EXECUTE,
+}
+
+// PATのエントリ4をライトコンバインに設定する
+// PageAttr::WriteCombining でマップする前に呼ぶ必要がある
+pub fn enable_write_combining() {
+    let shift = PAT_INDEX_WRITE_COMBINING * 8;
+    let pat = read_msr(MSR_IA32_PAT) & !(0xff << shift);
+    unsafe { write_msr(MSR_IA32_PAT, pat | (PAT_TYPE_WRITE_COMBINING << shift)) }
+    flush_tlb();
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -89,7 +278,16 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
         (self.read_value() & (1 << 1)) != 0
     }
     fn is_user(&self) -> bool {
-        (self.read_value() & (1 << 2)) != 0
+        (self.read_value() & ATTR_USER) != 0
+    }
+    fn is_large_page(&self) -> bool {
+        (self.read_value() & ATTR_PAGE_SIZE) != 0
+    }
+    fn phys_addr(&self) -> u64 {
+        self.read_value() & ADDR_MASK
+    }
+    fn clear(&mut self) {
+        self.value = 0;
     }
     fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -102,25 +300,38 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
             if self.is_writable() { "W" } else { "R" },
             if self.is_user() { "U" } else { "S" }
         )?;
-        write!(f, "}}")
+        write!(f, "-> {:#018X} [", self.phys_addr())?;
+        let mut is_first = true;
+        for (bit, name) in ENTRY_ATTRIBUTES {
+            if self.read_value() & bit != 0 {
+                let name = if LEVEL == 1 && bit == ATTR_PAGE_SIZE {
+                    "PAT"
+                } else {
+                    name
+                };
+                write!(f, "{}{name}", if is_first { "" } else { "|" })?;
+                is_first = false;
+            }
+        }
+        write!(f, "] }}")
     }
     fn table(&self) -> Result<&NEXT> {
         if self.is_present() {
             Ok(unsafe { &*((self.value & !ATTR_MASK) as *const NEXT) })
         } else {
-            Err("Page Not Fount")
+            Err(Error::Failed("Page Not Fount"))
         }
     }
     fn table_mut(&mut self) -> Result<&mut NEXT> {
         if self.is_present() {
             Ok(unsafe { &mut *((self.value & !ATTR_MASK) as *mut NEXT) })
         } else {
-            Err("Page Not Fount")
+            Err(Error::Failed("Page Not Fount"))
         }
     }
     fn set_page(&mut self, phys: u64, attr: PageAttr) -> Result<()> {
         if phys & ATTR_MASK != 0 {
-            Err("phys is not aligned")
+            Err(Error::Failed("phys is not aligned"))
         } else {
             self.value = phys | attr as u64;
             Ok(())
@@ -128,10 +339,11 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
     }
     fn populate(&mut self) -> Result<&mut Self> {
         if self.is_present() {
-            Err("Page is already populated")
+            Err(Error::Failed("Page is already populated"))
         } else {
-            let next: Box<NEXT> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
-            self.value = Box::into_raw(next) as u64 | PageAttr::ReadWriteKernel as u64;
+            // 次のレベルのテーブルは、ヒープではなくFrameAllocatorの0で埋められたフレームに置く
+            let next = alloc_frame()?;
+            self.value = next as u64 | PageAttr::WriteBack as u64;
             Ok(self)
         }
     }
@@ -142,6 +354,49 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
             self.populate()
         }
     }
+    // populate()で確保した次のレベルのテーブルを解放する
+    unsafe fn free_table(&mut self) -> Result<()> {
+        if self.is_present() && !self.is_large_page() {
+            free_frame(self.phys_addr() as usize)?;
+        }
+        self.clear();
+        Ok(())
+    }
+}
+impl Entry<2, 21, PT> {
+    // 2MiBページとしてマップする
+    fn set_large_page(&mut self, phys: u64, attr: PageAttr) -> Result<()> {
+        if phys & (LARGE_PAGE_SIZE as u64 - 1) != 0 {
+            return Err(Error::Failed("phys is not aligned to 2MiB"));
+        }
+        let attr = attr as u64;
+        self.value = if attr & ATTR_PRESENT == 0 {
+            0
+        } else if attr & ATTR_PAT_4K != 0 {
+            phys | ATTR_PAGE_SIZE | ATTR_PAT_2M | (attr & !ATTR_PAT_4K)
+        } else {
+            phys | ATTR_PAGE_SIZE | attr
+        };
+        Ok(())
+    }
+    // 2MiBページを、同じ物理アドレスと属性を持つ512個の4KiBページに分割する
+    fn split_large_page(&mut self) -> Result<()> {
+        if !self.is_present() || !self.is_large_page() {
+            return Err(Error::Failed("Not a 2MiB page"));
+        }
+        let value = self.read_value();
+        let phys = value & ADDR_MASK & !(LARGE_PAGE_SIZE as u64 - 1);
+        let mut attr = value & (ATTR_MASK | ATTR_NO_EXECUTE) & !ATTR_PAGE_SIZE;
+        if value & ATTR_PAT_2M != 0 {
+            attr |= ATTR_PAT_4K;
+        }
+        let pt = alloc_frame()? as *mut PT;
+        for (i, pte) in unsafe { &mut *pt }.entry.iter_mut().enumerate() {
+            pte.value = (phys + (i * PAGE_SIZE) as u64) | attr;
+        }
+        self.value = pt as u64 | PageAttr::WriteBack as u64;
+        Ok(())
+    }
 }
 impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Display for Entry<LEVEL, SHIFT, NEXT> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -176,6 +431,9 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT: core::fmt::Debug> Table<LEVEL
     fn calc_index(&self, addr: u64) -> usize {
         ((addr >> SHIFT) & 0b1_1111_1111) as usize
     }
+    fn is_empty(&self) -> bool {
+        self.entry.iter().all(|e| !e.is_present())
+    }
 }
 impl<const LEVEL: usize, const SHIFT: usize, NEXT: fmt::Debug> fmt::Debug
     for Table<LEVEL, SHIFT, NEXT>
@@ -211,13 +469,13 @@ impl PML4 {
         attr: PageAttr,
     ) -> Result<()> {
         if virt_start & ATTR_MASK != 0 {
-            return Err("Invalid virt_start");
+            return Err(Error::Failed("Invalid virt_start"));
         }
         if virt_end & ATTR_MASK != 0 {
-            return Err("Invalid virt_end");
+            return Err(Error::Failed("Invalid virt_end"));
         }
         if phys & ATTR_MASK != 0 {
-            return Err("Invalid phys");
+            return Err(Error::Failed("Invalid phys"));
         }
         for addr in (virt_start..virt_end).step_by(PAGE_SIZE) {
             let index = self.calc_index(addr);
@@ -225,13 +483,137 @@ impl PML4 {
             let index = table.calc_index(addr);
             let table = table.entry[index].ensure_populated()?.table_mut()?;
             let index = table.calc_index(addr);
-            let table = table.entry[index].ensure_populated()?.table_mut()?;
+            let pde = &mut table.entry[index];
+            // 2MiBページの一部だけ属性を変える場合は、先に4KiBページに分割する
+            if pde.is_present() && pde.is_large_page() {
+                pde.split_large_page()?;
+            }
+            let table = pde.ensure_populated()?.table_mut()?;
             let index = table.calc_index(addr);
             let pte = &mut table.entry[index];
             pte.set_page(phys + addr - virt_start, attr)?;
         }
         Ok(())
     }
+    // 2MiBページでマップする。範囲と物理アドレスは2MiB境界に揃っている必要がある
+    pub fn create_mapping_2m(
+        &mut self,
+        virt_start: u64,
+        virt_end: u64,
+        phys: u64,
+        attr: PageAttr,
+    ) -> Result<()> {
+        let mask = LARGE_PAGE_SIZE as u64 - 1;
+        if virt_start & mask != 0 || virt_end & mask != 0 || phys & mask != 0 {
+            return Err(Error::Failed("Mapping is not aligned to 2MiB"));
+        }
+        for addr in (virt_start..virt_end).step_by(LARGE_PAGE_SIZE) {
+            let index = self.calc_index(addr);
+            let table = self.entry[index].ensure_populated()?.table_mut()?;
+            let index = table.calc_index(addr);
+            let table = table.entry[index].ensure_populated()?.table_mut()?;
+            let index = table.calc_index(addr);
+            let pde = &mut table.entry[index];
+            if pde.is_present() && !pde.is_large_page() {
+                return Err(Error::Failed("Already mapped with 4KiB pages"));
+            }
+            pde.set_large_page(phys + addr - virt_start, attr)?;
+        }
+        Ok(())
+    }
+    // virtを含む2MiBページを4KiBページに分割する
+    fn split_large_page_at(&mut self, virt: u64) -> Result<()> {
+        let pdpt = self.entry[self.calc_index(virt)].table_mut()?;
+        let pd = pdpt.entry[pdpt.calc_index(virt)].table_mut()?;
+        let index = pd.calc_index(virt);
+        pd.entry[index].split_large_page()
+    }
+    // 仮想アドレスを物理アドレスに変換する
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let entry = &self.entry[self.calc_index(virt)];
+        let pdpt = entry.table()?;
+        let entry = &pdpt.entry[pdpt.calc_index(virt)];
+        if entry.is_present() && entry.is_large_page() {
+            return Ok(TranslationResult::PageMapped1G {
+                phys: entry.phys_addr() + (virt & ((1 << 30) - 1)),
+            });
+        }
+        let pd = entry.table()?;
+        let entry = &pd.entry[pd.calc_index(virt)];
+        if entry.is_present() && entry.is_large_page() {
+            return Ok(TranslationResult::PageMapped2M {
+                phys: (entry.phys_addr() & !(LARGE_PAGE_SIZE as u64 - 1))
+                    + (virt & (LARGE_PAGE_SIZE as u64 - 1)),
+            });
+        }
+        let pt = entry.table()?;
+        let entry = &pt.entry[pt.calc_index(virt)];
+        if entry.is_present() {
+            Ok(TranslationResult::PageMapped4K {
+                phys: entry.phys_addr() + (virt & ATTR_MASK),
+            })
+        } else {
+            Err(Error::Failed("Page Not Fount"))
+        }
+    }
+    // 4KiBページでマップされている仮想アドレスのPTEを返す
+    pub fn leaf_entry(&self, virt: u64) -> Result<&Entry<1, 12, [u8; PAGE_SIZE]>> {
+        let pdpt = self.entry[self.calc_index(virt)].table()?;
+        let entry = &pdpt.entry[pdpt.calc_index(virt)];
+        if entry.is_large_page() {
+            return Err(Error::Failed("Mapped with a 1GiB page"));
+        }
+        let pd = entry.table()?;
+        let entry = &pd.entry[pd.calc_index(virt)];
+        if entry.is_large_page() {
+            return Err(Error::Failed("Mapped with a 2MiB page"));
+        }
+        let pt = entry.table()?;
+        Ok(&pt.entry[pt.calc_index(virt)])
+    }
+    // マッピングを解除し、空になったページテーブルをアロケータに返す
+    pub fn unmap(&mut self, vaddr: usize, size: usize) -> Result<()> {
+        let virt_start = vaddr as u64;
+        let virt_end = virt_start
+            .checked_add(size as u64)
+            .ok_or("Invalid unmap range")?;
+        if virt_start & ATTR_MASK != 0 || virt_end & ATTR_MASK != 0 {
+            return Err(Error::Failed("Unmap range is not aligned"));
+        }
+        // 途中で失敗しないよう、先に範囲全体が4KiBページでマップされているか確認する
+        // 2MiBページは分割しても変換結果は変わらないので、ここで4KiBページにしておく
+        for addr in (virt_start..virt_end).step_by(PAGE_SIZE) {
+            match self.translate(addr)? {
+                TranslationResult::PageMapped4K { .. } => (),
+                TranslationResult::PageMapped2M { .. } => self.split_large_page_at(addr)?,
+                _ => return Err(Error::Failed("Unmapping a large page is not supported")),
+            }
+        }
+        for addr in (virt_start..virt_end).step_by(PAGE_SIZE) {
+            let i4 = self.calc_index(addr);
+            let pdpt = self.entry[i4].table_mut()?;
+            let i3 = pdpt.calc_index(addr);
+            let pd = pdpt.entry[i3].table_mut()?;
+            let i2 = pd.calc_index(addr);
+            let pt = pd.entry[i2].table_mut()?;
+            let i1 = pt.calc_index(addr);
+            pt.entry[i1].clear();
+            if !pt.is_empty() {
+                continue;
+            }
+            unsafe { pd.entry[i2].free_table()? };
+            if !pd.is_empty() {
+                continue;
+            }
+            unsafe { pdpt.entry[i3].free_table()? };
+            if !pdpt.is_empty() {
+                continue;
+            }
+            unsafe { self.entry[i4].free_table()? };
+        }
+        flush_tlb();
+        Ok(())
+    }
 }
 
 /// # Safety
@@ -434,6 +816,8 @@ interrupt_entrypoint_with_ecode!(8);
 interrupt_entrypoint_with_ecode!(13);
 interrupt_entrypoint_with_ecode!(14);
 interrupt_entrypoint!(32);
+interrupt_entrypoint!(33);
+interrupt_entrypoint!(36);
 
 extern "sysv64" {
     fn interrupt_entrypoint3();
@@ -442,6 +826,8 @@ extern "sysv64" {
     fn interrupt_entrypoint13();
     fn interrupt_entrypoint14();
     fn interrupt_entrypoint32();
+    fn interrupt_entrypoint33();
+    fn interrupt_entrypoint36();
 }
 
 // 例外処理関数interrupt_entrypointに呼び出される
@@ -514,9 +900,66 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+pub fn read_rsp() -> u64 {
+    let mut rsp: u64;
+    unsafe {
+        asm!("mov rax, rsp",
+                out("rax") rsp)
+    }
+    rsp
+}
+
+// RSPをstack_topに切り替えてfを実行する。元のスタックには戻らず、fが戻ってきた場合はpanicする
+// fは元のスタックに置いたまま新しいスタックに読み出すので、元のスタックは解放してはいけない
+pub fn switch_stack<F: FnOnce()>(stack_top: u64, f: F) -> ! {
+    extern "sysv64" fn start<F: FnOnce()>(f: *mut F) -> ! {
+        let f = unsafe { f.read() };
+        f();
+        panic!("switch_stack: returned to the bottom of the stack");
+    }
+    let mut f = ManuallyDrop::new(f);
+    // callの直前でRSPが16バイト境界に揃っている必要がある
+    assert_eq!(stack_top % 16, 0);
+    unsafe {
+        asm!("mov rsp, {stack_top}",
+                "call {start}",
+                stack_top = in(reg) stack_top,
+                start = in(reg) start::<F> as usize,
+                in("rdi") &mut *f as *mut F,
+                options(noreturn))
+    }
+}
+
+// カーネルスタックの直下にあるガードページ（NotPresentにマップされている）
+// 0の場合はガードページがない
+static STACK_GUARD_PAGE: AtomicU64 = AtomicU64::new(0);
+pub fn set_stack_guard_page(addr: u64) {
+    STACK_GUARD_PAGE.store(addr, Ordering::Relaxed);
+}
+pub fn is_stack_guard_fault(addr: u64) -> bool {
+    let guard = STACK_GUARD_PAGE.load(Ordering::Relaxed);
+    guard != 0 && (guard..guard + PAGE_SIZE as u64).contains(&addr)
+}
+
 // 例外処理で呼ばれる関数
 #[no_mangle]
 extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
+    if index == TIMER_INTERRUPT_VECTOR as usize {
+        // タイマー割り込みではロックやアロケーションをせず、フラグを立ててEOIを送るだけ
+        on_timer_interrupt();
+        send_eoi();
+        return;
+    }
+    if index == TIMER_QUEUE_INTERRUPT_VECTOR as usize {
+        on_timer_queue_interrupt();
+        send_eoi();
+        return;
+    }
+    if index == SERIAL_INTERRUPT_VECTOR as usize {
+        on_serial_interrupt();
+        send_eoi();
+        return;
+    }
     error!("Interrput Info: {:?}", info);
     error!("Exception {index:#04X}:");
     match index {
@@ -542,7 +985,12 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
         }
         14 => {
             error!("Page Fault");
-            error!("CR2={:#018X}", read_cr2());
+            let cr2 = read_cr2();
+            error!("CR2={:#018X}", cr2);
+            // #PFはISTのスタックで処理されるので、スタックを使い切っていてもここまで来られる
+            if is_stack_guard_fault(cr2) {
+                error!("STACK OVERFLOW: RSP={:#018X}", info.ctx.rsp);
+            }
             error!(
                 "Caused by: A {} mode {} on a {} page, page structures are {}",
                 if info.error_code & 0b0000_0100 != 0 {
@@ -643,6 +1091,19 @@ struct IdtrParameters {
 const _: () = assert!(size_of::<IdtrParameters>() == 10);
 const _: () = assert!(offset_of!(IdtrParameters, base) == 2);
 
+// 現在ロードされているIDTの(limit, base)
+pub fn read_idtr() -> (u16, u64) {
+    let mut params = IdtrParameters {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    unsafe {
+        asm!("sidt [{}]",
+                in(reg) &mut params)
+    }
+    (params.limit, params.base as u64)
+}
+
 pub struct Idt {
     #[allow(dead_code)]
     entries: Pin<Box<[IdtDescriptor; 0x100]>>,
@@ -694,6 +1155,18 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint32,
         );
+        entries[33] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint33,
+        );
+        entries[36] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint36,
+        );
 
         let limit = size_of_val(&entries) as u16;
         // IDTをPinしてアドレスを固定
@@ -828,12 +1301,21 @@ pub struct GdtWrapper {
 }
 
 impl GdtWrapper {
-    // TSSをCPUにロード
-    pub fn load(&self) {
-        let params = GdtParameters {
+    fn params(&self) -> GdtParameters {
+        GdtParameters {
             limit: (size_of::<Gdt>() - 1) as u16,
             base: self.inner.as_ref().get_ref() as *const Gdt,
-        };
+        }
+    }
+    // lgdtに渡す(limit, base)
+    // APがトランポリンからロードするときに使う
+    pub fn gdtr(&self) -> (u16, u64) {
+        let params = self.params();
+        (params.limit, params.base as u64)
+    }
+    // TSSをCPUにロード
+    pub fn load(&self) {
+        let params = self.params();
         info!("Loading GDT @ {:#018X}", params.base as u64);
         // SAFETY: This is safe since it is loading a valid GDT just constructed
         // in the above
@@ -923,3 +1405,154 @@ pub fn flush_tlb() {
         write_cr3(read_cr3());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame_allocator::FRAME_ALLOCATOR;
+
+    #[test_case]
+    fn unmap_clears_mapping_and_reclaims_page_tables() {
+        let mut table = PML4::new();
+        let before = FRAME_ALLOCATOR.lock().allocated_frames();
+        // PTをまたぐように範囲を選ぶ
+        let virt_start = 0x4000_0000u64 - 2 * PAGE_SIZE as u64;
+        let virt_end = virt_start + 4 * PAGE_SIZE as u64;
+        let phys = 0x20_0000u64;
+        table
+            .create_mapping(virt_start, virt_end, phys, PageAttr::WriteBack)
+            .expect("create_mapping failed");
+        for (i, addr) in (virt_start..virt_end).step_by(PAGE_SIZE).enumerate() {
+            assert_eq!(
+                table.translate(addr + 8),
+                Ok(TranslationResult::PageMapped4K {
+                    phys: phys + (i * PAGE_SIZE) as u64 + 8
+                })
+            );
+        }
+        assert!(FRAME_ALLOCATOR.lock().allocated_frames() > before);
+        table
+            .unmap(virt_start as usize, 4 * PAGE_SIZE)
+            .expect("unmap failed");
+        for addr in (virt_start..virt_end).step_by(PAGE_SIZE) {
+            assert!(table.translate(addr).is_err());
+        }
+        assert_eq!(FRAME_ALLOCATOR.lock().allocated_frames(), before);
+        assert!(table.unmap(virt_start as usize, PAGE_SIZE).is_err());
+    }
+
+    #[test_case]
+    fn format_entry_with_decoded_flags() {
+        extern crate alloc;
+        use alloc::format;
+        let entry = Entry::<2, 21, ()> {
+            value: ATTR_NO_EXECUTE
+                | 0x1234_5000_0000
+                | ATTR_PAGE_SIZE
+                | ATTR_DIRTY
+                | ATTR_ACCESSED
+                | ATTR_WRITABLE
+                | ATTR_PRESENT,
+            next_type: PhantomData,
+        };
+        let s = format!("{entry:?}");
+        assert!(s.starts_with("L2Entry @ "));
+        assert!(s.ends_with(
+            "{ 0x80001234500000E3 PWS -> 0x0000123450000000 \
+             [PRESENT|WRITABLE|ACCESSED|DIRTY|LARGE_PAGE|NX] }"
+        ));
+        let entry = Entry::<1, 12, ()> {
+            value: 0x1000 | ATTR_CACHE_DISABLE | ATTR_WRITE_THROUGH | ATTR_USER,
+            next_type: PhantomData,
+        };
+        assert!(format!("{entry:?}").ends_with(
+            "{ 0x000000000000101C NRU -> 0x0000000000001000 [USER|WRITE_THROUGH|CACHE_DISABLE] }"
+        ));
+    }
+
+    #[test_case]
+    fn map_with_cache_modes() {
+        extern crate alloc;
+        use alloc::format;
+        let mut table = PML4::new();
+        let mmio = 0xFEC0_0000u64;
+        let vram = 0x8000_0000u64;
+        table
+            .create_mapping(0, 0x10_0000, 0, PageAttr::WriteBack)
+            .expect("create_mapping failed");
+        table
+            .create_mapping(mmio, mmio + PAGE_SIZE as u64, mmio, PageAttr::Uncacheable)
+            .expect("create_mapping failed");
+        table
+            .create_mapping(
+                vram,
+                vram + PAGE_SIZE as u64,
+                vram,
+                PageAttr::WriteCombining,
+            )
+            .expect("create_mapping failed");
+        assert_eq!(
+            table.translate(mmio + 0x10),
+            Ok(TranslationResult::PageMapped4K { phys: mmio + 0x10 })
+        );
+        let pte = table.leaf_entry(mmio).expect("PTE not found");
+        assert!(format!("{pte:?}")
+            .ends_with("-> 0x00000000FEC00000 [PRESENT|WRITABLE|WRITE_THROUGH|CACHE_DISABLE] }"));
+        let pte = table.leaf_entry(vram).expect("PTE not found");
+        assert!(format!("{pte:?}").ends_with("-> 0x0000000080000000 [PRESENT|WRITABLE|PAT] }"));
+        let pte = table.leaf_entry(0x1000).expect("PTE not found");
+        assert!(format!("{pte:?}").ends_with("-> 0x0000000000001000 [PRESENT|WRITABLE] }"));
+    }
+
+    #[test_case]
+    fn large_pages_are_split_on_partial_change() {
+        extern crate alloc;
+        use alloc::format;
+        let mut table = PML4::new();
+        let base = 0x4000_0000u64;
+        let end = base + 2 * LARGE_PAGE_SIZE as u64;
+        assert!(table
+            .create_mapping_2m(base + PAGE_SIZE as u64, end, base, PageAttr::WriteBack)
+            .is_err());
+        table
+            .create_mapping_2m(base, end, base, PageAttr::WriteBack)
+            .expect("create_mapping_2m failed");
+        assert_eq!(
+            table.translate(base + 0x1234),
+            Ok(TranslationResult::PageMapped2M {
+                phys: base + 0x1234
+            })
+        );
+        assert!(table.leaf_entry(base).is_err());
+
+        // 1ページだけライトコンバインにすると、そのページを含む2MiBページだけが分割される
+        let vram = base + LARGE_PAGE_SIZE as u64 + 3 * PAGE_SIZE as u64;
+        table
+            .create_mapping(
+                vram,
+                vram + PAGE_SIZE as u64,
+                vram,
+                PageAttr::WriteCombining,
+            )
+            .expect("create_mapping failed");
+        assert_eq!(
+            table.translate(vram - 8),
+            Ok(TranslationResult::PageMapped4K { phys: vram - 8 })
+        );
+        let pte = table.leaf_entry(vram).expect("PTE not found");
+        assert!(format!("{pte:?}").ends_with("[PRESENT|WRITABLE|PAT] }"));
+        assert_eq!(
+            table.translate(base),
+            Ok(TranslationResult::PageMapped2M { phys: base })
+        );
+
+        table.unmap(base as usize, PAGE_SIZE).expect("unmap failed");
+        assert!(table.translate(base).is_err());
+        assert_eq!(
+            table.translate(base + PAGE_SIZE as u64),
+            Ok(TranslationResult::PageMapped4K {
+                phys: base + PAGE_SIZE as u64
+            })
+        );
+    }
+}